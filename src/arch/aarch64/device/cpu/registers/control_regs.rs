@@ -84,6 +84,12 @@ pub unsafe fn cntfreq_el0() -> u32 {
     ret as u32
 }
 
+pub unsafe fn cntpct_el0() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, cntpct_el0", out(reg) ret);
+    ret
+}
+
 pub unsafe fn tmr_ctrl() -> u32 {
     let ret: usize;
     asm!("mrs {}, cntp_ctl_el0", out(reg) ret);
@@ -8,7 +8,7 @@ use crate::{
     device::cpu::registers::control_regs,
     dtb::irqchip::{register_irq, InterruptHandler, IRQ_CHIP},
     interrupt::irq::trigger,
-    time,
+    time::{self, NANOS_PER_SEC},
 };
 use byteorder::{ByteOrder, BE};
 use fdt::Fdt;
@@ -104,12 +104,31 @@ impl GenericTimer {
     }
 }
 
+/// Program the timer of this CPU to fire at `deadline` on the monotonic clock, or as late as
+/// possible if there is none.
+pub fn set_deadline(deadline: Option<u128>) {
+    let freq = u128::from(unsafe { control_regs::cntfreq_el0() });
+    let ticks = deadline.map_or(u128::MAX, |deadline| {
+        deadline.saturating_sub(time::monotonic()) * freq / NANOS_PER_SEC
+    });
+    // The timer value is a signed 32-bit count, so wake up early rather than wrap around.
+    let ticks = ticks.clamp(1, i32::MAX as u128) as u32;
+
+    unsafe { control_regs::tmr_tval_write(ticks) };
+}
+
+/// Program the timer of this CPU to fire periodically again.
+pub fn set_periodic() {
+    let reload_count = unsafe { control_regs::cntfreq_el0() } / 100;
+
+    unsafe { control_regs::tmr_tval_write(reload_count) };
+}
+
 impl InterruptHandler for GenericTimer {
     fn irq_handler(&mut self, irq: u32) {
         self.clear_irq();
-        {
-            *time::OFFSET.lock() += self.clk_freq as u128;
-        }
+        // Rearm before ticking, which may stop the tick and program the next deadline instead.
+        self.reload_count();
 
         timeout::trigger();
 
@@ -118,6 +137,5 @@ impl InterruptHandler for GenericTimer {
         unsafe {
             trigger(irq);
         }
-        self.reload_count();
     }
 }
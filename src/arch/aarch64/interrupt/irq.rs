@@ -8,6 +8,7 @@ unsafe fn irq_ack() -> (u32, Option<usize>) {
 }
 
 exception_stack!(irq_at_el0, |_stack| {
+    let _irq = crate::context::load::IrqGuard::enter();
     let (irq, virq) = irq_ack();
     if let Some(virq) = virq
         && virq < 1024
@@ -19,6 +20,7 @@ exception_stack!(irq_at_el0, |_stack| {
 });
 
 exception_stack!(irq_at_el1, |_stack| {
+    let _irq = crate::context::load::IrqGuard::enter();
     let (irq, virq) = irq_ack();
     if let Some(virq) = virq
         && virq < 1024
@@ -16,6 +16,7 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
     let virt = RmmA::phys_to_virt(frame.base()).data() as *mut PercpuBlock;
 
     virt.write(PercpuBlock::init(cpu_id));
+    crate::percpu::init_tlb_shootdown(cpu_id, virt);
 
     crate::device::cpu::registers::control_regs::tpidr_el1_write(virt as u64);
 }
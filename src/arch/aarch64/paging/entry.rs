@@ -5,5 +5,11 @@ bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE = 1 << 2;
         const DEV_MEM = 2 << 2;
+        // The access flag is not set by hardware, but faults when clear.
+        const ACCESSED = 0;
+        // Dirty state is not tracked by hardware, so MADV_FREE frees pages right away.
+        const DIRTY = 0;
+        // Block mappings are not used for user memory.
+        const HUGE_PAGE = 0;
     }
 }
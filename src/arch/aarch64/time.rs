@@ -1,4 +1,23 @@
+use crate::{
+    device::{cpu::registers::control_regs, generic_timer},
+    time::NANOS_PER_SEC,
+};
+
 pub fn monotonic_absolute() -> u128 {
-    //TODO: aarch64 generic timer counter
-    *crate::time::OFFSET.lock()
+    let (counter, freq) = unsafe { (control_regs::cntpct_el0(), control_regs::cntfreq_el0()) };
+    if freq == 0 {
+        return 0;
+    }
+    u128::from(counter) * NANOS_PER_SEC / u128::from(freq)
+}
+
+/// Stop the periodic tick of this CPU, and have its generic timer fire at `deadline` instead.
+pub fn stop_tick(deadline: Option<u128>) -> bool {
+    generic_timer::set_deadline(deadline);
+    true
+}
+
+/// Restart the periodic tick of this CPU.
+pub fn restart_tick() {
+    generic_timer::set_periodic();
 }
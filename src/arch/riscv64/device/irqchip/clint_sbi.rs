@@ -3,6 +3,7 @@ use crate::{
     context,
     context::timeout,
     dtb::irqchip::{register_irq, InterruptHandler, IRQ_CHIP},
+    time,
 };
 use alloc::{boxed::Box, vec::Vec};
 use byteorder::{ByteOrder, BE};
@@ -136,6 +137,17 @@ impl Clint {
         }
     }
 
+    /// Program the timer of `hart` to fire at `deadline` on the monotonic clock, instead of
+    /// periodically, or not at all if there is no deadline.
+    pub fn set_deadline(self: &mut Self, hart: usize, deadline: Option<u128>) {
+        self.next_event[hart] = deadline.map_or(u64::MAX, |deadline| {
+            u64::try_from(deadline * u128::from(self.freq) / time::NANOS_PER_SEC)
+                .unwrap_or(u64::MAX)
+        });
+        SBI.set_timer(self.next_event[hart])
+            .expect("SBI timer cannot be set!");
+    }
+
     pub fn init(self: &mut Self, hart: usize) {
         let mtime: usize;
         unsafe {
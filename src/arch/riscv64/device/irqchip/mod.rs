@@ -42,3 +42,25 @@ pub unsafe fn init_clint(fdt: &Fdt) {
     *clint::CLINT.lock() = Some(clint);
     clint::CLINT.lock().as_mut().unwrap().init(0);
 }
+
+/// Program the timer of this hart for `deadline`, if there is a CLINT to do so. Must be called with
+/// interrupts disabled, as the timer interrupt handler locks the CLINT as well.
+pub fn set_timer_deadline(deadline: Option<u128>) -> bool {
+    let hart = crate::cpu_id().get() as usize;
+    match clint::CLINT.lock().as_mut() {
+        Some(clint) => {
+            clint.set_deadline(hart, deadline);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Program the timer of this hart to fire periodically again. Must be called with interrupts
+/// disabled, like `set_timer_deadline`.
+pub fn restart_timer() {
+    let hart = crate::cpu_id().get() as usize;
+    if let Some(clint) = clint::CLINT.lock().as_mut() {
+        clint.init(hart);
+    }
+}
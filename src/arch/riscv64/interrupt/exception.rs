@@ -137,6 +137,7 @@ unsafe fn handle_system_exception(scause: usize, regs: &InterruptStack) {
 }
 
 unsafe fn handle_interrupt(interrupt: usize) {
+    let _irq = crate::context::load::IrqGuard::enter();
     // FIXME retrieve from percpu area
     // For now all the interrupts go to boot hart so this suffices...
     let hart: usize = BOOT_HART_ID.load(Ordering::Relaxed);
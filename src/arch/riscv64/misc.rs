@@ -36,6 +36,7 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
         s_sp: 0,
         percpu: PercpuBlock::init(cpu_id),
     });
+    crate::percpu::init_tlb_shootdown(cpu_id, &mut (*virt).percpu);
 
     asm!(
         "mv tp, {}",
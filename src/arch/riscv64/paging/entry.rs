@@ -5,7 +5,13 @@ pub struct Entry(u64);
 bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE =        1 << 4;
+        // The accessed bit may not be set by hardware, in which case clearing it causes faults.
+        const ACCESSED =        0;
+        // Likewise for the dirty bit, so MADV_FREE frees pages right away.
+        const DIRTY =           0;
         const DEV_MEM =         0;
         const WRITE_COMBINING = 0;
+        // Superpages are not used for user memory.
+        const HUGE_PAGE =       0;
     }
 }
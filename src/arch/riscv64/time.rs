@@ -24,3 +24,13 @@ pub fn monotonic_absolute() -> u128 {
         0
     }
 }
+
+/// Stop the periodic tick of this hart, and have its timer fire at `deadline` instead.
+pub fn stop_tick(deadline: Option<u128>) -> bool {
+    super::device::irqchip::set_timer_deadline(deadline)
+}
+
+/// Restart the periodic tick of this hart.
+pub fn restart_tick() {
+    super::device::irqchip::restart_timer();
+}
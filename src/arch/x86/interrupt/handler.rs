@@ -324,6 +324,7 @@ macro_rules! interrupt {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                let _irq = $crate::context::load::IrqGuard::enter();
                 $code
             }
 
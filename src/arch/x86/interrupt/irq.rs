@@ -10,7 +10,6 @@ use crate::{
         serial::{COM1, COM2},
     },
     interrupt, interrupt_stack,
-    ipi::{ipi, IpiKind, IpiTarget},
     scheme::{
         debug::{debug_input, debug_notify},
         serio::serio_input,
@@ -74,14 +73,7 @@ extern "C" {
 /// Notify the IRQ scheme that an IRQ has been registered. This should mask the IRQ until the
 /// scheme user unmasks it ("acknowledges" it).
 unsafe fn trigger(irq: u8) {
-    match irq_method() {
-        IrqMethod::Pic => {
-            if irq < 16 {
-                pic_mask(irq)
-            }
-        }
-        IrqMethod::Apic => ioapic_mask(irq),
-    }
+    mask(irq);
     irq_trigger(irq);
 }
 
@@ -98,6 +90,18 @@ pub unsafe fn acknowledge(irq: usize) {
     }
 }
 
+/// Mask the IRQ, without notifying the IRQ scheme.
+pub unsafe fn mask(irq: u8) {
+    match irq_method() {
+        IrqMethod::Pic => {
+            if irq < 16 {
+                pic_mask(irq)
+            }
+        }
+        IrqMethod::Apic => ioapic_mask(irq),
+    }
+}
+
 /// Sends an end-of-interrupt, so that the interrupt controller can go on to the next one.
 pub unsafe fn eoi(irq: u8) {
     match irq_method() {
@@ -154,6 +158,9 @@ unsafe fn ioapic_unmask(irq: usize) {
 }
 
 interrupt_stack!(pit_stack, |_stack| {
+    // Exceptions share this macro, so IRQ time is accounted here rather than in it.
+    let _irq = crate::context::load::IrqGuard::enter();
+
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
     {
@@ -162,14 +169,17 @@ interrupt_stack!(pit_stack, |_stack| {
 
     eoi(0);
 
-    // Wake up other CPUs
-    ipi(IpiKind::Pit, IpiTarget::Other);
+    // Until the local APIC timers take over, this interrupt drives the tick of every CPU.
+    if crate::arch::time::legacy_tick() {
+        // Wake up other CPUs, unless they have stopped their tick
+        crate::arch::time::forward_tick();
 
-    // Any better way of doing this?
-    timeout::trigger();
+        // Any better way of doing this?
+        timeout::trigger();
 
-    // Switch after a sufficient amount of time since the last switch.
-    context::switch::tick();
+        // Switch after a sufficient amount of time since the last switch.
+        context::switch::tick();
+    }
 });
 
 interrupt!(keyboard, || {
@@ -272,8 +282,13 @@ interrupt!(ata2, || {
 });
 
 interrupt!(lapic_timer, || {
-    println!("Local apic timer interrupt");
     lapic_eoi();
+
+    // Timeouts are triggered by whichever CPU's timer fires first after their deadline.
+    timeout::trigger();
+
+    // Switch after a sufficient amount of time since the last switch.
+    context::switch::tick();
 });
 
 interrupt!(lapic_error, || {
@@ -14,6 +14,8 @@ pub mod entry {
     bitflags! {
         pub struct EntryFlags: usize {
             const NO_CACHE =        1 << 4;
+            const ACCESSED =        1 << 5;
+            const DIRTY =           1 << 6;
             const HUGE_PAGE =       1 << 7;
             const GLOBAL =          1 << 8;
             const DEV_MEM =         0;
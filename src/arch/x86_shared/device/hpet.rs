@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
 use super::pit;
 use crate::acpi::hpet::Hpet;
 
@@ -14,11 +16,17 @@ const GENERAL_INTERRUPT_OFFSET: usize = 0x20;
 pub(crate) const MAIN_COUNTER_OFFSET: usize = 0xF0;
 // const NUM_TIMER_CAP_MASK: u64 = 0x0f00;
 const LEG_RT_CAP: u64 = 0x8000;
+const COUNT_SIZE_CAP: u64 = 0x2000;
 const T0_CONFIG_CAPABILITY_OFFSET: usize = 0x100;
 pub(crate) const T0_COMPARATOR_OFFSET: usize = 0x108;
 
 const PER_INT_CAP: u64 = 0x10;
 
+/// Whether the HPET was initialized, rather than the PIT used instead
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Value of the main counter when the HPET was initialized, at which the system clock started
+static START_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(hpet: &mut Hpet) -> bool {
     println!("HPET Before Init");
     debug(hpet);
@@ -47,6 +55,7 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
     }
 
     let counter = hpet.base_address.read_u64(MAIN_COUNTER_OFFSET);
+    START_COUNTER.store(counter, Ordering::Relaxed);
 
     let t0_config_word: u64 = TN_VAL_SET_CNF | TN_TYPE_CNF | TN_INT_ENB_CNF;
     hpet.base_address
@@ -68,9 +77,28 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
     println!("HPET After Init");
     debug(hpet);
 
+    ACTIVE.store(true, Ordering::Relaxed);
     true
 }
 
+/// Nanoseconds since the HPET was initialized, read from its main counter, if it is 64 bits wide.
+/// Narrower counters overflow within minutes, so time is then kept by counting interrupts.
+pub fn elapsed(hpet: &Hpet) -> Option<u128> {
+    let capability = unsafe { hpet.base_address.read_u64(CAPABILITY_OFFSET) };
+    if !ACTIVE.load(Ordering::Relaxed) || capability & COUNT_SIZE_CAP == 0 {
+        return None;
+    }
+    let counter = unsafe { hpet.base_address.read_u64(MAIN_COUNTER_OFFSET) };
+    // Same workaround for a period of 0 as when reading the time since the last interrupt
+    let period_fs = match capability >> 32 {
+        0 => 10_000_000,
+        period_fs => period_fs,
+    };
+
+    let elapsed = counter.wrapping_sub(START_COUNTER.load(Ordering::Relaxed));
+    Some(u128::from(elapsed) * u128::from(period_fs) / 1_000_000)
+}
+
 pub unsafe fn debug(hpet: &mut Hpet) {
     println!("HPET @ {:#x}", { hpet.base_address.address });
 
@@ -1,13 +1,14 @@
 use core::{
     cell::SyncUnsafeCell,
     ptr::{read_volatile, write_volatile},
-    sync::atomic::{self, AtomicU32},
+    sync::atomic::{self, AtomicU32, AtomicU64},
 };
 use x86::msr::*;
 
 use crate::{
     ipi::IpiKind,
     paging::{PageFlags, PhysicalAddress},
+    time::NANOS_PER_SEC,
 };
 
 use crate::{arch::cpuid::cpuid, memory::KernelMapper};
@@ -36,6 +37,22 @@ pub struct LocalApic {
 
 static BSP_APIC_ID: AtomicU32 = AtomicU32::new(u32::max_value());
 
+/// Interrupt vector of the timer
+const TIMER_VECTOR: u32 = 48;
+/// Set in a local vector table entry to mask its interrupt
+const LVT_MASKED: u32 = 1 << 16;
+/// Divide configuration of the timer, which counts at the bus frequency divided by 16
+const TIMER_DIV_16: u32 = 0b0011;
+
+/// Number of times the timer counts down per second, which is the same on every CPU, or 0 until
+/// it has been calibrated against the system clock.
+static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the timer has been calibrated, and can thus be programmed in nanoseconds.
+pub fn timer_calibrated() -> bool {
+    TIMER_FREQ.load(atomic::Ordering::Relaxed) != 0
+}
+
 #[no_mangle]
 pub fn bsp_apic_id() -> Option<u32> {
     let value = BSP_APIC_ID.load(atomic::Ordering::SeqCst);
@@ -92,7 +109,7 @@ impl LocalApic {
             self.write(0xF0, 0x100);
         }
         self.setup_error_int();
-        //self.setup_timer();
+        self.setup_timer();
     }
 
     unsafe fn read(&self, reg: u32) -> u32 {
@@ -247,6 +264,51 @@ impl LocalApic {
         let vector = 49u32;
         self.set_lvt_error(vector);
     }
+    unsafe fn setup_timer(&mut self) {
+        self.set_div_conf(TIMER_DIV_16);
+        self.set_timer_oneshot(None);
+    }
+    /// Start counting down from the highest count without interrupting, in order to calibrate
+    /// the timer.
+    pub unsafe fn start_timer_calibration(&mut self) {
+        self.set_lvt_timer(TIMER_VECTOR | LVT_MASKED | (LvtTimerMode::OneShot as u32) << 17);
+        self.set_init_count(u32::MAX);
+    }
+    /// Calibrate the timer, given the time elapsed on the system clock since
+    /// `start_timer_calibration`.
+    pub unsafe fn finish_timer_calibration(&mut self, elapsed: u128) {
+        let ticks = u128::from(u32::MAX - self.cur_count());
+        self.set_init_count(0);
+
+        let freq = u64::try_from(ticks * NANOS_PER_SEC / elapsed.max(1)).unwrap_or(0);
+        log::info!("Local APIC timer frequency: {} Hz", freq);
+        TIMER_FREQ.store(freq, atomic::Ordering::Relaxed);
+    }
+    /// Number of timer ticks in `nanos` nanoseconds, as a count the timer can be programmed with.
+    fn timer_count(nanos: u128) -> u32 {
+        let freq = u128::from(TIMER_FREQ.load(atomic::Ordering::Relaxed));
+        (nanos * freq / NANOS_PER_SEC).clamp(1, u32::MAX.into()) as u32
+    }
+    /// Have the timer interrupt this CPU every `period` nanoseconds. The timer must have been
+    /// calibrated.
+    pub unsafe fn set_timer_periodic(&mut self, period: u128) {
+        self.set_lvt_timer(TIMER_VECTOR | (LvtTimerMode::Periodic as u32) << 17);
+        self.set_init_count(Self::timer_count(period));
+    }
+    /// Have the timer interrupt this CPU once after `delay` nanoseconds, or not at all. The timer
+    /// must have been calibrated, unless there is no delay.
+    pub unsafe fn set_timer_oneshot(&mut self, delay: Option<u128>) {
+        match delay {
+            Some(delay) => {
+                self.set_lvt_timer(TIMER_VECTOR | (LvtTimerMode::OneShot as u32) << 17);
+                self.set_init_count(Self::timer_count(delay));
+            }
+            None => {
+                self.set_lvt_timer(TIMER_VECTOR | LVT_MASKED);
+                self.set_init_count(0);
+            }
+        }
+    }
 }
 
 #[repr(u8)]
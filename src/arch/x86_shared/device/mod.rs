@@ -1,3 +1,5 @@
+use core::sync::atomic::AtomicBool;
+
 use crate::memory::KernelMapper;
 
 pub mod cpu;
@@ -77,4 +79,7 @@ pub unsafe fn init_ap() {
 pub struct ArchPercpuMisc {
     #[cfg(feature = "x86_kvm_pv")]
     pub tsc_info: tsc::TscPercpu,
+    /// Whether the local APIC timer drives the tick of this CPU, rather than the PIT or HPET
+    /// interrupt of the BSP
+    pub local_tick: AtomicBool,
 }
@@ -2,6 +2,9 @@ use crate::{context, device::local_apic::the_local_apic, percpu::PercpuBlock};
 
 interrupt!(wakeup, || {
     the_local_apic().eoi();
+
+    // Something was queued on this CPU, which may need to preempt the current context.
+    context::switch::restart_tick();
 });
 
 interrupt!(tlb, || {
@@ -19,6 +22,9 @@ interrupt!(switch, || {
 interrupt!(pit, || {
     the_local_apic().eoi();
 
+    // Forwarded by the BSP until the local APIC timer of this CPU takes over.
+    crate::arch::time::take_over_tick();
+
     // Switch after a sufficient amount of time since the last switch.
     context::switch::tick();
 });
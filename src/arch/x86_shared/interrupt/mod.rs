@@ -14,6 +14,16 @@ pub unsafe fn disable() {
     core::arch::asm!("cli", options(nomem, nostack));
 }
 
+/// Whether interrupts are enabled
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let flags: usize;
+    unsafe {
+        core::arch::asm!("pushf; pop {}", out(reg) flags, options(preserves_flags));
+    }
+    flags & (1 << 9) != 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
@@ -1,6 +1,22 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 #[cfg(feature = "acpi")]
 use super::device::hpet;
-use super::device::pit;
+use super::device::{
+    local_apic::{self, the_local_apic},
+    pit,
+};
+use crate::{
+    cpu_set::LogicalCpuId,
+    ipi::{ipi_single, IpiKind},
+    percpu::{self, PercpuBlock},
+};
+
+/// How long to calibrate the local APIC timer against the system clock for
+const CALIBRATION_TIME: u128 = 100_000_000;
+/// Time at which calibrating the local APIC timer started, 0 if it has not, or `u64::MAX` if it
+/// failed
+static CALIBRATION_START: AtomicU64 = AtomicU64::new(0);
 
 pub fn monotonic_absolute() -> u128 {
     // The paravirtualized TSC is already guaranteed to be monotonic, and thus doesn't need to be
@@ -10,8 +26,32 @@ pub fn monotonic_absolute() -> u128 {
         return ns;
     }
 
+    #[cfg(feature = "acpi")]
+    if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read()
+        && let Some(ns) = hpet::elapsed(hpet)
+    {
+        return ns;
+    }
+
     *crate::time::OFFSET.lock() + hpet_or_pit()
 }
+
+/// Whether the system clock is kept by counting the interrupts of the PIT or HPET, rather than
+/// read from a counter that does not need them.
+fn clock_needs_legacy_timer() -> bool {
+    #[cfg(feature = "x86_kvm_pv")]
+    if super::device::tsc::monotonic_absolute().is_some() {
+        return false;
+    }
+    #[cfg(feature = "acpi")]
+    if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read()
+        && hpet::elapsed(hpet).is_some()
+    {
+        return false;
+    }
+    true
+}
+
 fn hpet_or_pit() -> u128 {
     #[cfg(feature = "acpi")]
     if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
@@ -47,3 +87,109 @@ fn hpet_or_pit() -> u128 {
     // Calculate nanoseconds since last interrupt
     (elapsed as u128 * pit::PERIOD_FS) / 1_000_000
 }
+
+/// Handle an interrupt of the PIT or HPET, which only the BSP receives. Until the local APIC timer
+/// has been calibrated against the system clock, that interrupt drives the tick of every CPU, and
+/// this returns true. Once it has, the local APIC timer of every CPU takes over, and the PIT or
+/// HPET interrupt is masked, unless it is still needed to keep time.
+pub fn legacy_tick() -> bool {
+    let start = CALIBRATION_START.load(Ordering::Relaxed);
+    if start == u64::MAX {
+        return true;
+    }
+    if local_apic::timer_calibrated() {
+        return false;
+    }
+
+    let now = crate::time::monotonic();
+    if start == 0 {
+        unsafe { the_local_apic().start_timer_calibration() };
+        CALIBRATION_START.store(
+            u64::try_from(now).unwrap_or(u64::MAX - 1).max(1),
+            Ordering::Relaxed,
+        );
+        return true;
+    }
+    let elapsed = now.saturating_sub(u128::from(start));
+    if elapsed < CALIBRATION_TIME {
+        return true;
+    }
+    unsafe { the_local_apic().finish_timer_calibration(elapsed) };
+    if !local_apic::timer_calibrated() {
+        log::warn!("Local APIC timer is not counting, keeping the system timer as the tick");
+        CALIBRATION_START.store(u64::MAX, Ordering::Relaxed);
+        return true;
+    }
+
+    if !clock_needs_legacy_timer() {
+        // Both the PIT and the HPET in legacy replacement mode interrupt as IRQ 0.
+        unsafe { crate::arch::interrupt::irq::mask(0) };
+    }
+
+    // Forward the interrupt one last time, which lets the other CPUs start their own timers.
+    take_over_tick();
+    let current = crate::cpu_id();
+    for block in (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&id| id != current)
+        .filter_map(percpu::get)
+    {
+        ipi_single(IpiKind::Pit, block.cpu_id);
+    }
+    false
+}
+
+/// Have the local APIC timer drive the tick of this CPU from now on, if it has been calibrated
+/// and does not already.
+pub fn take_over_tick() {
+    let local_tick = &PercpuBlock::current().misc_arch_info.local_tick;
+    if local_apic::timer_calibrated() && !local_tick.swap(true, Ordering::Relaxed) {
+        unsafe { the_local_apic().set_timer_periodic(pit::RATE) };
+    }
+}
+
+/// Stop the periodic tick of this CPU, and have its local APIC timer fire at `deadline` instead.
+///
+/// Until that timer has been calibrated, only the BSP receives the tick, from the PIT or HPET,
+/// which it forwards to the other CPUs as an IPI. Those can then stop their tick, by having the
+/// BSP skip them until their deadline has passed.
+pub fn stop_tick(deadline: Option<u128>) -> bool {
+    if !local_apic::timer_calibrated() {
+        return crate::cpu_id() != LogicalCpuId::BSP;
+    }
+    let delay = deadline.map(|deadline| deadline.saturating_sub(crate::time::monotonic()));
+    unsafe { the_local_apic().set_timer_oneshot(delay) };
+    PercpuBlock::current()
+        .misc_arch_info
+        .local_tick
+        .store(true, Ordering::Relaxed);
+    true
+}
+
+/// Restart the periodic tick of this CPU, from its local APIC timer, or from the BSP forwarding
+/// its next interrupt until that timer has been calibrated.
+pub fn restart_tick() {
+    if local_apic::timer_calibrated() {
+        unsafe { the_local_apic().set_timer_periodic(pit::RATE) };
+        PercpuBlock::current()
+            .misc_arch_info
+            .local_tick
+            .store(true, Ordering::Relaxed);
+    }
+}
+
+/// Forward the PIT interrupt to every other CPU that still needs its tick.
+pub fn forward_tick() {
+    let now = crate::time::monotonic();
+    let current = crate::cpu_id();
+
+    for block in (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&id| id != current)
+        .filter_map(percpu::get)
+    {
+        if block.switch_internals.needs_tick(now) {
+            ipi_single(IpiKind::Pit, block.cpu_id);
+        }
+    }
+}
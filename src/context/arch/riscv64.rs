@@ -8,13 +8,11 @@ use crate::{
     percpu::PercpuBlock,
     syscall::FloatRegisters,
 };
-use core::{mem::offset_of, sync::atomic::AtomicBool};
+use core::mem::offset_of;
 use rmm::{Arch, TableKind, VirtualAddress};
 use spin::Once;
 use syscall::{error::*, EnvRegisters};
 
-pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
-
 pub const KFX_ALIGN: usize = 16;
 
 #[derive(Clone, Debug, Default)]
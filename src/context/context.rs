@@ -1,10 +1,17 @@
-use alloc::{borrow::Cow, collections::VecDeque, sync::Arc, vec::Vec};
+use alloc::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use core::{
     cmp::Ordering,
     mem::{self, size_of},
     num::NonZeroUsize,
+    sync::atomic::{self, AtomicUsize},
 };
 use spin::RwLock;
+use spinning_top::RwSpinlock;
 use syscall::{RtSigInfo, SigProcControl, Sigcontrol};
 
 use crate::{
@@ -12,11 +19,12 @@ use crate::{
     common::aligned_box::AlignedBox,
     context::{self, arch, file::FileDescriptor},
     cpu_set::{LogicalCpuId, LogicalCpuSet},
-    ipi::{ipi, IpiKind, IpiTarget},
+    ipi::{ipi_single, IpiKind},
     memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, RaiiFrame},
     paging::{RmmA, RmmArch},
     percpu::PercpuBlock,
     scheme::FileHandle,
+    syscall::futex::FutexKey,
 };
 
 use crate::syscall::error::{Error, Result, EAGAIN, ESRCH};
@@ -25,8 +33,12 @@ use super::{
     empty_cr3,
     memory::{AddrSpaceWrapper, GrantFileRef},
     process::{Process, ProcessId},
+    switch::SchedPolicy,
 };
 
+/// The next thread ID to be assigned. Zero is never used, as PI futexes use it to mean unlocked.
+static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+
 /// The status of a context - used for scheduling
 /// See `syscall::process::waitpid` and the `sync` module for examples of usage
 #[derive(Clone, Debug)]
@@ -58,11 +70,53 @@ impl Status {
 #[derive(Clone, Debug)]
 pub enum HardBlockedReason {
     AwaitingMmap { file_ref: GrantFileRef },
-    // TODO: PageFaultOom?
+    AwaitingSwap,
     NotYetStarted,
     PtraceStop,
 }
 
+/// Scheduler statistics of a context, used to tell scheduling delay apart from blocking.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SchedStats {
+    /// Number of times the context stopped running because it blocked
+    pub voluntary_switches: u64,
+    /// Number of times the context was preempted while still runnable
+    pub involuntary_switches: u64,
+    /// Total time spent runnable, but waiting for a CPU
+    pub wait_time: u128,
+    /// Total time spent blocked
+    pub blocked_time: u128,
+    /// Time of the last transition between running, waiting and blocked
+    since: u128,
+}
+impl SchedStats {
+    fn new(now: u128) -> Self {
+        Self {
+            since: now,
+            ..Self::default()
+        }
+    }
+    /// Account for the context becoming runnable after being blocked.
+    fn unblocked(&mut self, now: u128) {
+        self.blocked_time += now.saturating_sub(self.since);
+        self.since = now;
+    }
+    /// Account for the context being switched away from, while `runnable` or not.
+    pub(super) fn switched_out(&mut self, runnable: bool, now: u128) {
+        if runnable {
+            self.involuntary_switches += 1;
+        } else {
+            self.voluntary_switches += 1;
+        }
+        self.since = now;
+    }
+    /// Account for the context being switched to, after waiting since being queued.
+    pub(super) fn switched_in(&mut self, now: u128) {
+        self.wait_time += now.saturating_sub(self.since);
+        self.since = now;
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct WaitpidKey {
     pub pid: Option<ProcessId>,
@@ -127,6 +181,8 @@ impl Eq for WaitpidKey {}
 pub struct Context {
     /// The process ID of this context
     pub pid: ProcessId,
+    /// Thread ID, unique among all contexts ever created, which PI futexes record as their owner
+    pub tid: usize,
     /// Process state shared with other threads
     pub process: Arc<RwLock<Process>>,
     /// Signal handler
@@ -142,9 +198,45 @@ pub struct Context {
     pub switch_time: u128,
     /// Amount of CPU time used
     pub cpu_time: u128,
+    /// Context switch counts and time spent waiting or blocked
+    pub sched_stats: SchedStats,
     /// Scheduler CPU affinity. If set, [`cpu_id`] can except [`None`] never be anything else than
     /// this value.
     pub sched_affinity: LogicalCpuSet,
+    /// The CPU whose run queue this context is currently waiting in, if any.
+    pub sched_queue: Option<LogicalCpuId>,
+    /// Nice value, from [`NICE_MIN`](super::switch::NICE_MIN) (most favorable) to
+    /// [`NICE_MAX`](super::switch::NICE_MAX).
+    pub sched_nice: i8,
+    /// CPU time weighted by the nice value, used to pick the context that has received the least
+    /// of its share of the CPU.
+    pub sched_vruntime: u128,
+    /// Scheduling class, either time-sharing or one of the real-time policies.
+    pub sched_policy: SchedPolicy,
+    /// Real-time priority, from [`RT_PRIORITY_MIN`](super::switch::RT_PRIORITY_MIN) to
+    /// [`RT_PRIORITY_MAX`](super::switch::RT_PRIORITY_MAX) (most favorable). Unused for
+    /// time-sharing contexts.
+    pub sched_rt_priority: u8,
+    /// Time a round-robin context may run before being put behind others of the same priority.
+    pub sched_rr_quantum: u128,
+    /// CPU time used since the context was last put at the back of a run queue.
+    pub sched_slice_used: u128,
+    /// Real-time priority inherited from contexts waiting on PI futexes owned by this context.
+    pub sched_pi_boost: Option<u8>,
+    /// Key of the PI futex this context is waiting to lock, if any.
+    pub pi_blocked_on: Option<FutexKey>,
+    /// The futex this context is waiting on, if any, which requeueing may change.
+    pub futex_blocked_on: Option<FutexKey>,
+    /// Index of the futex that woke this context up while waiting on several of them.
+    pub futex_woken: Option<usize>,
+    /// Whether this context is parked, i.e. waiting to be unparked.
+    pub parked: bool,
+    /// Whether this context has been unparked since it last parked, so that its next park returns
+    /// immediately.
+    pub unpark_pending: bool,
+    /// Weak reference to the lock containing this context, used to put it on a run queue when it
+    /// becomes runnable.
+    pub self_ref: Weak<RwSpinlock<Context>>,
     /// Keeps track of whether this context is currently handling a syscall. Only up-to-date when
     /// not running.
     pub inside_syscall: bool,
@@ -180,7 +272,9 @@ pub struct Context {
     /// interrupts or syscalls occur. This flag is set for all contexts but kmain.
     pub userspace: bool,
     pub being_sigkilled: bool,
-    pub fmap_ret: Option<Frame>,
+    pub fmap_ret: Option<Vec<Frame>>,
+    /// Whether the page the context is waiting for has been read back from swap
+    pub swap_ret: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -204,6 +298,7 @@ impl Context {
     pub fn new(pid: ProcessId, process: Arc<RwLock<Process>>) -> Result<Context> {
         let this = Context {
             pid,
+            tid: NEXT_TID.fetch_add(1, atomic::Ordering::Relaxed),
             process,
             sig: None,
             status: Status::HardBlocked {
@@ -214,7 +309,22 @@ impl Context {
             cpu_id: None,
             switch_time: 0,
             cpu_time: 0,
+            sched_stats: SchedStats::new(crate::time::monotonic()),
             sched_affinity: LogicalCpuSet::all(),
+            sched_queue: None,
+            sched_nice: 0,
+            sched_vruntime: 0,
+            sched_policy: SchedPolicy::Other,
+            sched_rt_priority: 0,
+            sched_rr_quantum: context::switch::DEFAULT_RR_QUANTUM,
+            sched_slice_used: 0,
+            sched_pi_boost: None,
+            pi_blocked_on: None,
+            futex_blocked_on: None,
+            futex_woken: None,
+            parked: false,
+            unpark_pending: false,
+            self_ref: Weak::new(),
             inside_syscall: false,
             syscall_head: Some(RaiiFrame::allocate()?),
             syscall_tail: Some(RaiiFrame::allocate()?),
@@ -227,6 +337,7 @@ impl Context {
             files: Arc::new(RwLock::new(Vec::new())),
             userspace: false,
             fmap_ret: None,
+            swap_ret: None,
             being_sigkilled: false,
 
             #[cfg(feature = "syscall_debug")]
@@ -259,13 +370,7 @@ impl Context {
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.unblock_no_ipi() {
-            // TODO: Only send IPI if currently running?
-            if let Some(cpu_id) = self.cpu_id {
-                if cpu_id != crate::cpu_id() {
-                    // Send IPI if not on current CPU
-                    ipi(IpiKind::Wakeup, IpiTarget::Other);
-                }
-            }
+            self.wakeup_queue_cpu();
 
             true
         } else {
@@ -278,6 +383,8 @@ impl Context {
         if self.status.is_soft_blocked() {
             self.status = Status::Runnable;
             self.status_reason = "";
+            self.sched_stats.unblocked(crate::time::monotonic());
+            context::switch::enqueue(self);
 
             true
         } else {
@@ -285,6 +392,75 @@ impl Context {
         }
     }
 
+    /// Make the context runnable regardless of why it was blocked, e.g. when it is started or
+    /// resumed after being stopped.
+    pub fn set_runnable(&mut self) {
+        if !self.status.is_runnable() {
+            self.sched_stats.unblocked(crate::time::monotonic());
+        }
+        self.status = Status::Runnable;
+        self.status_reason = "";
+        context::switch::enqueue(self);
+        self.wakeup_queue_cpu();
+    }
+
+    /// Priority derived from the real-time priority, including any inherited one, or the nice
+    /// value, where lower values are more important.
+    pub fn sched_priority(&self) -> u8 {
+        if let Some(rt_priority) = self.rt_priority() {
+            context::switch::RT_PRIORITY_MAX - rt_priority
+        } else {
+            (i16::from(context::switch::DEFAULT_PRIORITY) + i16::from(self.sched_nice)) as u8
+        }
+    }
+
+    /// The real-time priority this context is scheduled with, if any, which is the higher of its
+    /// own and the one inherited through PI futexes.
+    pub fn rt_priority(&self) -> Option<u8> {
+        let own = self
+            .sched_policy
+            .is_realtime()
+            .then_some(self.sched_rt_priority);
+        own.max(self.sched_pi_boost)
+    }
+
+    /// Queue this context again after its scheduling policy or priority changed, and have the CPU
+    /// it is queued or running on reschedule, as it may now have to preempt or be preempted.
+    /// Returns whether that is the current CPU, which the caller must then switch on once it no
+    /// longer holds the context lock.
+    pub fn reschedule(&mut self) -> bool {
+        context::switch::requeue(self);
+
+        let cpu_id = if self.running {
+            self.cpu_id
+        } else {
+            self.sched_queue
+        };
+        match cpu_id {
+            Some(cpu_id) if cpu_id == crate::cpu_id() => true,
+            Some(cpu_id) => {
+                ipi_single(IpiKind::Switch, cpu_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn wakeup_queue_cpu(&self) {
+        if let Some(cpu_id) = self.sched_queue {
+            if cpu_id != crate::cpu_id() {
+                // Send IPI if queued on another CPU, which may be halted. Real-time contexts
+                // should preempt whatever is running there right away.
+                let kind = if self.rt_priority().is_some() {
+                    IpiKind::Switch
+                } else {
+                    IpiKind::Wakeup
+                };
+                ipi_single(kind, cpu_id);
+            }
+        }
+    }
+
     /// Add a file to the lowest available slot.
     /// Return the file descriptor number or None if no slot was found
     pub fn add_file(&self, file: FileDescriptor) -> Option<FileHandle> {
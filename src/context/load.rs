@@ -0,0 +1,187 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{cpu_set::LogicalCpuId, percpu, percpu::PercpuBlock, time::NANOS_PER_SEC};
+
+/// Load averages are fixed-point numbers with this many fractional bits.
+pub const LOAD_SHIFT: u32 = 11;
+const LOAD_ONE: u64 = 1 << LOAD_SHIFT;
+
+/// Interval between load average updates.
+const LOAD_INTERVAL: u128 = 5 * NANOS_PER_SEC;
+
+/// Upper bound on the number of missed intervals to catch up with, beyond which all load averages
+/// have decayed to the current load anyway.
+const LOAD_INTERVALS_MAX: u128 = 1024;
+
+/// Decay factors for the 1, 5 and 15 minute load averages per [`LOAD_INTERVAL`], which are
+/// `LOAD_ONE / exp(5s / 1min)` and so on. These are the same factors as used by Linux.
+const LOAD_DECAY: [u64; 3] = [1884, 2014, 2037];
+
+/// The 1, 5 and 15 minute load averages, as fixed-point numbers with [`LOAD_SHIFT`] fractional bits
+static LOAD_AVG: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static LOAD_NEXT_UPDATE: AtomicU64 = AtomicU64::new(0);
+
+/// Time spent by a CPU in each state, in nanoseconds.
+#[derive(Default)]
+pub struct CpuStats {
+    /// Running contexts other than the idle context
+    pub busy_time: AtomicU64,
+    /// Running the idle context, typically halted
+    pub idle_time: AtomicU64,
+    /// Handling interrupts
+    pub irq_time: AtomicU64,
+    /// Number of context switches
+    pub switches: AtomicU64,
+
+    /// Whether the current context is the idle context
+    running_idle: AtomicBool,
+    // Time up to which the above times have been accounted
+    accounted_until: AtomicU64,
+    irq_nesting: Cell<usize>,
+}
+impl CpuStats {
+    fn charge(&self, counter: &AtomicU64, now: u128) {
+        let now = u64::try_from(now).unwrap_or(u64::MAX);
+        let delta = now.saturating_sub(self.accounted_until.swap(now, Ordering::Relaxed));
+        counter.fetch_add(delta, Ordering::Relaxed);
+    }
+    /// Account the time up to `now` to the context that was running.
+    fn charge_running(&self, now: u128) {
+        if self.running_idle.load(Ordering::Relaxed) {
+            self.charge(&self.idle_time, now);
+        } else {
+            self.charge(&self.busy_time, now);
+        }
+    }
+    /// Called when switching contexts, after `now` was charged to the previous context. When
+    /// switching from an interrupt handler, the previous context may return from it on another
+    /// CPU, so the nesting starts over for the next context.
+    pub fn switched(&self, now: u128, next_is_idle: bool) {
+        if self.irq_nesting.replace(0) > 0 {
+            self.charge(&self.irq_time, now);
+        } else {
+            self.charge_running(now);
+        }
+        self.running_idle.store(next_is_idle, Ordering::Relaxed);
+        self.switches.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn is_running_idle(&self) -> bool {
+        self.running_idle.load(Ordering::Relaxed)
+    }
+    /// The busy, idle and IRQ times up to `now`, including time not yet accounted for because
+    /// the CPU has not ticked since, e.g. while it is halted with its tick stopped.
+    pub fn times(&self, now: u128) -> [u64; 3] {
+        let now = u64::try_from(now).unwrap_or(u64::MAX);
+        let pending = now.saturating_sub(self.accounted_until.load(Ordering::Relaxed));
+        let mut busy = self.busy_time.load(Ordering::Relaxed);
+        let mut idle = self.idle_time.load(Ordering::Relaxed);
+        if self.is_running_idle() {
+            idle += pending;
+        } else {
+            busy += pending;
+        }
+        [busy, idle, self.irq_time.load(Ordering::Relaxed)]
+    }
+}
+
+/// Mark the start of interrupt handling on this CPU, which is accounted as IRQ time rather than
+/// as time of the interrupted context.
+pub fn irq_enter() {
+    let stats = &PercpuBlock::current().switch_internals.cpu_stats;
+    let nesting = stats.irq_nesting.get();
+    if nesting == 0 {
+        stats.charge_running(crate::time::monotonic());
+    }
+    stats.irq_nesting.set(nesting + 1);
+}
+
+/// Mark the end of interrupt handling on this CPU.
+pub fn irq_exit() {
+    let stats = &PercpuBlock::current().switch_internals.cpu_stats;
+    let nesting = stats.irq_nesting.get();
+    if nesting == 0 {
+        // The handler switched contexts, after which the nesting started over.
+        return;
+    }
+    stats.irq_nesting.set(nesting - 1);
+    if nesting == 1 {
+        stats.charge(&stats.irq_time, crate::time::monotonic());
+    }
+}
+
+/// Accounts the time until it is dropped as IRQ time, for the interrupt entry paths.
+pub struct IrqGuard(());
+impl IrqGuard {
+    pub fn enter() -> Self {
+        irq_enter();
+        Self(())
+    }
+}
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        irq_exit();
+    }
+}
+
+/// Bring the per-CPU times up to date, and update the load averages if due. Called on every tick.
+pub fn tick(now: u128) {
+    let stats = &PercpuBlock::current().switch_internals.cpu_stats;
+    if stats.irq_nesting.get() == 0 {
+        stats.charge_running(now);
+    }
+
+    let next_update = LOAD_NEXT_UPDATE.load(Ordering::Relaxed);
+    if now < u128::from(next_update) {
+        return;
+    }
+    let missed = if next_update == 0 {
+        1
+    } else {
+        (now - u128::from(next_update)) / LOAD_INTERVAL + 1
+    };
+    let new_next_update = now + LOAD_INTERVAL;
+    // Only one CPU updates the load averages for each interval.
+    if LOAD_NEXT_UPDATE
+        .compare_exchange(
+            next_update,
+            u64::try_from(new_next_update).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        return;
+    }
+
+    let active = nr_active() * LOAD_ONE;
+    for (avg, decay) in LOAD_AVG.iter().zip(LOAD_DECAY) {
+        let mut load = avg.load(Ordering::Relaxed);
+        // Intervals may have been missed while all CPUs had their tick stopped, during which the
+        // load is assumed to have been the same as now.
+        for _ in 0..missed.min(LOAD_INTERVALS_MAX) {
+            load = (load * decay + active * (LOAD_ONE - decay)) / LOAD_ONE;
+        }
+        avg.store(load, Ordering::Relaxed);
+    }
+}
+
+/// Number of contexts that are running or waiting to run, on all CPUs.
+pub fn nr_active() -> u64 {
+    (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter_map(percpu::get)
+        .map(|block| {
+            let internals = &block.switch_internals;
+            internals.run_queue.len() as u64 + u64::from(!internals.cpu_stats.is_running_idle())
+        })
+        .sum()
+}
+
+/// The 1, 5 and 15 minute load averages, as fixed-point numbers with [`LOAD_SHIFT`] fractional
+/// bits.
+pub fn load_avg() -> [u64; 3] {
+    LOAD_AVG.each_ref().map(|avg| avg.load(Ordering::Relaxed))
+}
@@ -1,4 +1,8 @@
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
 use arrayvec::ArrayVec;
 use core::{
     cmp,
@@ -7,7 +11,7 @@ use core::{
     sync::atomic::{AtomicU32, Ordering},
 };
 use rmm::{Arch as _, PageFlush};
-use spin::{RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard};
+use spin::{Mutex, RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard};
 use syscall::{error::*, flag::MapFlags, GrantFlags, MunmapFlags};
 
 use crate::{
@@ -15,11 +19,17 @@ use crate::{
     context::arch::setup_new_utable,
     cpu_set::LogicalCpuSet,
     memory::{
-        deallocate_frame, deallocate_p2frame, get_page_info, init_frame, the_zeroed_frame,
-        AddRefError, Enomem, Frame, PageInfo, RaiiFrame, RefCount, RefKind,
+        allocate_p2frame, deallocate_frame, deallocate_p2frame, get_page_info, huge,
+        hugepool::{self, HugePageSize},
+        init_frame, ksm,
+        limit::{self, MemoryLimit},
+        mlock, oom,
+        swap::{self, SwapSlot},
+        the_zeroed_frame, AddRefError, Enomem, Frame, PageInfo, RaiiFrame, RefCount, RefKind,
     },
-    paging::{Page, PageFlags, PageMapper, RmmA, TableKind, VirtualAddress},
+    paging::{entry::EntryFlags, Page, PageFlags, PageMapper, RmmA, TableKind, VirtualAddress},
     percpu::PercpuBlock,
+    random,
     scheme::{self, KernelSchemes},
 };
 
@@ -27,6 +37,26 @@ use super::{context::HardBlockedReason, file::FileDescription};
 
 pub const MMAP_MIN_DEFAULT: usize = PAGE_SIZE;
 
+/// Number of pages at the bottom of the reservation of a grow-down stack that it never grows
+/// into.
+pub const STACK_GUARD_PAGES: usize = 1;
+
+/// Upper bound of the random offset of the mmap base above `mmap_min`, in pages: 1 TiB on 64-bit
+/// architectures and 1 MiB on 32-bit ones, but at most a quarter of the user address space.
+#[cfg(target_pointer_width = "64")]
+const ASLR_MMAP_PAGES: usize = 1 << 28;
+#[cfg(target_pointer_width = "32")]
+const ASLR_MMAP_PAGES: usize = 1 << 8;
+
+/// Upper bound of the random gap left before each mapping placed by the kernel, in multiples of
+/// its alignment.
+const ASLR_GAP_MAX: usize = 16;
+
+fn random_mmap_offset() -> usize {
+    let bound = cmp::min(ASLR_MMAP_PAGES, crate::USER_END_OFFSET / PAGE_SIZE / 4);
+    random::below(bound) * PAGE_SIZE
+}
+
 pub fn page_flags(flags: MapFlags) -> PageFlags<RmmA> {
     PageFlags::new()
         .user(true)
@@ -83,12 +113,15 @@ impl UnmapResult {
 pub struct AddrSpaceWrapper {
     inner: RwLock<AddrSpace>,
     pub tlb_ack: AtomicU32,
+    /// Memory charged to the limits of process groups, locked after the address space.
+    charge: Mutex<limit::Charge>,
 }
 impl AddrSpaceWrapper {
     pub fn new() -> Result<Arc<Self>> {
         Arc::try_new(Self {
             inner: RwLock::new(AddrSpace::new()?),
             tlb_ack: AtomicU32::new(0),
+            charge: Mutex::new(limit::Charge::default()),
         })
         .map_err(|_| Error::new(ENOMEM))
     }
@@ -143,6 +176,14 @@ pub struct AddrSpace {
     /// the exception that we have a memory safe kernel which doesn't have to protect itself
     /// against null pointers, so fixed mmaps to address zero are still allowed.
     pub mmap_min: usize,
+    /// The user that new mappings are locked for, after mlockall with MCL_FUTURE, and whether
+    /// they are only faulted in when accessed, due to MCL_ONFAULT.
+    pub mlock_future: Option<u32>,
+    pub mlock_future_on_fault: bool,
+    /// Whether mappings placed by the kernel are randomized, starting from a random base
+    /// `mmap_offset` bytes above `mmap_min` and separated by random gaps.
+    aslr: bool,
+    mmap_offset: usize,
 }
 impl AddrSpaceWrapper {
     /// Attempt to clone an existing address space so that all mappings are copied (CoW).
@@ -154,12 +195,16 @@ impl AddrSpaceWrapper {
 
         let new =
             Arc::get_mut(&mut new_arc).expect("expected new address space Arc not to be aliased");
+        new.inner.get_mut().aslr = guard.aslr;
+        new.inner.get_mut().mmap_offset = guard.mmap_offset;
 
         let this_mapper = &mut guard.table.utable;
         let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        // Shared grants whose unmapped pages have been mapped here as well while copying them.
+        let mut populated = Vec::new();
 
         for (grant_base, grant_info) in guard.grants.iter() {
-            let new_grant = match grant_info.provider {
+            let mut new_grant = match grant_info.provider {
                 // No, your temporary UserScheme mappings will not be kept across forks.
                 Provider::External {
                     is_pinned_userscheme_borrow: true,
@@ -174,7 +219,8 @@ impl AddrSpaceWrapper {
                 Provider::Allocated {
                     phys_contiguous: true,
                     ..
-                } => continue,
+                }
+                | Provider::HugePool { .. } => continue,
 
                 Provider::PhysBorrowed { base } => Grant::physmap(
                     base.clone(),
@@ -202,17 +248,20 @@ impl AddrSpaceWrapper {
                 // TODO: Merge Allocated and AllocatedShared, and make CopyMappingsMode a field?
                 Provider::AllocatedShared {
                     is_pinned_userscheme_borrow: false,
-                } => Grant::copy_mappings(
-                    grant_base,
-                    grant_base,
-                    grant_info.page_count,
-                    grant_info.flags,
-                    this_mapper,
-                    &mut new.inner.get_mut().table.utable,
-                    &mut this_flusher,
-                    &mut NopFlusher,
-                    CopyMappingsMode::Borrowed,
-                )?,
+                } => {
+                    populated.push(grant_base);
+                    Grant::copy_mappings(
+                        grant_base,
+                        grant_base,
+                        grant_info.page_count,
+                        grant_info.flags,
+                        this_mapper,
+                        &mut new.inner.get_mut().table.utable,
+                        &mut this_flusher,
+                        &mut NopFlusher,
+                        CopyMappingsMode::Borrowed,
+                    )?
+                }
 
                 // MAP_SHARED grants are retained by reference, across address space clones (the
                 // "fork" analogue from monolithic kernels).
@@ -230,10 +279,26 @@ impl AddrSpaceWrapper {
                     false,
                 )?,
                 Provider::FmapBorrowed { .. } => continue,
+                Provider::Guard => Grant::guard(PageSpan::new(grant_base, grant_info.page_count)),
             };
-
+            new_grant.info.grows_down = grant_info.grows_down;
+            new_grant.info.mergeable = grant_info.mergeable;
+
+            // Paged out pages are shared with the new address space just like CoW pages, and are
+            // paged in separately by both.
+            if let Provider::Allocated { .. } = grant_info.provider {
+                for (page, slot) in guard.grants.swapped_in_span(new_grant.span()) {
+                    new.inner
+                        .get_mut()
+                        .grants
+                        .set_swapped(page, swap::dup(slot));
+                }
+            }
             new.inner.get_mut().grants.insert(new_grant);
         }
+        for base in populated {
+            guard.grants.recount_resident(base, this_mapper);
+        }
         Ok(new_arc)
     }
     pub fn mprotect(&self, requested_span: PageSpan, flags: MapFlags) -> Result<()> {
@@ -248,26 +313,31 @@ impl AddrSpaceWrapper {
             .grants
             .conflicts(requested_span)
             .map(|(base, info)| {
+                let grant_span = PageSpan::new(base, info.page_count);
                 if info.is_pinned() {
                     Err(Error::new(EBUSY))
+                } else if !info.can_split_at(base, grant_span.intersection(requested_span)) {
+                    Err(Error::new(EINVAL))
                 } else {
-                    Ok(PageSpan::new(base, info.page_count))
+                    Ok(grant_span)
                 }
             })
             .collect::<Vec<_>>();
 
         for grant_span_res in regions {
             let grant_span = grant_span_res?;
+            let intersection = grant_span.intersection(requested_span);
+            split_huge_pages_at(mapper, intersection.base, &mut flusher)?;
+            split_huge_pages_at(mapper, intersection.end(), &mut flusher)?;
 
             let grant = guard
                 .grants
                 .remove(grant_span.base)
                 .expect("grant cannot magically disappear while we hold the lock!");
             //log::info!("Mprotecting {:#?} to {:#?} in {:#?}", grant, flags, grant_span);
-            let intersection = grant_span.intersection(requested_span);
 
             let (before, mut grant, after) = grant
-                .extract(intersection)
+                .extract(intersection, mapper)
                 .expect("failed to extract grant");
             //log::info!("Sliced into\n\n{:#?}\n\n{:#?}\n\n{:#?}", before, grant, after);
 
@@ -295,6 +365,7 @@ impl AddrSpaceWrapper {
             // think), execute-only memory is also supported.
 
             grant.remap(mapper, &mut flusher, new_flags);
+            guard.grants.forget_lazy_free(grant.span());
             //log::info!("Mprotect grant became {:#?}", grant);
             guard.grants.insert(grant);
         }
@@ -365,8 +436,7 @@ impl AddrSpaceWrapper {
                 base
             }
             _ => {
-                dst.grants
-                    .find_free(dst.mmap_min, cmp::max(new_page_count, src_span.count))
+                dst.find_free(cmp::max(new_page_count, src_span.count))
                     .ok_or(Error::new(ENOMEM))?
                     .base
             }
@@ -383,6 +453,13 @@ impl AddrSpaceWrapper {
         {
             return Err(Error::new(EBUSY));
         }
+        // Moving pool huge pages would require splitting them.
+        if src_grants
+            .conflicts(src_span)
+            .any(|(_, g)| matches!(g.provider, Provider::HugePool { .. }))
+        {
+            return Err(Error::new(EINVAL));
+        }
         if src_grants
             .conflicts(src_span)
             .any(|(_, g)| !g.can_have_flags(new_flags))
@@ -408,6 +485,9 @@ impl AddrSpaceWrapper {
         }
 
         let mut remaining_src_span = PageSpan::new(src_span.base, new_page_count);
+        // Grants are moved page by page, so huge pages are split up front, where running out of
+        // memory still leaves every grant in place.
+        split_huge_pages(src_mapper, remaining_src_span, src_flusher)?;
 
         let to_remap = src_grants
             .conflicts(remaining_src_span)
@@ -433,7 +513,7 @@ impl AddrSpaceWrapper {
                 )?);
             }
 
-            let (src_grants, _, _) = src_opt.as_mut().map_or(
+            let (src_grants, src_mapper, _) = src_opt.as_mut().map_or(
                 (&mut dst.grants, &mut dst.table.utable, &mut dst_flusher),
                 |(g, m, f)| (&mut *g, &mut *m, &mut *f),
             );
@@ -442,7 +522,7 @@ impl AddrSpaceWrapper {
                 .expect("grant cannot disappear");
             let grant_span = PageSpan::new(grant.base, grant.info.page_count());
             let (before, middle, after) = grant
-                .extract(remaining_src_span.intersection(grant_span))
+                .extract(remaining_src_span.intersection(grant_span), src_mapper)
                 .expect("called intersect(), must succeed");
 
             if let Some(before) = before {
@@ -454,6 +534,8 @@ impl AddrSpaceWrapper {
 
             let dst_grant_base = dst_base.next_by(middle.base.offset_from(src_span.base));
             let middle_span = middle.span();
+            let swapped = src_grants.take_swapped_span(middle_span);
+            src_grants.forget_lazy_free(middle_span);
 
             let mut src_opt = src_opt
                 .as_mut()
@@ -478,6 +560,13 @@ impl AddrSpaceWrapper {
                 )?,
             });
 
+            for (page, slot) in swapped {
+                dst.grants.set_swapped(
+                    dst_grant_base.next_by(page.offset_from(middle_span.base)),
+                    slot,
+                );
+            }
+
             prev_grant_end = middle_span.base.next_by(middle_span.count);
             let pages_advanced = prev_grant_end.offset_from(remaining_src_span.base);
             remaining_src_span =
@@ -513,6 +602,14 @@ impl AddrSpaceWrapper {
         if !matches!(info.provider, Provider::Allocated { .. }) {
             return Err(Error::new(EPERM));
         }
+        {
+            let guard = &mut *guard;
+            split_huge_pages(
+                &mut guard.table.utable,
+                PageSpan::new(page, 1),
+                &mut Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack),
+            )?;
+        }
 
         let frame = if let Some((f, fl)) = guard.table.utable.translate(page.start_address())
             && fl.has_write()
@@ -524,23 +621,727 @@ impl AddrSpaceWrapper {
             flush.flush();
             guard = new_guard;
 
-            frame
-        };
+            frame
+        };
+
+        let frame = match get_page_info(frame)
+            .expect("missing page info for Allocated grant")
+            .add_ref(RefKind::Shared)
+        {
+            Ok(_) => Ok(unsafe { RaiiFrame::new_unchecked(frame) }),
+            Err(AddRefError::RcOverflow) => Err(Error::new(ENOMEM)),
+            Err(AddRefError::SharedToCow) => unreachable!(),
+            Err(AddRefError::CowToShared) => unreachable!(
+                "if it was CoW, it was read-only, but in that case we already called correct_inner"
+            ),
+        };
+        drop(guard);
+
+        frame
+    }
+    /// Split the huge pages in `span`, so that their frames can be shared individually.
+    pub fn split_huge_pages(&self, span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        split_huge_pages(
+            &mut guard.table.utable,
+            span,
+            &mut Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack),
+        )?;
+        Ok(())
+    }
+    /// Advise how `requested_span`, which must be entirely mapped, will be used. The contents of
+    /// pages can only be dropped in private anonymous memory, and readahead only applies to
+    /// file-backed grants.
+    pub fn madvise(self: &Arc<Self>, requested_span: PageSpan, advice: Advice) -> Result<()> {
+        let guard = self.acquire_write();
+        if !guard.grants.is_mapped(requested_span) {
+            return Err(Error::new(ENOMEM));
+        }
+
+        match advice {
+            Advice::Normal => self.set_readahead(guard, requested_span, Readahead::Normal),
+            Advice::Random => self.set_readahead(guard, requested_span, Readahead::Random),
+            Advice::Sequential => self.set_readahead(guard, requested_span, Readahead::Sequential),
+            Advice::WillNeed => self.populate(guard, requested_span),
+            Advice::DontNeed => self.discard(guard, requested_span, false),
+            Advice::Free => self.discard(guard, requested_span, true),
+            Advice::Mergeable => self.set_mergeable(guard, requested_span, true),
+            Advice::Unmergeable => self.set_mergeable(guard, requested_span, false),
+        }
+    }
+    fn set_readahead(
+        &self,
+        mut guard: RwLockWriteGuard<'_, AddrSpace>,
+        requested_span: PageSpan,
+        readahead: Readahead,
+    ) -> Result<()> {
+        let regions = guard
+            .grants
+            .conflicts(requested_span)
+            .filter(|(_, info)| matches!(info.provider, Provider::FmapBorrowed { .. }))
+            .map(|(base, info)| {
+                if info.is_pinned() {
+                    Err(Error::new(EBUSY))
+                } else {
+                    Ok(PageSpan::new(base, info.page_count))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for grant_span_res in regions {
+            let grant_span = grant_span_res?;
+            let grant = guard
+                .grants
+                .remove(grant_span.base)
+                .expect("grant cannot magically disappear while we hold the lock!");
+
+            let (before, mut grant, after) = grant
+                .extract(grant_span.intersection(requested_span), &guard.table.utable)
+                .expect("failed to extract grant");
+
+            if let Some(before) = before {
+                guard.grants.insert(before);
+            }
+            if let Some(after) = after {
+                guard.grants.insert(after);
+            }
+
+            if let Provider::FmapBorrowed {
+                readahead: ref mut grant_readahead,
+                ..
+            } = grant.info.provider
+            {
+                *grant_readahead = readahead;
+            }
+            guard.grants.insert(grant);
+        }
+        Ok(())
+    }
+    /// Allow or disallow KSM to merge the private anonymous memory in `span`, splitting grants at
+    /// its boundaries. Other grants are left alone, and pages that have already been merged stay
+    /// shared until written to.
+    fn set_mergeable(
+        &self,
+        mut guard: RwLockWriteGuard<'_, AddrSpace>,
+        span: PageSpan,
+        mergeable: bool,
+    ) -> Result<()> {
+        let guard = &mut *guard;
+        let regions = guard
+            .grants
+            .conflicts(span)
+            .filter(|(_, info)| {
+                info.mergeable != mergeable
+                    && matches!(
+                        info.provider,
+                        Provider::Allocated {
+                            cow_file_ref: None,
+                            phys_contiguous: false,
+                        }
+                    )
+            })
+            .map(|(base, info)| PageSpan::new(base, info.page_count))
+            .collect::<Vec<_>>();
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+
+        for grant_span in regions {
+            let intersection = grant_span.intersection(span);
+            split_huge_pages_at(mapper, intersection.base, &mut flusher)?;
+            split_huge_pages_at(mapper, intersection.end(), &mut flusher)?;
+
+            let mut grant = guard
+                .grants
+                .remove(grant_span.base)
+                .expect("grant cannot magically disappear while we hold the lock!");
+
+            if intersection.count != grant_span.count {
+                let (before, middle, after) = grant
+                    .extract(intersection, mapper)
+                    .expect("failed to extract grant");
+                if let Some(before) = before {
+                    guard.grants.insert(before);
+                }
+                if let Some(after) = after {
+                    guard.grants.insert(after);
+                }
+                grant = middle;
+            }
+            grant.info.mergeable = mergeable;
+            guard.grants.insert(grant);
+        }
+        if mergeable {
+            ksm::wake();
+        }
+        Ok(())
+    }
+    /// Pages of grants that KSM may merge, starting at `from`, up to `max` pages.
+    pub fn mergeable_pages(&self, from: Page, max: usize) -> Vec<Page> {
+        let guard = self.acquire_read();
+        guard
+            .grants
+            .conflicts(PageSpan::between(
+                from,
+                Page::containing_address(VirtualAddress::new(crate::USER_END_OFFSET)),
+            ))
+            .filter(|(_, info)| info.mergeable)
+            .flat_map(|(base, info)| PageSpan::new(base, info.page_count).pages())
+            .filter(|page| *page >= from)
+            .take(max)
+            .collect()
+    }
+    /// Hash the contents of `page`, if it can be merged by KSM.
+    pub fn ksm_hash(&self, page: Page) -> Option<u64> {
+        let guard = self.acquire_read();
+        let (frame, _) = ksm_frame(&guard, page)?;
+        Some(ksm::hash(frame))
+    }
+    /// Turn the frame of `page` into a frame shared by KSM, if it can be merged and still has the
+    /// given hash. The page is write-protected, so that writing to it copies it, and KSM keeps a
+    /// reference to the frame, so that it is never written to again.
+    pub fn ksm_promote(&self, page: Page, hash: u64) -> Option<Frame> {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        let (frame, _) = ksm_frame(guard, page)?;
+        ksm_write_protect(guard, &self.tlb_ack, page, frame);
+
+        if ksm::hash(frame) != hash {
+            return None;
+        }
+        get_page_info(frame)?.add_ref(RefKind::Cow).ok()?;
+        Some(frame)
+    }
+    /// Map the frame `shared` by KSM at `page` instead of its own frame, if it can be merged and
+    /// has the same contents. Returns whether the page was merged.
+    pub fn ksm_merge(&self, page: Page, shared: Frame) -> bool {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        let Some((frame, flags)) = ksm_frame(guard, page) else {
+            return false;
+        };
+        ksm_write_protect(guard, &self.tlb_ack, page, frame);
+
+        if !ksm::same_contents(frame, shared) {
+            return false;
+        }
+        let Some(shared_info) = get_page_info(shared) else {
+            return false;
+        };
+        if shared_info.add_ref(RefKind::Cow).is_err() {
+            return false;
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        unsafe {
+            let (_, _, flush) = mapper
+                .unmap_phys(page.start_address(), false)
+                .expect("page was mapped while the address space was locked");
+            flush.ignore();
+            // The page table was not freed, so mapping cannot fail.
+            mapper
+                .map_phys(page.start_address(), shared.base(), flags.write(false))
+                .expect("failed to map merged page")
+                .ignore();
+        }
+        flusher.queue(frame, None, TlbShootdownActions::FREE);
+        true
+    }
+    /// Fault in the pages of `span` that are file-backed or paged out, so that accessing them
+    /// later does not block.
+    fn populate<'l>(
+        self: &'l Arc<Self>,
+        mut guard: RwLockWriteGuard<'l, AddrSpace>,
+        span: PageSpan,
+    ) -> Result<()> {
+        for page in span.pages() {
+            let Some((_, info)) = guard.grants.contains(page) else {
+                continue;
+            };
+            let is_needed = match info.provider {
+                Provider::FmapBorrowed { .. } => true,
+                Provider::Allocated { .. } => guard.grants.swapped(page).is_some(),
+                _ => false,
+            };
+            // Pages read ahead by previous iterations are already mapped.
+            if !is_needed || huge::translate(&guard.table.utable, page.start_address()).is_some() {
+                continue;
+            }
+            let (_, flush, new_guard) = correct_inner(self, guard, page, AccessMode::Read, 0)
+                .map_err(|_| Error::new(ENOMEM))?;
+            flush.flush();
+            guard = new_guard;
+        }
+        Ok(())
+    }
+    /// Drop the contents of the pages in `span`, so that they read as zero afterwards. If `lazy`,
+    /// the frames of pages that are not shared are instead only freed when memory runs low, and
+    /// not at all if written to before that.
+    fn discard(
+        &self,
+        mut guard: RwLockWriteGuard<'_, AddrSpace>,
+        span: PageSpan,
+        lazy: bool,
+    ) -> Result<()> {
+        let guard = &mut *guard;
+
+        for (_, info) in guard.grants.conflicts(span) {
+            if info.is_pinned() {
+                return Err(Error::new(EBUSY));
+            }
+            if info.is_locked() {
+                return Err(Error::new(EINVAL));
+            }
+            // Private file mappings cannot be read from the file again.
+            if !matches!(
+                info.provider,
+                Provider::Allocated {
+                    cow_file_ref: None,
+                    phys_contiguous: false,
+                }
+            ) {
+                return Err(Error::new(EINVAL));
+            }
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        split_huge_pages_at(mapper, span.base, &mut flusher)?;
+        split_huge_pages_at(mapper, span.end(), &mut flusher)?;
+
+        // Without a dirty bit, pages written to after MADV_FREE cannot be told apart.
+        let lazy = lazy && EntryFlags::DIRTY.bits() != 0;
+
+        let zeroed = the_zeroed_frame().0;
+        let mut page = span.base;
+        while page < span.end() {
+            if let Some(mapping) = unsafe { huge::unmap(mapper, page) } {
+                flusher.queue(
+                    mapping.frame,
+                    NonZeroUsize::new(mapping.page_count()),
+                    TlbShootdownActions::FREE,
+                );
+                guard.grants.sub_resident(page, mapping.page_count());
+                page = mapping.span().end();
+                continue;
+            }
+            if let Some(slot) = guard.grants.take_swapped(page) {
+                swap::release(slot);
+            }
+
+            let frame = mapper
+                .translate(page.start_address())
+                .map(|(phys, _)| Frame::containing(phys));
+            // Shared frames, including the zeroed frame, are unmapped right away.
+            if lazy
+                && let Some(frame) = frame
+                && get_page_info(frame).and_then(PageInfo::refcount) == Some(RefCount::One)
+            {
+                if let Some((_, _, flush)) = unsafe {
+                    mapper.remap_with(page.start_address(), |flags| {
+                        flags.custom_flag(EntryFlags::DIRTY.bits(), false)
+                    })
+                } {
+                    unsafe {
+                        flush.ignore();
+                    }
+                    flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
+                }
+                guard.grants.lazy_free.insert(page);
+            } else if let Some((phys, _, flush)) =
+                unsafe { mapper.unmap_phys(page.start_address(), true) }
+            {
+                unsafe {
+                    flush.ignore();
+                }
+                flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
+                guard.grants.lazy_free.remove(&page);
+                if Frame::containing(phys) != zeroed {
+                    guard.grants.sub_resident(page, 1);
+                }
+            }
+            page = page.next_by(1);
+        }
+        Ok(())
+    }
+    /// Lock the pages of `requested_span`, which must be entirely mapped, charging them to `uid`.
+    /// Unless `on_fault`, they are also faulted in right away. Locked pages are never paged out or
+    /// otherwise reclaimed.
+    pub fn mlock(
+        self: &Arc<Self>,
+        requested_span: PageSpan,
+        uid: u32,
+        on_fault: bool,
+    ) -> Result<()> {
+        let mut guard = self.acquire_write();
+        if !guard.grants.is_mapped(requested_span) {
+            return Err(Error::new(ENOMEM));
+        }
+        self.set_locked(&mut guard, requested_span, Some(uid))?;
+
+        if on_fault {
+            return Ok(());
+        }
+        self.fault_in(guard, requested_span).map(drop)
+    }
+    /// Unlock the pages of `requested_span`, which must be entirely mapped.
+    pub fn munlock(&self, requested_span: PageSpan) -> Result<()> {
+        let mut guard = self.acquire_write();
+        if !guard.grants.is_mapped(requested_span) {
+            return Err(Error::new(ENOMEM));
+        }
+        self.set_locked(&mut guard, requested_span, None)
+    }
+    /// Lock all current mappings, future mappings, or both, depending on `flags`.
+    pub fn mlockall(self: &Arc<Self>, flags: MlockallFlags, uid: u32) -> Result<()> {
+        if !flags.intersects(MlockallFlags::CURRENT | MlockallFlags::FUTURE) {
+            return Err(Error::new(EINVAL));
+        }
+        let mut guard = self.acquire_write();
+
+        // Future mappings are only locked once all current ones could be.
+        if flags.contains(MlockallFlags::CURRENT) {
+            let span = PageSpan::user_range();
+            self.set_locked(&mut guard, span, Some(uid))?;
+
+            if !flags.contains(MlockallFlags::ONFAULT) {
+                guard = self.fault_in(guard, span)?;
+            }
+        }
+        guard.mlock_future_on_fault = flags.contains(MlockallFlags::ONFAULT);
+        guard.mlock_future = flags.contains(MlockallFlags::FUTURE).then_some(uid);
+        Ok(())
+    }
+    /// Fault in a new mapping at `span` if it was locked due to mlockall with MCL_FUTURE, unless
+    /// MCL_ONFAULT was given as well. Pages that cannot be faulted in for lack of memory stay
+    /// locked, and are faulted in when accessed like any other.
+    pub fn fault_in_future_locked(self: &Arc<Self>, span: PageSpan) {
+        let guard = self.acquire_write();
+        if guard.mlock_future.is_none() || guard.mlock_future_on_fault {
+            return;
+        }
+        let _ = self.fault_in(guard, span);
+    }
+    /// Unlock all mappings, and stop locking future mappings.
+    pub fn munlockall(&self) -> Result<()> {
+        let mut guard = self.acquire_write();
+        guard.mlock_future = None;
+        self.set_locked(&mut guard, PageSpan::user_range(), None)
+    }
+    /// Lock or unlock the grants in `span`, splitting them at its boundaries. Grants that are
+    /// already locked keep being charged to the user that locked them first.
+    fn set_locked(
+        &self,
+        addr_space: &mut AddrSpace,
+        span: PageSpan,
+        locked: Option<u32>,
+    ) -> Result<()> {
+        let regions = addr_space
+            .grants
+            .conflicts(span)
+            // Guard reservations have no pages to lock.
+            .filter(|(_, info)| !info.is_guard() && info.is_locked() != locked.is_some())
+            .map(|(base, info)| {
+                let grant_span = PageSpan::new(base, info.page_count);
+                let intersection = grant_span.intersection(span);
+                if intersection.count == grant_span.count {
+                    Ok(grant_span)
+                } else if info.is_pinned() {
+                    Err(Error::new(EBUSY))
+                } else if !info.can_split_at(base, intersection) {
+                    Err(Error::new(EINVAL))
+                } else {
+                    Ok(grant_span)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Number of pages charged for regions that have not been locked yet, which are given back
+        // if locking fails.
+        let mut charged = 0;
+        if let Some(uid) = locked {
+            charged = regions
+                .iter()
+                .map(|grant_span| grant_span.intersection(span).count)
+                .sum();
+            mlock::charge(uid, charged)?;
+        }
+
+        let mapper = &mut addr_space.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &self.tlb_ack);
+
+        for grant_span in regions {
+            let intersection = grant_span.intersection(span);
+            let split = split_huge_pages_at(mapper, intersection.base, &mut flusher)
+                .and_then(|()| split_huge_pages_at(mapper, intersection.end(), &mut flusher));
+            if let Err(err) = split {
+                if let Some(uid) = locked {
+                    mlock::uncharge(uid, charged);
+                }
+                return Err(err.into());
+            }
+            charged = charged.saturating_sub(intersection.count);
+
+            let mut grant = addr_space
+                .grants
+                .remove(grant_span.base)
+                .expect("grant cannot magically disappear while we hold the lock!");
+
+            if intersection.count != grant_span.count {
+                let (before, middle, after) = grant
+                    .extract(intersection, mapper)
+                    .expect("failed to extract grant");
+                if let Some(before) = before {
+                    addr_space.grants.insert(before);
+                }
+                if let Some(after) = after {
+                    addr_space.grants.insert(after);
+                }
+                grant = middle;
+            }
+
+            // When locking, the pages have already been charged above.
+            if let Some(uid) = core::mem::replace(&mut grant.info.locked, locked) {
+                mlock::uncharge(uid, grant.info.page_count);
+            }
+            if locked.is_some() {
+                addr_space.grants.forget_lazy_free(grant.span());
+            }
+            addr_space.grants.insert(grant);
+        }
+        Ok(())
+    }
+    /// Fault in every page of the grants in `span`, writable if the grant is writable, so that
+    /// accessing them later does not fault. Returns the guard, which may be released in between.
+    fn fault_in<'l>(
+        self: &'l Arc<Self>,
+        mut guard: RwLockWriteGuard<'l, AddrSpace>,
+        span: PageSpan,
+    ) -> Result<RwLockWriteGuard<'l, AddrSpace>> {
+        let grant_spans = guard
+            .grants
+            .conflicts(span)
+            .filter(|(_, info)| !info.is_guard())
+            .map(|(base, info)| PageSpan::new(base, info.page_count).intersection(span))
+            .collect::<Vec<_>>();
+
+        for page in grant_spans.iter().flat_map(|grant_span| grant_span.pages()) {
+            let Some((_, info)) = guard.grants.contains(page) else {
+                continue;
+            };
+            let access = if info.flags().has_write() {
+                AccessMode::Write
+            } else {
+                AccessMode::Read
+            };
+            if let Some((_, flags)) = huge::translate(&guard.table.utable, page.start_address())
+                && (access == AccessMode::Read || flags.has_write())
+            {
+                continue;
+            }
+            let (_, flush, new_guard) =
+                correct_inner(self, guard, page, access, 0).map_err(|_| Error::new(ENOMEM))?;
+            flush.flush();
+            guard = new_guard;
+        }
+        Ok(guard)
+    }
+    /// Free the frames of the pages freed with MADV_FREE that have not been written to since,
+    /// returning how many were freed.
+    pub fn reclaim_lazy_free(&self) -> usize {
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        if guard.grants.lazy_free.is_empty() {
+            return 0;
+        }
+
+        let mapper = &mut guard.table.utable;
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut freed = 0;
+        for page in core::mem::take(&mut guard.grants.lazy_free) {
+            let Some((phys, flags)) = mapper.translate(page.start_address()) else {
+                continue;
+            };
+            let frame = Frame::containing(phys);
+            if flags.data() & EntryFlags::DIRTY.bits() != 0
+                || get_page_info(frame).and_then(PageInfo::refcount) != Some(RefCount::One)
+            {
+                continue;
+            }
+            let (_, flags, flush) = unsafe { mapper.unmap_phys(page.start_address(), true) }
+                .expect("page was mapped while the address space was locked");
+            unsafe {
+                flush.ignore();
+            }
+            // Written to after it was checked above, but before it was unmapped.
+            if flags.data() & EntryFlags::DIRTY.bits() != 0 {
+                if let Some(flush) = unsafe { mapper.map_phys(page.start_address(), phys, flags) } {
+                    unsafe {
+                        flush.ignore();
+                    }
+                }
+                continue;
+            }
+            flusher.queue(frame, None, TlbShootdownActions::FREE);
+            guard.grants.sub_resident(page, 1);
+            freed += 1;
+        }
+        freed
+    }
+    /// Charge `page_count` pages about to be allocated to the memory limits of the current
+    /// process, reclaiming memory of their groups if needed. Fails with ENOMEM if a limit would
+    /// still be exceeded, or has been reached if `page_count` is 0.
+    pub fn charge_memory(&self, page_count: usize) -> Result<()> {
+        loop {
+            let result = {
+                let guard = self.acquire_read();
+                self.charge
+                    .lock()
+                    .try_charge(limit::current(), page_count, || guard.resident_pages())
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(limit) if reclaim_group(&limit) => continue,
+                Err(_) => return Err(Error::new(ENOMEM)),
+            }
+        }
+    }
+    /// Give back the charge for pages that were charged with `charge_memory`, but then not
+    /// allocated.
+    pub fn uncharge_memory(&self, page_count: usize) {
+        self.charge.lock().uncharge(page_count);
+    }
+    /// Page out up to `max` pages of anonymous memory that have not been accessed since they were
+    /// last looked at, returning how many were paged out. The accessed bit of the other pages is
+    /// cleared, without flushing the TLB, which at worst makes them look cold a bit early. Cold
+    /// huge pages are split first.
+    pub fn page_out_cold(&self, max: usize) -> usize {
+        // Without an accessed bit, the pages in use cannot be told apart from the cold ones, and
+        // paging out blindly would evict the working set.
+        if EntryFlags::ACCESSED.bits() == 0 {
+            return 0;
+        }
+        let mut guard = self.acquire_write();
+        let guard = &mut *guard;
+        let mapper = &mut guard.table.utable;
+
+        let mut flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
+        let mut cold = Vec::new();
+        'grants: for (grant_base, grant_info) in guard.grants.iter() {
+            // Pinned grants are borrowed by schemes, and physically contiguous ones by drivers.
+            if grant_info.is_pinned()
+                || grant_info.is_locked()
+                || !matches!(
+                    grant_info.provider,
+                    Provider::Allocated {
+                        phys_contiguous: false,
+                        ..
+                    }
+                )
+            {
+                continue;
+            }
+            // The rest of a huge page that is still in use is skipped.
+            let mut next = grant_base;
+            for page in PageSpan::new(grant_base, grant_info.page_count).pages() {
+                if cold.len() >= max {
+                    break 'grants;
+                }
+                if page < next {
+                    continue;
+                }
+                if let Some(mapping) = huge::lookup(mapper, page) {
+                    if mapping.flags.data() & EntryFlags::ACCESSED.bits() != 0 {
+                        if let Some((_, flush)) = unsafe {
+                            huge::remap(
+                                mapper,
+                                page,
+                                mapping
+                                    .flags
+                                    .custom_flag(EntryFlags::ACCESSED.bits(), false),
+                            )
+                        } {
+                            unsafe {
+                                flush.ignore();
+                            }
+                        }
+                        next = mapping.span().end();
+                        continue;
+                    }
+                    // Cold huge pages are split, so that their pages can be paged out like any
+                    // other.
+                    if split_huge_pages(mapper, mapping.span(), &mut flusher).is_err() {
+                        next = mapping.span().end();
+                        continue;
+                    }
+                }
+                let Some((phys, flags)) = mapper.translate(page.start_address()) else {
+                    continue;
+                };
+                // Frames that are shared, either CoW or borrowed, cannot be paged out without
+                // unmapping them everywhere.
+                let frame = Frame::containing(phys);
+                if get_page_info(frame).and_then(PageInfo::refcount) != Some(RefCount::One) {
+                    continue;
+                }
+                if flags.data() & EntryFlags::ACCESSED.bits() == 0 {
+                    cold.push((page, frame, flags));
+                } else if let Some((_, _, flush)) = unsafe {
+                    mapper.remap_with(page.start_address(), |flags| {
+                        flags.custom_flag(EntryFlags::ACCESSED.bits(), false)
+                    })
+                } {
+                    unsafe {
+                        flush.ignore();
+                    }
+                }
+            }
+        }
+
+        let slots = swap::alloc_slots(cold.len());
+        cold.truncate(slots.len());
+        if cold.is_empty() {
+            return 0;
+        }
 
-        let frame = match get_page_info(frame)
-            .expect("missing page info for Allocated grant")
-            .add_ref(RefKind::Shared)
-        {
-            Ok(_) => Ok(unsafe { RaiiFrame::new_unchecked(frame) }),
-            Err(AddRefError::RcOverflow) => Err(Error::new(ENOMEM)),
-            Err(AddRefError::SharedToCow) => unreachable!(),
-            Err(AddRefError::CowToShared) => unreachable!(
-                "if it was CoW, it was read-only, but in that case we already called correct_inner"
-            ),
-        };
-        drop(guard);
+        for ((page, frame, _), slot) in cold.iter().zip(&slots) {
+            let (_, _, flush) = unsafe { mapper.unmap_phys(page.start_address(), false) }
+                .expect("page was mapped while the address space was locked");
+            unsafe {
+                flush.ignore();
+            }
+            flusher.queue(*frame, None, TlbShootdownActions::MOVE);
+            guard.grants.set_swapped(*page, *slot);
+            guard.grants.sub_resident(*page, 1);
+        }
+        // The frames must no longer be accessible through stale TLB entries once the swap daemon
+        // reads them.
+        flusher.flush();
+        drop(flusher);
 
-        frame
+        let pages = slots
+            .iter()
+            .zip(&cold)
+            .map(|(slot, (_, frame, _))| (*slot, *frame))
+            .collect();
+        if swap::page_out(pages).is_err() {
+            // The swap device went away in the meantime.
+            for (page, frame, flags) in &cold {
+                guard.grants.take_swapped(*page);
+                if let Some(flush) =
+                    unsafe { mapper.map_phys(page.start_address(), frame.base(), *flags) }
+                {
+                    unsafe {
+                        flush.ignore();
+                    }
+                    guard.grants.add_resident(*page, 1);
+                }
+            }
+            return 0;
+        }
+        cold.len()
     }
 }
 impl AddrSpace {
@@ -557,9 +1358,82 @@ impl AddrSpace {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
+            mlock_future: None,
+            mlock_future_on_fault: false,
+            aslr: true,
+            mmap_offset: random_mmap_offset(),
             used_by: LogicalCpuSet::empty(),
         })
     }
+    /// Number of pages of allocated memory that are currently mapped, not counting the zeroed
+    /// frame that untouched pages are mapped to.
+    pub fn resident_pages(&self) -> usize {
+        self.grants.resident()
+    }
+    /// Memory usage of the whole address space, counted from its page tables.
+    pub fn usage(&self) -> MemoryUsage {
+        self.grants
+            .iter()
+            .map(|(base, info)| self.grant_usage(base, info))
+            .fold(MemoryUsage::default(), |total, usage| total + usage)
+    }
+    /// Memory usage of the grant starting at `base`, counted from its page tables.
+    pub fn grant_usage(&self, base: Page, info: &GrantInfo) -> MemoryUsage {
+        let span = PageSpan::new(base, info.page_count);
+        let swapped = self.grants.swapped_in_span(span).count();
+        let mut usage = MemoryUsage {
+            size: span.count * PAGE_SIZE,
+            swapped: swapped * PAGE_SIZE,
+            ..MemoryUsage::default()
+        };
+        if info.is_guard() {
+            return usage;
+        }
+
+        let zeroed = the_zeroed_frame().0;
+        let mut present = 0;
+        // Summed in fixed point, so that the shares of pages mapped by many address spaces are
+        // not all rounded down.
+        let mut proportional = 0_u64;
+        // Only populated page tables are walked, so that sparse grants are cheap to count.
+        huge::for_each_mapped(&self.table.utable, span, |_, phys, flags, page_count| {
+            for i in 0..page_count {
+                let frame = Frame::containing(phys.add(i * PAGE_SIZE));
+                if frame == zeroed {
+                    continue;
+                }
+                present += 1;
+
+                // Frames that do not belong to the frame allocator, such as device memory, are not
+                // counted as memory used.
+                let Some(refcount) = get_page_info(frame).and_then(PageInfo::refcount) else {
+                    continue;
+                };
+                usage.resident += PAGE_SIZE;
+                match refcount {
+                    RefCount::One => {
+                        proportional += (PAGE_SIZE as u64) << PSS_SHIFT;
+                        // Without a dirty bit, private pages must be assumed to have been written
+                        // to.
+                        if EntryFlags::DIRTY.bits() == 0
+                            || flags.data() & EntryFlags::DIRTY.bits() != 0
+                        {
+                            usage.private_dirty += PAGE_SIZE;
+                        } else {
+                            usage.private_clean += PAGE_SIZE;
+                        }
+                    }
+                    RefCount::Shared(count) | RefCount::Cow(count) => {
+                        proportional += ((PAGE_SIZE as u64) << PSS_SHIFT) / count.get() as u64;
+                        usage.shared += PAGE_SIZE;
+                    }
+                }
+            }
+        });
+        usage.proportional = (proportional >> PSS_SHIFT) as usize;
+        usage.unfaulted = (span.count - present).saturating_sub(swapped) * PAGE_SIZE;
+        usage
+    }
     fn munmap_inner(
         this_grants: &mut UserGrants,
         this_mapper: &mut PageMapper,
@@ -573,12 +1447,15 @@ impl AddrSpace {
             grants
                 .conflicts(span)
                 .map(|(base, info)| {
+                    let grant_span = PageSpan::new(base, info.page_count);
                     if info.is_pinned() && !unpin {
                         Err(Error::new(EBUSY))
-                    } else if !info.can_extract(unpin) {
+                    } else if !info.can_extract(unpin)
+                        || !info.can_split_at(base, grant_span.intersection(span))
+                    {
                         Err(Error::new(EINVAL))
                     } else {
-                        Ok(PageSpan::new(base, info.page_count))
+                        Ok(grant_span)
                     }
                 })
                 .next()
@@ -586,6 +1463,9 @@ impl AddrSpace {
 
         while let Some(conflicting_span_res) = next(this_grants, requested_span) {
             let conflicting_span = conflicting_span_res?;
+            let intersection = conflicting_span.intersection(requested_span);
+            split_huge_pages_at(this_mapper, intersection.base, this_flusher)?;
+            split_huge_pages_at(this_mapper, intersection.end(), this_flusher)?;
 
             let mut grant = this_grants
                 .remove(conflicting_span.base)
@@ -594,8 +1474,6 @@ impl AddrSpace {
                 grant.info.unpin();
             }
 
-            let intersection = conflicting_span.intersection(requested_span);
-
             requested_span = {
                 // In the following diagrams [---> indicates a range of
                 // base..base+count where the [ is at the base and > is at
@@ -626,7 +1504,7 @@ impl AddrSpace {
             };
 
             let (before, grant, after) = grant
-                .extract(intersection)
+                .extract(intersection, this_mapper)
                 .expect("conflicting region shared no common parts");
 
             // Keep untouched regions
@@ -638,6 +1516,8 @@ impl AddrSpace {
             }
 
             // Remove irrelevant region
+            this_grants.release_swapped(grant.span());
+            this_grants.forget_lazy_free(grant.span());
             let unmap_result = grant.unmap(this_mapper, this_flusher);
 
             // Notify scheme that holds grant
@@ -648,6 +1528,55 @@ impl AddrSpace {
 
         Ok(notify_files)
     }
+    /// Find a free region for a new mapping, aligned so that private mappings can be backed by huge
+    /// pages if possible.
+    fn find_free_aligned(
+        &self,
+        page_count: usize,
+        flags: MapFlags,
+        near: Option<Page>,
+    ) -> Result<PageSpan> {
+        let align = if flags.contains(MapFlags::MAP_SHARED) {
+            1
+        } else {
+            huge::alignment(page_count)
+        };
+        self.find_free_near(page_count, align, near)
+            .or_else(|| self.find_free_near(page_count, 1, near))
+            .ok_or(Error::new(ENOMEM))
+    }
+    /// Find a free region for a mapping placed by the kernel, randomized if ASLR is enabled.
+    pub fn find_free(&self, page_count: usize) -> Option<PageSpan> {
+        self.find_free_near(page_count, 1, None)
+    }
+    fn find_free_near(
+        &self,
+        page_count: usize,
+        align: usize,
+        near: Option<Page>,
+    ) -> Option<PageSpan> {
+        // An explicit hint is honored as closely as possible, but otherwise mappings start at the
+        // random base and are spread out by random gaps.
+        let (near, max_gap) = match near {
+            Some(near) => (Some(near), 0),
+            None if self.aslr => (
+                Some(Page::containing_address(VirtualAddress::new(
+                    self.mmap_min.saturating_add(self.mmap_offset),
+                ))),
+                ASLR_GAP_MAX,
+            ),
+            None => (None, 0),
+        };
+        self.grants
+            .find_free_near(self.mmap_min, page_count, align, near, max_gap)
+    }
+    /// Enable or disable randomization of mappings placed by the kernel, choosing a new random
+    /// mmap base. Existing mappings are not moved, so this is meant to be done before anything
+    /// has been mapped, e.g. when an address space is created for exec.
+    pub fn set_aslr(&mut self, enabled: bool) {
+        self.aslr = enabled;
+        self.mmap_offset = if enabled { random_mmap_offset() } else { 0 };
+    }
     pub fn mmap_anywhere(
         &mut self,
         dst_lock: &AddrSpaceWrapper,
@@ -666,9 +1595,129 @@ impl AddrSpace {
         notify_files_out: &mut Vec<UnmapResult>,
         map: impl FnOnce(Page, PageFlags<RmmA>, &mut PageMapper, &mut Flusher) -> Result<Grant>,
     ) -> Result<Page> {
+        let selected_span = self.select_span(
+            dst_lock,
+            requested_base_opt,
+            page_count,
+            flags,
+            notify_files_out,
+        )?;
+
+        // TODO: Threads share address spaces, so not only the inactive flusher should be sending
+        // out IPIs. IPIs will only be sent when downgrading mappings (i.e. when a stale TLB entry
+        // will not be corrected by a page fault), and will furthermore require proper
+        // synchronization.
+
+        // After mlockall with MCL_FUTURE, new mappings are locked. Those mapped by userspace are
+        // then faulted in by `fault_in_future_locked`, as faulting in requires the wrapper.
+        let locked = self.mlock_future;
+        if let Some(uid) = locked {
+            mlock::charge(uid, page_count.get()).map_err(|_| Error::new(EAGAIN))?;
+        }
+
+        let mut grant = match map(
+            selected_span.base,
+            page_flags(flags),
+            &mut self.table.utable,
+            &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
+        ) {
+            Ok(grant) => grant,
+            Err(err) => {
+                if let Some(uid) = locked {
+                    mlock::uncharge(uid, page_count.get());
+                }
+                return Err(err);
+            }
+        };
+        grant.info.locked = locked;
+        self.grants.insert(grant);
+
+        Ok(selected_span.base)
+    }
+    /// Map a stack that grows down on demand, up to `page_count` pages. Only its top page is
+    /// initially part of the grant, and the rest is a guard reservation that the grant grows into
+    /// when accessed, except for the lowest `STACK_GUARD_PAGES`, so that overflowing the stack
+    /// faults rather than corrupting the mapping below.
+    pub fn mmap_grows_down(
+        &mut self,
+        dst_lock: &AddrSpaceWrapper,
+        requested_base_opt: Option<Page>,
+        page_count: NonZeroUsize,
+        flags: MapFlags,
+        notify_files_out: &mut Vec<UnmapResult>,
+    ) -> Result<Page> {
+        if page_count.get() <= STACK_GUARD_PAGES || flags.contains(MapFlags::MAP_SHARED) {
+            return Err(Error::new(EINVAL));
+        }
+        let selected_span = self.select_span(
+            dst_lock,
+            requested_base_opt,
+            page_count,
+            flags,
+            notify_files_out,
+        )?;
+        let guard_span = PageSpan::new(selected_span.base, page_count.get() - 1);
+        let stack_span = PageSpan::new(guard_span.end(), 1);
+
+        let locked = self.mlock_future;
+        if let Some(uid) = locked {
+            mlock::charge(uid, stack_span.count).map_err(|_| Error::new(EAGAIN))?;
+        }
+        let mut stack = match Grant::zeroed(
+            stack_span,
+            page_flags(flags),
+            &mut self.table.utable,
+            &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
+            false,
+        ) {
+            Ok(grant) => grant,
+            Err(Enomem) => {
+                if let Some(uid) = locked {
+                    mlock::uncharge(uid, stack_span.count);
+                }
+                return Err(Error::new(ENOMEM));
+            }
+        };
+        stack.info.locked = locked;
+        stack.info.grows_down = true;
+
+        self.grants.insert(Grant::guard(guard_span));
+        self.grants.insert(stack);
+
+        Ok(selected_span.base)
+    }
+    /// Reserve `page_count` pages of address space that are never mapped, e.g. as guard pages.
+    pub fn mmap_guard(
+        &mut self,
+        dst_lock: &AddrSpaceWrapper,
+        requested_base_opt: Option<Page>,
+        page_count: NonZeroUsize,
+        flags: MapFlags,
+        notify_files_out: &mut Vec<UnmapResult>,
+    ) -> Result<Page> {
+        let selected_span = self.select_span(
+            dst_lock,
+            requested_base_opt,
+            page_count,
+            flags,
+            notify_files_out,
+        )?;
+        self.grants.insert(Grant::guard(selected_span));
+
+        Ok(selected_span.base)
+    }
+    /// Choose where to place a new mapping, unmapping whatever is there already for MAP_FIXED.
+    fn select_span(
+        &mut self,
+        dst_lock: &AddrSpaceWrapper,
+        requested_base_opt: Option<Page>,
+        page_count: NonZeroUsize,
+        flags: MapFlags,
+        notify_files_out: &mut Vec<UnmapResult>,
+    ) -> Result<PageSpan> {
         debug_assert_eq!(dst_lock.inner.as_mut_ptr(), self as *mut Self);
 
-        let selected_span = match requested_base_opt {
+        Ok(match requested_base_opt {
             // TODO: Rename MAP_FIXED+MAP_FIXED_NOREPLACE to MAP_FIXED and
             // MAP_FIXED_REPLACE/MAP_REPLACE?
             Some(requested_base) => {
@@ -692,31 +1741,11 @@ impl AddrSpace {
 
                     requested_span
                 } else {
-                    self.grants
-                        .find_free_near(self.mmap_min, page_count.get(), Some(requested_base))
-                        .ok_or(Error::new(ENOMEM))?
+                    self.find_free_aligned(page_count.get(), flags, Some(requested_base))?
                 }
             }
-            None => self
-                .grants
-                .find_free(self.mmap_min, page_count.get())
-                .ok_or(Error::new(ENOMEM))?,
-        };
-
-        // TODO: Threads share address spaces, so not only the inactive flusher should be sending
-        // out IPIs. IPIs will only be sent when downgrading mappings (i.e. when a stale TLB entry
-        // will not be corrected by a page fault), and will furthermore require proper
-        // synchronization.
-
-        let grant = map(
-            selected_span.base,
-            page_flags(flags),
-            &mut self.table.utable,
-            &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
-        )?;
-        self.grants.insert(grant);
-
-        Ok(selected_span.base)
+            None => self.find_free_aligned(page_count.get(), flags, None)?,
+        })
     }
 }
 
@@ -728,6 +1757,15 @@ pub struct UserGrants {
     holes: BTreeMap<VirtualAddress, usize>,
     // TODO: Would an additional map ordered by (size,start) to allow for O(log n) allocations be
     // beneficial?
+    /// Pages of `Allocated` grants that are paged out to swap, and thus not present in the page
+    /// tables.
+    swapped: BTreeMap<Page, SwapSlot>,
+    /// Pages of `Allocated` grants that have been freed with MADV_FREE, and whose frames can be
+    /// freed once memory runs low, unless they have been written to since.
+    lazy_free: BTreeSet<Page>,
+    /// Sum of the resident pages of all grants, so that it never needs to be counted from the page
+    /// tables.
+    resident: usize,
 }
 
 #[derive(Clone, Copy)]
@@ -745,6 +1783,13 @@ impl PageSpan {
             count: 0,
         }
     }
+    /// The span of the whole user address space.
+    pub fn user_range() -> Self {
+        Self::new(
+            Page::containing_address(VirtualAddress::new(0)),
+            crate::USER_END_OFFSET / PAGE_SIZE,
+        )
+    }
     pub fn validate_nonempty(address: VirtualAddress, size: usize) -> Option<Self> {
         Self::validate(address, size).filter(|this| !this.is_empty())
     }
@@ -833,6 +1878,9 @@ impl UserGrants {
             inner: BTreeMap::new(),
             holes: core::iter::once((VirtualAddress::new(0), crate::USER_END_OFFSET))
                 .collect::<BTreeMap<_, _>>(),
+            swapped: BTreeMap::new(),
+            lazy_free: BTreeSet::new(),
+            resident: 0,
         }
     }
     /// Returns the grant, if any, which occupies the specified page
@@ -877,43 +1925,53 @@ impl UserGrants {
             .take_while(move |(base, info)| PageSpan::new(**base, info.page_count).intersects(span))
             .map(|(base, info)| (*base, info))
     }
-    /// Return a free region with the specified size
-    // TODO: Alignment (x86_64: 4 KiB, 2 MiB, or 1 GiB).
-    // TODO: Support finding grant close to a requested address?
+    /// Return a free region with the specified size, aligned to `align` pages. The first region
+    /// at or above `near` is preferred, falling back to the first one above `min`. The region is
+    /// placed a random number of alignments, up to `max_gap`, above the start of its hole, as far
+    /// as the hole allows.
     pub fn find_free_near(
         &self,
         min: usize,
         page_count: usize,
-        _near: Option<Page>,
+        align: usize,
+        near: Option<Page>,
+        max_gap: usize,
     ) -> Option<PageSpan> {
         // Get first available hole, but do reserve the page starting from zero as most compiled
         // languages cannot handle null pointers safely even if they point to valid memory. If an
         // application absolutely needs to map the 0th page, they will have to do so explicitly via
-        // MAP_FIXED/MAP_FIXED_NOREPLACE.
-        // TODO: Allow explicitly allocating guard pages? Perhaps using mprotect or mmap with
-        // PROT_NONE?
+        // MAP_FIXED/MAP_FIXED_NOREPLACE. Guard pages are reserved by `Provider::Guard` grants, and
+        // are thus never part of a hole.
+
+        let align_size = align * PAGE_SIZE;
+        let search = |lower: usize| {
+            self.holes
+                .iter()
+                .skip_while(move |(hole_offset, hole_size)| {
+                    hole_offset.data() + **hole_size <= lower
+                })
+                .find_map(move |(hole_offset, hole_size)| {
+                    let hole_end = hole_offset.data() + *hole_size;
+                    let start =
+                        cmp::max(hole_offset.data(), lower).checked_next_multiple_of(align_size)?;
+                    let end = start.checked_add(page_count * PAGE_SIZE)?;
+                    if end > hole_end {
+                        return None;
+                    }
+                    let slack = (hole_end - end) / align_size;
+                    let gap = random::below(cmp::min(slack, max_gap) + 1);
+                    // Create new region
+                    Some(PageSpan::new(
+                        Page::containing_address(VirtualAddress::new(start + gap * align_size)),
+                        page_count,
+                    ))
+                })
+        };
 
-        let (hole_start, _hole_size) = self
-            .holes
-            .iter()
-            .skip_while(|(hole_offset, hole_size)| hole_offset.data() + **hole_size <= min)
-            .find(|(hole_offset, hole_size)| {
-                let avail_size =
-                    if hole_offset.data() <= min && min <= hole_offset.data() + **hole_size {
-                        **hole_size - (min - hole_offset.data())
-                    } else {
-                        **hole_size
-                    };
-                page_count * PAGE_SIZE <= avail_size
-            })?;
-        // Create new region
-        Some(PageSpan::new(
-            Page::containing_address(VirtualAddress::new(cmp::max(hole_start.data(), min))),
-            page_count,
-        ))
-    }
-    pub fn find_free(&self, min: usize, page_count: usize) -> Option<PageSpan> {
-        self.find_free_near(min, page_count, None)
+        match near.map(|near| near.start_address().data()) {
+            Some(near) if near > min => search(near).or_else(|| search(min)),
+            _ => search(min),
+        }
     }
     fn reserve(&mut self, base: Page, page_count: usize) {
         let start_address = base.start_address();
@@ -1002,16 +2060,22 @@ impl UserGrants {
             })
             .map(|(base, info)| (*base, info.page_count));
 
+        self.resident += grant.info.resident;
+
         if let Some((before_base, before_page_count)) = before_region {
             grant.base = before_base;
             grant.info.page_count += before_page_count;
 
-            core::mem::forget(self.inner.remove(&before_base));
+            let before = self.inner.remove(&before_base);
+            grant.info.resident += before.as_ref().map_or(0, |info| info.resident);
+            core::mem::forget(before);
         }
         if let Some((after_base, after_page_count)) = after_region {
             grant.info.page_count += after_page_count;
 
-            core::mem::forget(self.inner.remove(&after_base));
+            let after = self.inner.remove(&after_base);
+            grant.info.resident += after.as_ref().map_or(0, |info| info.resident);
+            core::mem::forget(after);
         }
 
         self.inner.insert(grant.base, grant.info);
@@ -1019,8 +2083,57 @@ impl UserGrants {
     pub fn remove(&mut self, base: Page) -> Option<Grant> {
         let info = self.inner.remove(&base)?;
         Self::unreserve(&mut self.holes, base, info.page_count);
+        self.resident -= info.resident;
         Some(Grant { base, info })
     }
+    /// Number of pages of allocated memory that are currently mapped, not counting the zeroed
+    /// frame that untouched pages are mapped to.
+    pub fn resident(&self) -> usize {
+        self.resident
+    }
+    /// Account for `page_count` pages of the grant containing `page` having been mapped to frames
+    /// other than the zeroed frame.
+    pub fn add_resident(&mut self, page: Page, page_count: usize) {
+        if let Some(info) = self.resident_info_mut(page) {
+            info.resident += page_count;
+            self.resident += page_count;
+        }
+    }
+    /// Account for `page_count` pages of the grant containing `page` having been unmapped, or
+    /// mapped to the zeroed frame instead.
+    pub fn sub_resident(&mut self, page: Page, page_count: usize) {
+        if let Some(info) = self.resident_info_mut(page) {
+            let page_count = page_count.min(info.resident);
+            info.resident -= page_count;
+            self.resident -= page_count;
+        }
+    }
+    /// Count the resident pages of the grant at `base` from the page tables again, after they
+    /// have been mapped without keeping track.
+    pub fn recount_resident(&mut self, base: Page, mapper: &PageMapper) {
+        if let Some(info) = self
+            .inner
+            .get_mut(&base)
+            .filter(|info| info.counts_resident())
+        {
+            let resident = huge::count_mapped(
+                mapper,
+                PageSpan::new(base, info.page_count),
+                the_zeroed_frame().0,
+            );
+            self.resident = self.resident - info.resident + resident;
+            info.resident = resident;
+        }
+    }
+    /// The grant containing `page`, if its resident pages are counted.
+    fn resident_info_mut(&mut self, page: Page) -> Option<&mut GrantInfo> {
+        self.inner
+            .range_mut(..=page)
+            .next_back()
+            .filter(|(base, info)| (**base..base.next_by(info.page_count)).contains(&page))
+            .map(|(_, info)| info)
+            .filter(|info| info.counts_resident())
+    }
     pub fn iter(&self) -> impl Iterator<Item = (Page, &GrantInfo)> + '_ {
         self.inner.iter().map(|(base, info)| (*base, info))
     }
@@ -1032,6 +2145,61 @@ impl UserGrants {
             .into_iter()
             .map(|(base, info)| Grant { base, info })
     }
+    /// Whether every page in `span` belongs to a grant.
+    pub fn is_mapped(&self, span: PageSpan) -> bool {
+        let mapped_count = self
+            .conflicts(span)
+            .map(|(base, info)| {
+                PageSpan::new(base, info.page_count)
+                    .intersection(span)
+                    .count
+            })
+            .sum::<usize>();
+        mapped_count == span.count
+    }
+    /// Returns the swap slot the page has been paged out to, if any
+    pub fn swapped(&self, page: Page) -> Option<SwapSlot> {
+        self.swapped.get(&page).copied()
+    }
+    pub fn swapped_in_span(&self, span: PageSpan) -> impl Iterator<Item = (Page, SwapSlot)> + '_ {
+        self.swapped
+            .range(span.base..span.end())
+            .map(|(page, slot)| (*page, *slot))
+    }
+    pub fn set_swapped(&mut self, page: Page, slot: SwapSlot) {
+        // The page will be mapped again with the dirty bit clear when it is paged in.
+        self.lazy_free.remove(&page);
+        self.swapped.insert(page, slot);
+    }
+    pub fn take_swapped(&mut self, page: Page) -> Option<SwapSlot> {
+        self.swapped.remove(&page)
+    }
+    /// Remove the paged out pages in `span`, returning them so they can be moved elsewhere.
+    fn take_swapped_span(&mut self, span: PageSpan) -> Vec<(Page, SwapSlot)> {
+        let pages = self.swapped_in_span(span).collect::<Vec<_>>();
+        for (page, _) in &pages {
+            self.swapped.remove(page);
+        }
+        pages
+    }
+    /// Free the swap slots of the paged out pages in `span`, when they are unmapped.
+    fn release_swapped(&mut self, span: PageSpan) {
+        for (_, slot) in self.take_swapped_span(span) {
+            swap::release(slot);
+        }
+    }
+    /// Stop treating the pages in `span` as lazily freed, when they are unmapped or remapped in a
+    /// way that does not preserve their dirty bit.
+    fn forget_lazy_free(&mut self, span: PageSpan) {
+        let pages = self
+            .lazy_free
+            .range(span.base..span.end())
+            .copied()
+            .collect::<Vec<_>>();
+        for page in pages {
+            self.lazy_free.remove(&page);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1040,6 +2208,16 @@ pub struct GrantInfo {
     flags: PageFlags<RmmA>,
     // TODO: Rename to unmapped?
     mapped: bool,
+    /// The user that the pages are charged to, if locked with mlock.
+    locked: Option<u32>,
+    /// Whether the grant grows down into the guard reservation directly below it, when accessed
+    /// there.
+    grows_down: bool,
+    /// Whether identical pages of the grant can be merged with other pages by KSM.
+    mergeable: bool,
+    /// Number of pages mapped to frames other than the zeroed frame, which is only counted for
+    /// `Allocated` and `AllocatedShared` grants.
+    resident: usize,
     pub(crate) provider: Provider,
 }
 
@@ -1076,6 +2254,11 @@ pub enum Provider {
         is_pinned_userscheme_borrow: bool,
     },
 
+    /// The grant is owned, and backed by huge pages of the given order from the pool reserved at
+    /// boot, which are returned to the pool when unmapped. These grants are never CoW-shared, and
+    /// are only split at huge page boundaries.
+    HugePool { order: u32 },
+
     /// The memory is MAP_SHARED borrowed from a scheme.
     ///
     /// Since the address space is not tracked here, all nonpresent pages must be present before
@@ -1084,7 +2267,104 @@ pub enum Provider {
     FmapBorrowed {
         file_ref: GrantFileRef,
         pin_refcount: usize,
+        readahead: Readahead,
     },
+
+    /// Address space reserved so that nothing else is mapped there, such as guard pages. Nothing
+    /// is ever mapped in these grants, and accessing them is fatal, unless they lie directly below
+    /// a grow-down grant, which then grows into them.
+    Guard,
+}
+
+/// Number of fractional bits of the proportional set size while it is summed.
+const PSS_SHIFT: u32 = 12;
+
+/// Memory usage of an address space or of one of its grants, in bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryUsage {
+    /// Size of the mappings, whether populated or not.
+    pub size: usize,
+    /// Memory currently mapped, not counting the zeroed frame that untouched pages are mapped
+    /// to, or memory that does not belong to the frame allocator.
+    pub resident: usize,
+    /// The resident memory, with each page divided by the number of references to it.
+    pub proportional: usize,
+    /// Resident memory also mapped elsewhere, either CoW or shared.
+    pub shared: usize,
+    /// Resident memory only mapped here, that has not been written to since it was mapped.
+    pub private_clean: usize,
+    /// Resident memory only mapped here, that has been written to.
+    pub private_dirty: usize,
+    /// Memory that has been paged out.
+    pub swapped: usize,
+    /// Memory that has not been faulted in yet, and is neither resident nor swapped.
+    pub unfaulted: usize,
+}
+impl core::ops::Add for MemoryUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            size: self.size + other.size,
+            resident: self.resident + other.resident,
+            proportional: self.proportional + other.proportional,
+            shared: self.shared + other.shared,
+            private_clean: self.private_clean + other.private_clean,
+            private_dirty: self.private_dirty + other.private_dirty,
+            swapped: self.swapped + other.swapped,
+            unfaulted: self.unfaulted + other.unfaulted,
+        }
+    }
+}
+
+/// How many pages to request from the scheme when a page of a file-backed grant is faulted in,
+/// as set with madvise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Readahead {
+    Normal,
+    Random,
+    Sequential,
+}
+impl Readahead {
+    /// Number of pages to request, including the faulting page.
+    ///
+    /// TODO: Read ahead by default, once schemes handle the requested page count.
+    pub fn page_count(self) -> usize {
+        match self {
+            Self::Normal | Self::Random => 1,
+            Self::Sequential => 16,
+        }
+    }
+}
+
+/// Advice given with `ADDRSPACE_OP_MADVISE` on how a range of memory will be used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Advice {
+    Normal,
+    Random,
+    Sequential,
+    WillNeed,
+    DontNeed,
+    Free,
+    Mergeable,
+    Unmergeable,
+}
+impl Advice {
+    /// Parse the value of one of the POSIX or Linux `MADV_*` constants.
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        Some(match raw {
+            0 => Self::Normal,
+            1 => Self::Random,
+            2 => Self::Sequential,
+            3 => Self::WillNeed,
+            4 => Self::DontNeed,
+            8 => Self::Free,
+            12 => Self::Mergeable,
+            13 => Self::Unmergeable,
+
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -1141,6 +2421,10 @@ impl Grant {
                 page_count: 1,
                 flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 1,
                 provider: Provider::AllocatedShared {
                     is_pinned_userscheme_borrow: is_pinned,
                 },
@@ -1184,6 +2468,10 @@ impl Grant {
                 page_count: span.count,
                 flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
                 provider: Provider::PhysBorrowed { base: phys },
             },
         })
@@ -1201,18 +2489,32 @@ impl Grant {
         let alloc_order = span.count.next_power_of_two().trailing_zeros();
         let base = crate::memory::allocate_p2frame(alloc_order).ok_or(Enomem)?;
 
-        for (i, page) in span.pages().enumerate() {
-            let frame = base.next_by(i);
-
-            get_page_info(frame)
+        for i in 0..span.count {
+            get_page_info(base.next_by(i))
                 .expect("PageInfo must exist for allocated frame")
                 .refcount
                 .store(RefCount::One.to_raw(), Ordering::Relaxed);
+        }
+
+        for (i, page) in span.pages().enumerate() {
+            let frame = base.next_by(i);
 
             unsafe {
-                let result = mapper
-                    .map_phys(page.start_address(), frame.base(), flags)
-                    .expect("TODO: page table OOM");
+                let Some(result) = mapper.map_phys(page.start_address(), frame.base(), flags)
+                else {
+                    // Out of memory for page tables, so undo the mappings made so far.
+                    for page in span.pages().take(i) {
+                        if let Some((_, _, flush)) = mapper.unmap_phys(page.start_address(), true) {
+                            flush.ignore();
+                        }
+                    }
+                    flusher.queue(
+                        base,
+                        NonZeroUsize::new(span.count),
+                        TlbShootdownActions::FREE,
+                    );
+                    return Err(Enomem);
+                };
                 result.ignore();
 
                 flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
@@ -1225,6 +2527,10 @@ impl Grant {
                 page_count: span.count,
                 flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: span.count,
                 provider: Provider::Allocated {
                     cow_file_ref: None,
                     phys_contiguous: true,
@@ -1232,6 +2538,74 @@ impl Grant {
             },
         })
     }
+    /// Map zeroed huge pages from the pool reserved at boot, failing if the pool does not have
+    /// enough free pages.
+    pub fn zeroed_hugepages(
+        span: PageSpan,
+        size: HugePageSize,
+        flags: PageFlags<RmmA>,
+        mapper: &mut PageMapper,
+        flusher: &mut Flusher,
+    ) -> Result<Grant, Enomem> {
+        let huge_page_count = size.page_count();
+        assert_eq!(
+            span.base.start_address().data() % (huge_page_count * PAGE_SIZE),
+            0
+        );
+        assert_eq!(span.count % huge_page_count, 0);
+
+        let mut frames = Vec::new();
+        for _ in 0..span.count / huge_page_count {
+            let Some(frame) = hugepool::alloc(size) else {
+                for frame in frames {
+                    hugepool::free(frame, size);
+                }
+                return Err(Enomem);
+            };
+            frames.push(frame);
+        }
+
+        for (i, &frame) in frames.iter().enumerate() {
+            let page = span.base.next_by(i * huge_page_count);
+            let Some(flush) = (unsafe { huge::map(mapper, page, frame, flags, size.order()) })
+            else {
+                // Out of memory for page tables, so undo the mappings made so far.
+                for (j, &frame) in frames.iter().enumerate() {
+                    if j < i {
+                        unsafe { huge::unmap(mapper, span.base.next_by(j * huge_page_count)) };
+                        flusher.queue(
+                            frame,
+                            NonZeroUsize::new(huge_page_count),
+                            TlbShootdownActions::FREE,
+                        );
+                    } else {
+                        hugepool::free(frame, size);
+                    }
+                }
+                return Err(Enomem);
+            };
+            unsafe {
+                flush.ignore();
+            }
+            flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+        }
+
+        Ok(Grant {
+            base: span.base,
+            info: GrantInfo {
+                page_count: span.count,
+                flags,
+                mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
+                provider: Provider::HugePool {
+                    order: size.order(),
+                },
+            },
+        })
+    }
     pub fn zeroed(
         span: PageSpan,
         flags: PageFlags<RmmA>,
@@ -1243,9 +2617,16 @@ impl Grant {
 
         let (the_frame, the_frame_info) = the_zeroed_frame();
 
+        // Huge pages can only be mapped where nothing is mapped yet.
+        let eager_pages = if !shared && flags.has_write() && huge::fits(span) {
+            0
+        } else {
+            MAX_EAGER_PAGES
+        };
+
         // TODO: Use flush_all after a certain number of pages, otherwise no
 
-        for page in span.pages().take(MAX_EAGER_PAGES) {
+        for page in span.pages().take(eager_pages) {
             // Good thing with lazy page fault handlers, is that if we fail due to ENOMEM here, we
             // can continue and let the process face the OOM killer later.
             unsafe {
@@ -1269,6 +2650,10 @@ impl Grant {
                 page_count: span.count,
                 flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
                 provider: if shared {
                     Provider::AllocatedShared {
                         is_pinned_userscheme_borrow: false,
@@ -1282,6 +2667,21 @@ impl Grant {
             },
         })
     }
+    pub fn guard(span: PageSpan) -> Grant {
+        Grant {
+            base: span.base,
+            info: GrantInfo {
+                page_count: span.count,
+                flags: PageFlags::new().user(true),
+                mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
+                provider: Provider::Guard,
+            },
+        }
+    }
 
     // XXX: borrow_grant is needed because of the borrow checker (iterator invalidation), maybe
     // borrow_grant/borrow can be abstracted somehow?
@@ -1300,6 +2700,10 @@ impl Grant {
                 page_count: src_info.page_count,
                 flags: src_info.flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
                 provider: Provider::External {
                     src_base,
                     address_space: src_address_space_lock,
@@ -1321,6 +2725,22 @@ impl Grant {
         if let Some(src) = src {
             let mut guard = src.addr_space_guard;
             let mut src_addrspace = &mut *guard;
+            // Pool huge pages cannot be shared page by page, as they could be made CoW.
+            if src_addrspace
+                .grants
+                .conflicts(PageSpan::new(src.src_base, span.count))
+                .any(|(_, info)| matches!(info.provider, Provider::HugePool { .. }))
+            {
+                return Err(Error::new(EINVAL));
+            }
+            split_huge_pages(
+                &mut src_addrspace.table.utable,
+                PageSpan::new(src.src_base, span.count),
+                &mut Flusher::with_cpu_set(
+                    &mut src_addrspace.used_by,
+                    &src.addr_space_lock.tlb_ack,
+                ),
+            )?;
             let mut src_flusher_state =
                 Flusher::with_cpu_set(&mut src_addrspace.used_by, &lock.tlb_ack).detach();
             for dst_page in span.pages() {
@@ -1396,6 +2816,9 @@ impl Grant {
                                     (new_cow_frame.base(), flags)
                                 })
                                 .expect("page did exist");
+                            if frame == the_zeroed_frame().0 {
+                                src_addrspace.grants.add_resident(src_page, 1);
+                            }
 
                             // TODO: flush.ignore() is correct, but seems to be amplifying a
                             // userspace race condition
@@ -1450,9 +2873,14 @@ impl Grant {
                 page_count: span.count,
                 mapped: true,
                 flags: new_flags,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
                 provider: Provider::FmapBorrowed {
                     file_ref,
                     pin_refcount: 0,
+                    readahead: Readahead::Normal,
                 },
             },
         })
@@ -1480,6 +2908,24 @@ impl Grant {
         const MAX_EAGER_PAGES: usize = 4096;
 
         let src_span = PageSpan::new(src_base, page_count);
+        if eager {
+            let eager_span = PageSpan::new(src_base, cmp::min(page_count, MAX_EAGER_PAGES));
+            let src_address_space = &mut *src_address_space;
+            let mut src_flusher = Flusher::with_cpu_set(
+                &mut src_address_space.used_by,
+                &src_address_space_lock.tlb_ack,
+            );
+            // Pool huge pages can be borrowed as they are, as their frames are never CoW.
+            for (grant_base, grant_info) in src_address_space.grants.conflicts(eager_span) {
+                if !matches!(grant_info.provider, Provider::HugePool { .. }) {
+                    split_huge_pages(
+                        &mut src_address_space.table.utable,
+                        PageSpan::new(grant_base, grant_info.page_count).intersection(eager_span),
+                        &mut src_flusher,
+                    )?;
+                }
+            }
+        }
         let mut prev_span = None;
 
         for (src_grant_base, src_grant) in src_address_space.grants.conflicts_mut(src_span) {
@@ -1536,10 +2982,8 @@ impl Grant {
                 .enumerate()
                 .take(MAX_EAGER_PAGES)
             {
-                let Some((phys, _)) = src_address_space
-                    .table
-                    .utable
-                    .translate(page.start_address())
+                let Some((phys, _)) =
+                    huge::translate(&src_address_space.table.utable, page.start_address())
                 else {
                     continue;
                 };
@@ -1580,6 +3024,10 @@ impl Grant {
                 page_count,
                 flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident: 0,
                 provider: Provider::External {
                     address_space: src_address_space_lock,
                     src_base,
@@ -1603,12 +3051,42 @@ impl Grant {
             CopyMappingsMode::Owned { .. } => (false, RefKind::Cow),
             CopyMappingsMode::Borrowed => (true, RefKind::Shared),
         };
+        // The rest of a huge page that has been shared as a whole is skipped.
+        let mut next_idx = 0;
+        let zeroed = the_zeroed_frame().0;
+        let mut resident = 0;
 
         // TODO: Page table iterator
         for page_idx in 0..page_count {
+            if page_idx < next_idx {
+                continue;
+            }
             let src_page = src_base.next_by(page_idx);
             let dst_page = dst_base.next_by(page_idx).start_address();
 
+            if let Some(mapping) = huge::lookup(src_mapper, src_page) {
+                if matches!(rk, RefKind::Cow)
+                    && mapping.base == src_page
+                    && mapping.page_count() <= page_count - page_idx
+                    && unsafe {
+                        cow_share_huge_page(
+                            &mapping,
+                            Page::containing_address(dst_page),
+                            flags,
+                            src_mapper,
+                            dst_mapper,
+                            src_flusher,
+                            dst_flusher,
+                        )
+                    }
+                {
+                    next_idx = page_idx + mapping.page_count();
+                    resident += mapping.page_count();
+                    continue;
+                }
+                split_huge_pages(src_mapper, mapping.span(), src_flusher)?;
+            }
+
             let src_frame = match rk {
                 RefKind::Cow => {
                     let Some((_, phys, flush)) = (unsafe {
@@ -1633,11 +3111,12 @@ impl Grant {
                         Frame::containing(phys)
                     } else {
                         // TODO: Omit the unnecessary subsequent add_ref call.
-                        let new_frame = init_frame(RefCount::One).expect("TODO: handle OOM");
-                        let src_flush = unsafe {
-                            src_mapper
-                                .map_phys(src_page.start_address(), new_frame.base(), flags)
-                                .expect("TODO: handle OOM")
+                        let new_frame = init_frame(RefCount::One).map_err(|_| Enomem)?;
+                        let Some(src_flush) = (unsafe {
+                            src_mapper.map_phys(src_page.start_address(), new_frame.base(), flags)
+                        }) else {
+                            src_flusher.queue(new_frame, None, TlbShootdownActions::FREE);
+                            return Err(Enomem);
                         };
                         unsafe {
                             src_flush.ignore();
@@ -1722,6 +3201,9 @@ impl Grant {
             unsafe {
                 map_result.ignore();
             }
+            if src_frame != zeroed {
+                resident += 1;
+            }
 
             dst_flusher.queue(src_frame, None, TlbShootdownActions::NEW_MAPPING);
         }
@@ -1732,6 +3214,10 @@ impl Grant {
                 page_count,
                 flags,
                 mapped: true,
+                locked: None,
+                grows_down: false,
+                mergeable: false,
+                resident,
                 provider: match mode {
                     CopyMappingsMode::Owned { cow_file_ref } => Provider::Allocated {
                         cow_file_ref,
@@ -1744,7 +3230,7 @@ impl Grant {
             },
         })
     }
-    /// Move a grant between two address spaces.
+    /// Move a grant between two address spaces. Huge pages in it must have been split.
     pub fn transfer(
         mut self,
         dst_base: Page,
@@ -1803,22 +3289,37 @@ impl Grant {
     ) {
         assert!(self.info.mapped);
 
-        for page in self.span().pages() {
+        let mut page = self.base;
+        while page < self.span().end() {
             unsafe {
+                if let Some(mapping) = huge::lookup(mapper, page) {
+                    // Huge pages shared copy-on-write stay read-only until written to.
+                    let flags = flags.write(flags.has_write() && !is_cow_shared(&mapping));
+                    if let Some((_, flush)) = huge::remap(mapper, page, flags) {
+                        flush.ignore();
+                        flusher.queue(
+                            mapping.frame,
+                            None,
+                            TlbShootdownActions::change_of_flags(mapping.flags, flags),
+                        );
+                    }
+                    page = mapping.span().end();
+                    continue;
+                }
                 // Lazy mappings don't require remapping, as info.flags will be updated.
-                let Some((old_flags, phys, flush)) =
+                if let Some((old_flags, phys, flush)) =
                     mapper.remap_with(page.start_address(), |_| flags)
-                else {
-                    continue;
-                };
-                flush.ignore();
-                //log::info!("Remapped page {:?} (frame {:?})", page, Frame::containing(mapper.translate(page.start_address()).unwrap().0));
-                flusher.queue(
-                    Frame::containing(phys),
-                    None,
-                    TlbShootdownActions::change_of_flags(old_flags, flags),
-                );
+                {
+                    flush.ignore();
+                    //log::info!("Remapped page {:?} (frame {:?})", page, Frame::containing(mapper.translate(page.start_address()).unwrap().0));
+                    flusher.queue(
+                        Frame::containing(phys),
+                        None,
+                        TlbShootdownActions::change_of_flags(old_flags, flags),
+                    );
+                }
             }
+            page = page.next_by(1);
         }
 
         self.info.flags = flags;
@@ -1868,11 +3369,12 @@ impl Grant {
 
         // TODO: Add old debug assertions back, into Flusher.
         let is_fmap_shared = match self.info.provider {
-            Provider::Allocated { .. } => Some(false),
+            Provider::Allocated { .. } | Provider::HugePool { .. } => Some(false),
             Provider::AllocatedShared { .. } => None,
             Provider::External { .. } => None,
             Provider::PhysBorrowed { .. } => None,
             Provider::FmapBorrowed { .. } => Some(true),
+            Provider::Guard => None,
         };
 
         if is_phys_contiguous {
@@ -1895,19 +3397,60 @@ impl Grant {
                 Some(NonZeroUsize::new(self.info.page_count).unwrap()),
                 TlbShootdownActions::FREE,
             );
-        } else {
-            for page in self.span().pages() {
-                // Lazy mappings do not need to be unmapped.
-                let Some((phys, _, flush)) =
-                    (unsafe { mapper.unmap_phys(page.start_address(), true) })
-                else {
+        } else if let Provider::Guard = self.info.provider {
+            // Nothing is ever mapped in guard reservations.
+        } else if let Provider::HugePool { order } = self.info.provider {
+            let huge_page_count = 1 << order;
+
+            for base in self.span().pages().step_by(huge_page_count) {
+                // The huge page may have been split since, but still maps the whole pool page.
+                let Some((phys_base, _)) = huge::translate(mapper, base.start_address()) else {
                     continue;
                 };
-                unsafe {
-                    flush.ignore();
+                let mut page = base;
+                while page < base.next_by(huge_page_count) {
+                    if let Some(mapping) = unsafe { huge::unmap(mapper, page) } {
+                        page = mapping.span().end();
+                        continue;
+                    }
+                    if let Some((_, _, flush)) =
+                        unsafe { mapper.unmap_phys(page.start_address(), true) }
+                    {
+                        unsafe {
+                            flush.ignore();
+                        }
+                    }
+                    page = page.next_by(1);
+                }
+                flusher.queue(
+                    Frame::containing(phys_base),
+                    NonZeroUsize::new(huge_page_count),
+                    TlbShootdownActions::FREE,
+                );
+            }
+        } else {
+            let mut page = self.base;
+            while page < self.span().end() {
+                if let Some(mapping) = unsafe { huge::unmap(mapper, page) } {
+                    flusher.queue(
+                        mapping.frame,
+                        NonZeroUsize::new(mapping.page_count()),
+                        TlbShootdownActions::FREE,
+                    );
+                    page = mapping.span().end();
+                    continue;
                 }
+                // Lazy mappings do not need to be unmapped.
+                if let Some((phys, _, flush)) =
+                    unsafe { mapper.unmap_phys(page.start_address(), true) }
+                {
+                    unsafe {
+                        flush.ignore();
+                    }
 
-                flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
+                    flusher.queue(Frame::containing(phys), None, TlbShootdownActions::FREE);
+                }
+                page = page.next_by(1);
             }
         }
 
@@ -1954,17 +3497,25 @@ impl Grant {
     pub fn span(&self) -> PageSpan {
         PageSpan::new(self.base, self.info.page_count)
     }
-    pub fn extract(mut self, span: PageSpan) -> Option<(Option<Grant>, Grant, Option<Grant>)> {
+    pub fn extract(
+        mut self,
+        span: PageSpan,
+        mapper: &PageMapper,
+    ) -> Option<(Option<Grant>, Grant, Option<Grant>)> {
         assert!(self.info.can_extract(false));
 
         let (before_span, this_span, after_span) = self.span().slice(span);
 
-        let before_grant = before_span.map(|span| Grant {
+        let mut before_grant = before_span.map(|span| Grant {
             base: span.base,
             info: GrantInfo {
                 flags: self.info.flags,
                 mapped: self.info.mapped,
                 page_count: span.count,
+                locked: self.info.locked,
+                grows_down: self.info.grows_down,
+                mergeable: self.info.mergeable,
+                resident: 0,
                 provider: match self.info.provider {
                     Provider::External {
                         ref address_space,
@@ -1987,10 +3538,17 @@ impl Grant {
                     Provider::PhysBorrowed { base } => {
                         Provider::PhysBorrowed { base: base.clone() }
                     }
-                    Provider::FmapBorrowed { ref file_ref, .. } => Provider::FmapBorrowed {
+                    Provider::HugePool { order } => Provider::HugePool { order },
+                    Provider::FmapBorrowed {
+                        ref file_ref,
+                        readahead,
+                        ..
+                    } => Provider::FmapBorrowed {
                         file_ref: file_ref.clone(),
                         pin_refcount: 0,
+                        readahead,
                     },
+                    Provider::Guard => Provider::Guard,
                 },
             },
         });
@@ -2010,15 +3568,21 @@ impl Grant {
                 cow_file_ref: None, ..
             }
             | Provider::AllocatedShared { .. }
+            | Provider::HugePool { .. }
+            | Provider::Guard
             | Provider::External { .. } => (),
         }
 
-        let after_grant = after_span.map(|span| Grant {
+        let mut after_grant = after_span.map(|span| Grant {
             base: span.base,
             info: GrantInfo {
                 flags: self.info.flags,
                 mapped: self.info.mapped,
                 page_count: span.count,
+                locked: self.info.locked,
+                grows_down: self.info.grows_down,
+                mergeable: self.info.mergeable,
+                resident: 0,
                 provider: match self.info.provider {
                     Provider::Allocated {
                         cow_file_ref: None, ..
@@ -2052,13 +3616,20 @@ impl Grant {
                     Provider::PhysBorrowed { base } => Provider::PhysBorrowed {
                         base: base.next_by(this_span.count),
                     },
-                    Provider::FmapBorrowed { ref file_ref, .. } => Provider::FmapBorrowed {
+                    Provider::HugePool { order } => Provider::HugePool { order },
+                    Provider::FmapBorrowed {
+                        ref file_ref,
+                        readahead,
+                        ..
+                    } => Provider::FmapBorrowed {
                         file_ref: GrantFileRef {
                             base_offset: file_ref.base_offset + this_span.count * PAGE_SIZE,
                             description: Arc::clone(&file_ref.description),
                         },
                         pin_refcount: 0,
+                        readahead,
                     },
+                    Provider::Guard => Provider::Guard,
                 },
             },
         });
@@ -2066,10 +3637,33 @@ impl Grant {
         self.base = this_span.base;
         self.info.page_count = this_span.count;
 
+        // Only the parts split off are counted, what remains is resident in the middle.
+        let zeroed = the_zeroed_frame().0;
+        for grant in before_grant.iter_mut().chain(after_grant.iter_mut()) {
+            if self.info.resident > 0 {
+                grant.info.resident = huge::count_mapped(mapper, grant.span(), zeroed);
+                self.info.resident = self.info.resident.saturating_sub(grant.info.resident);
+            }
+        }
+
         Some((before_grant, self, after_grant))
     }
 }
 impl GrantInfo {
+    /// Whether the pages mapped by the grant are counted as resident memory of the address space.
+    fn counts_resident(&self) -> bool {
+        matches!(
+            self.provider,
+            Provider::Allocated { .. } | Provider::AllocatedShared { .. }
+        )
+    }
+    /// Whether the grant is locked with mlock, and thus never paged out or otherwise reclaimed.
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+    pub fn is_guard(&self) -> bool {
+        matches!(self.provider, Provider::Guard)
+    }
     pub fn is_pinned(&self) -> bool {
         matches!(
             self.provider,
@@ -2095,6 +3689,15 @@ impl GrantInfo {
                 }
             )
     }
+    /// Whether the grant starting at `base` can be split at the start and end of `span`, which
+    /// for pool huge pages must be at huge page boundaries.
+    pub fn can_split_at(&self, base: Page, span: PageSpan) -> bool {
+        let Provider::HugePool { order } = self.provider else {
+            return true;
+        };
+        span.base.offset_from(base) % (1 << order) == 0
+            && span.end().offset_from(base) % (1 << order) == 0
+    }
     pub fn unpin(&mut self) {
         if let Provider::External {
             ref mut is_pinned_userscheme_borrow,
@@ -2121,13 +3724,18 @@ impl GrantInfo {
             && (self.flags.has_execute() || !flags.contains(MapFlags::PROT_EXEC));
 
         match self.provider {
-            Provider::Allocated { .. } => true,
+            Provider::Allocated { .. } | Provider::HugePool { .. } => true,
             _ => is_downgrade,
         }
     }
 
     pub fn can_be_merged_if_adjacent(&self, with: &Self) -> bool {
-        if self.mapped != with.mapped || self.flags.data() != with.flags.data() {
+        if self.mapped != with.mapped
+            || self.flags.data() != with.flags.data()
+            || self.locked != with.locked
+            || self.grows_down != with.grows_down
+            || self.mergeable != with.mergeable
+        {
             return false;
         }
 
@@ -2142,6 +3750,7 @@ impl GrantInfo {
                     phys_contiguous: false,
                 },
             ) => true,
+            (Provider::Guard, Provider::Guard) => true,
             //(Provider::PhysBorrowed { base: ref lhs }, Provider::PhysBorrowed { base: ref rhs }) => lhs.next_by(self.page_count) == rhs.clone(),
             //(Provider::External { address_space: ref lhs_space, src_base: ref lhs_base, cow: lhs_cow, .. }, Provider::External { address_space: ref rhs_space, src_base: ref rhs_base, cow: rhs_cow, .. }) => Arc::ptr_eq(lhs_space, rhs_space) && lhs_cow == rhs_cow && lhs_base.next_by(self.page_count) == rhs_base.clone(),
             _ => false,
@@ -2182,6 +3791,7 @@ impl GrantInfo {
             Provider::PhysBorrowed { .. } => {
                 flags |= GrantFlags::GRANT_SHARED | GrantFlags::GRANT_PHYS;
             }
+            Provider::HugePool { .. } | Provider::Guard => (),
             Provider::FmapBorrowed { .. } => {
                 flags |= GrantFlags::GRANT_SHARED | GrantFlags::GRANT_SCHEME;
             }
@@ -2212,6 +3822,9 @@ impl Drop for GrantInfo {
             "Grant dropped while still mapped: {:#x?}",
             self
         );
+        if let Some(uid) = self.locked {
+            mlock::uncharge(uid, self.page_count);
+        }
     }
 }
 
@@ -2224,6 +3837,9 @@ pub struct Table {
 
 impl Drop for AddrSpace {
     fn drop(&mut self) {
+        for (_, slot) in core::mem::take(&mut self.grants.swapped) {
+            swap::release(slot);
+        }
         for mut grant in core::mem::take(&mut self.grants).into_iter() {
             // Unpinning the grant is allowed, because pinning only occurs in UserScheme calls to
             // prevent unmapping the mapped range twice (which would corrupt only the scheme
@@ -2274,6 +3890,8 @@ pub enum PfError {
     // TODO: Handle recursion limit by mapping a zeroed page? Or forbid borrowing borrowed memory,
     // and ensure pages are mapped at grant time?
     RecursionLimitExceeded,
+    /// Allocating a frame would exceed the memory limit of a group of the process.
+    OverLimit(Arc<MemoryLimit>),
 }
 
 pub struct CowResult {
@@ -2364,6 +3982,292 @@ pub unsafe fn copy_frame_to_frame_directly(dst: Frame, src: Frame) {
     }
 }
 
+/// Split the huge pages in `span`, so that its pages can be mapped, shared or remapped individually.
+fn split_huge_pages(
+    mapper: &mut PageMapper,
+    span: PageSpan,
+    flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    let mut page = span.base;
+    while page < span.end() {
+        match huge::lookup(mapper, page) {
+            // The smaller pages may still be huge pages themselves, so look up the same page again.
+            Some(mapping) => {
+                unsafe { huge::split(mapper, &mapping) }.ok_or(Enomem)?;
+                flusher.queue(mapping.frame, None, TlbShootdownActions::SPLIT);
+            }
+            None => page = page.next_by(1),
+        }
+    }
+    Ok(())
+}
+/// Whether any frame of a huge page is shared copy-on-write, after being shared as a whole by
+/// [`cow_share_huge_page`].
+fn is_cow_shared(mapping: &huge::HugeMapping) -> bool {
+    (0..mapping.page_count()).any(|i| {
+        matches!(
+            get_page_info(mapping.frame.next_by(i)).and_then(PageInfo::refcount),
+            Some(RefCount::Cow(_))
+        )
+    })
+}
+/// Share a huge page copy-on-write as a whole, by mapping it read-only at `dst_page` as well, if
+/// that page is aligned to it and nothing is mapped there yet. Returns whether it was shared, and
+/// otherwise leaves both address spaces unchanged.
+unsafe fn cow_share_huge_page(
+    mapping: &huge::HugeMapping,
+    dst_page: Page,
+    flags: PageFlags<RmmA>,
+    src_mapper: &mut PageMapper,
+    dst_mapper: &mut PageMapper,
+    src_flusher: &mut Flusher,
+    dst_flusher: &mut impl GenericFlusher,
+) -> bool {
+    if huge::aligned_span(dst_page, mapping.order).base != dst_page
+        || !huge::is_unmapped(dst_mapper, dst_page, mapping.order)
+    {
+        return false;
+    }
+    let frames = || (0..mapping.page_count()).map(|i| mapping.frame.next_by(i));
+    let remove_refs = |count| {
+        for frame in frames().take(count) {
+            let info = get_page_info(frame).expect("huge page frames need PageInfos");
+            let _ = info.remove_ref();
+        }
+    };
+    for (i, frame) in frames().enumerate() {
+        let Some(info) = get_page_info(frame) else {
+            remove_refs(i);
+            return false;
+        };
+        if info.add_ref(RefKind::Cow).is_err() {
+            remove_refs(i);
+            return false;
+        }
+    }
+    let Some(flush) = huge::map(
+        dst_mapper,
+        dst_page,
+        mapping.frame,
+        flags.write(false),
+        mapping.order,
+    ) else {
+        remove_refs(mapping.page_count());
+        return false;
+    };
+    flush.ignore();
+    dst_flusher.queue(mapping.frame, None, TlbShootdownActions::NEW_MAPPING);
+
+    if let Some((_, flush)) = huge::remap(src_mapper, mapping.base, mapping.flags.write(false)) {
+        flush.ignore();
+        src_flusher.queue(mapping.frame, None, TlbShootdownActions::REVOKE_WRITE);
+    }
+    true
+}
+/// Split the huge pages that contain `page` other than at their start, so that a grant can be
+/// split at `page`.
+fn split_huge_pages_at(
+    mapper: &mut PageMapper,
+    page: Page,
+    flusher: &mut impl GenericFlusher,
+) -> Result<(), Enomem> {
+    while let Some(mapping) = huge::lookup(mapper, page).filter(|mapping| mapping.base != page) {
+        unsafe { huge::split(mapper, &mapping) }.ok_or(Enomem)?;
+        flusher.queue(mapping.frame, None, TlbShootdownActions::SPLIT);
+    }
+    Ok(())
+}
+
+/// Map a zeroed huge page around `page`, if it is in private anonymous memory and the huge page
+/// would lie within the grant, in a region where nothing has been mapped or swapped out yet, and
+/// it can be charged to `limits`.
+fn map_huge_zeroed(
+    addr_space_lock: &AddrSpaceWrapper,
+    addr_space: &mut AddrSpace,
+    limits: &limit::Limits,
+    page: Page,
+) -> Option<PageFlush<RmmA>> {
+    let (grant_base, grant_info) = addr_space.grants.contains(page)?;
+    if !matches!(
+        grant_info.provider,
+        Provider::Allocated {
+            cow_file_ref: None,
+            phys_contiguous: false,
+        }
+    ) || !grant_info.flags().has_write()
+    {
+        return None;
+    }
+    let grant_span = PageSpan::new(grant_base, grant_info.page_count);
+    let flags = grant_info.flags();
+
+    for order in huge::orders() {
+        let span = huge::aligned_span(page, order);
+        if span.base < grant_span.base
+            || span.end() > grant_span.end()
+            || addr_space.grants.swapped_in_span(span).next().is_some()
+            || !huge::is_unmapped(&addr_space.table.utable, span.base, order)
+        {
+            continue;
+        }
+        let mut charge = addr_space_lock.charge.lock();
+        if charge
+            .try_charge(limits.clone(), span.count, || addr_space.resident_pages())
+            .is_err()
+        {
+            continue;
+        }
+        let Some(frame) = allocate_p2frame(order) else {
+            charge.uncharge(span.count);
+            continue;
+        };
+        let Some(flush) =
+            (unsafe { huge::map(&mut addr_space.table.utable, span.base, frame, flags, order) })
+        else {
+            unsafe { deallocate_p2frame(frame, order) };
+            charge.uncharge(span.count);
+            continue;
+        };
+        // Every frame is refcounted, so that the huge page can be split.
+        for i in 0..span.count {
+            get_page_info(frame.next_by(i))
+                .expect("PageInfo must exist for allocated frame")
+                .refcount
+                .store(RefCount::One.to_raw(), Ordering::Relaxed);
+        }
+        addr_space.grants.add_resident(span.base, span.count);
+        return Some(flush);
+    }
+    None
+}
+
+/// If `page` is in a guard reservation directly below a grow-down grant, grow the grant down to
+/// `page`, failing if that would leave fewer than `STACK_GUARD_PAGES` of the reservation.
+fn grow_down(addr_space: &mut AddrSpace, page: Page) -> Result<(), PfError> {
+    let Some((guard_base, guard_info)) = addr_space.grants.contains(page) else {
+        return Ok(());
+    };
+    if !guard_info.is_guard() {
+        return Ok(());
+    }
+    let guard_span = PageSpan::new(guard_base, guard_info.page_count);
+    let Some((_, stack_info)) = addr_space
+        .grants
+        .contains(guard_span.end())
+        .filter(|(_, info)| info.grows_down)
+    else {
+        return Ok(());
+    };
+    if page.offset_from(guard_base) < STACK_GUARD_PAGES {
+        log::debug!("Stack overflow into guard pages");
+        return Err(PfError::Segv);
+    }
+    let (flags, locked, mergeable) = (stack_info.flags, stack_info.locked, stack_info.mergeable);
+    let grow_span = PageSpan::between(page, guard_span.end());
+
+    if let Some(uid) = locked {
+        mlock::charge(uid, grow_span.count).map_err(|_| PfError::Segv)?;
+    }
+
+    let guard = addr_space
+        .grants
+        .remove(guard_base)
+        .expect("grant cannot magically disappear while we hold the lock!");
+    let (before, mut grown, _) = guard
+        .extract(grow_span, &addr_space.table.utable)
+        .expect("failed to extract guard reservation");
+    if let Some(before) = before {
+        addr_space.grants.insert(before);
+    }
+
+    // Nothing is mapped in the reservation, so it can simply become lazily allocated memory,
+    // which is merged with the grant above.
+    grown.info.flags = flags;
+    grown.info.locked = locked;
+    grown.info.grows_down = true;
+    grown.info.mergeable = mergeable;
+    grown.info.provider = Provider::Allocated {
+        cow_file_ref: None,
+        phys_contiguous: false,
+    };
+    addr_space.grants.insert(grown);
+    Ok(())
+}
+
+/// The frame mapped at `page` and its flags, if it is in a grant that KSM may merge, and is
+/// neither shared with anything else, part of a huge page, nor freed with MADV_FREE.
+fn ksm_frame(addr_space: &AddrSpace, page: Page) -> Option<(Frame, PageFlags<RmmA>)> {
+    let (_, info) = addr_space.grants.contains(page)?;
+    if !info.mergeable
+        || !matches!(
+            info.provider,
+            Provider::Allocated {
+                cow_file_ref: None,
+                phys_contiguous: false,
+            }
+        )
+        || addr_space.grants.lazy_free.contains(&page)
+        || huge::lookup(&addr_space.table.utable, page).is_some()
+    {
+        return None;
+    }
+    let (phys, flags) = addr_space.table.utable.translate(page.start_address())?;
+    let frame = Frame::containing(phys);
+    (get_page_info(frame)?.refcount() == Some(RefCount::One)).then_some((frame, flags))
+}
+/// Write-protect `page`, and wait for the TLB to be flushed, so that the contents of `frame`
+/// cannot change without the address space being locked again.
+fn ksm_write_protect(addr_space: &mut AddrSpace, tlb_ack: &AtomicU32, page: Page, frame: Frame) {
+    let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, tlb_ack);
+    if let Some((_, _, flush)) = unsafe {
+        addr_space
+            .table
+            .utable
+            .remap_with(page.start_address(), |flags| flags.write(false))
+    } {
+        unsafe {
+            flush.ignore();
+        }
+        flusher.queue(frame, None, TlbShootdownActions::REVOKE_WRITE);
+    }
+    flusher.flush();
+}
+
+/// The address spaces of all contexts, each once, ordered by address.
+pub(crate) fn all_addr_spaces() -> Vec<Arc<AddrSpaceWrapper>> {
+    let mut addr_spaces = super::contexts()
+        .iter()
+        .filter_map(|context_ref| context_ref.0.read().addr_space().ok().cloned())
+        .collect::<Vec<_>>();
+    addr_spaces.sort_unstable_by_key(Arc::as_ptr);
+    addr_spaces.dedup_by(|a, b| Arc::ptr_eq(a, b));
+    addr_spaces
+}
+/// Free the frames of pages freed with MADV_FREE in all address spaces. Returns whether any were
+/// freed.
+pub fn reclaim_lazy_free() -> bool {
+    all_addr_spaces()
+        .iter()
+        .map(|addr_space| addr_space.reclaim_lazy_free())
+        .sum::<usize>()
+        > 0
+}
+/// Free the lazily freed pages of the address spaces charged to `limit`, and recount their
+/// charges, which only grow between recounts. Returns whether the usage of the group went down.
+/// Must be called without holding any address space lock.
+fn reclaim_group(limit: &Arc<MemoryLimit>) -> bool {
+    let usage = limit.usage();
+
+    for addr_space in all_addr_spaces()
+        .iter()
+        .filter(|addr_space| addr_space.charge.lock().is_charged_to(limit))
+    {
+        addr_space.reclaim_lazy_free();
+        let resident = addr_space.acquire_read().resident_pages();
+        addr_space.charge.lock().recount(resident);
+    }
+    limit.usage() < usage
+}
 pub fn try_correcting_page_tables(faulting_page: Page, access: AccessMode) -> Result<(), PfError> {
     let Ok(addr_space_lock) = AddrSpace::current() else {
         log::debug!("User page fault without address space being set.");
@@ -2371,11 +4275,48 @@ pub fn try_correcting_page_tables(faulting_page: Page, access: AccessMode) -> Re
     };
 
     let lock = &addr_space_lock;
-    let (_, flush, _) = correct_inner(lock, lock.acquire_write(), faulting_page, access, 0)?;
-
-    flush.flush();
-
-    Ok(())
+    loop {
+        let limits = limit::current();
+        let mut guard = lock.acquire_write();
+        grow_down(&mut guard, faulting_page)?;
+        // Read faults map the shared zeroed frame instead, which costs no memory.
+        if access == AccessMode::Write
+            && let Some(flush) = map_huge_zeroed(lock, &mut guard, &limits, faulting_page)
+        {
+            flush.flush();
+            return Ok(());
+        }
+        match correct_inner(lock, guard, faulting_page, access, 0) {
+            Ok((_, flush, _)) => {
+                flush.flush();
+                return Ok(());
+            }
+            // If allocating a frame would exceed the memory limit of a group of the process,
+            // memory of the group is reclaimed, or failing that one of its processes killed.
+            Err(PfError::OverLimit(limit)) => {
+                if reclaim_group(&limit) || oom::kill_victim_in(limit.group) {
+                    continue;
+                }
+                return Err(PfError::Oom);
+            }
+            // Free memory by dropping lazily freed pages or paging out other pages, or failing
+            // that by killing a process, and try again.
+            Err(PfError::Oom) if reclaim_lazy_free() || swap::reclaim() || oom::kill_victim() => {
+                continue
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+/// Charge a frame about to be allocated for the grants of an address space to the memory limits of
+/// the current process. This is done where page faults allocate frames, rather than when they
+/// occur, as many faults do not allocate any.
+fn charge_frame(addr_space_lock: &AddrSpaceWrapper, grants: &UserGrants) -> Result<(), PfError> {
+    addr_space_lock
+        .charge
+        .lock()
+        .try_charge(limit::current(), 1, || grants.resident())
+        .map_err(PfError::OverLimit)
 }
 fn correct_inner<'l>(
     addr_space_lock: &'l Arc<AddrSpaceWrapper>,
@@ -2411,6 +4352,41 @@ fn correct_inner<'l>(
         _ => (),
     }
 
+    // Huge pages are mapped with the flags of their grant, except for those shared copy-on-write
+    // on fork. These are made writable again once no longer shared, and split otherwise, so that
+    // only the faulting page is copied. Other faults on huge pages can only be caused by stale TLB
+    // entries.
+    if let Some(mapping) = huge::lookup(&addr_space.table.utable, faulting_page)
+        && access == AccessMode::Write
+        && !mapping.flags.has_write()
+    {
+        if is_cow_shared(&mapping) {
+            split_huge_pages(&mut addr_space.table.utable, mapping.span(), &mut flusher)
+                .map_err(|_| PfError::Oom)?;
+        } else {
+            let (_, flush) =
+                unsafe { huge::remap(&mut addr_space.table.utable, faulting_page, grant_flags) }
+                    .ok_or(PfError::Oom)?;
+            drop(flusher);
+            return Ok((
+                mapping
+                    .frame
+                    .next_by(faulting_page.offset_from(mapping.base)),
+                flush,
+                addr_space_guard,
+            ));
+        }
+    } else if let Some(mapping) = huge::lookup(&addr_space.table.utable, faulting_page) {
+        drop(flusher);
+        return Ok((
+            mapping
+                .frame
+                .next_by(faulting_page.offset_from(mapping.base)),
+            PageFlush::new(faulting_page.start_address()),
+            addr_space_guard,
+        ));
+    }
+
     // By now, the memory at the faulting page is actually valid, but simply not yet mapped, either
     // at all, or with the required flags.
 
@@ -2425,8 +4401,57 @@ fn correct_inner<'l>(
     // single TLB entry, thus emulating 16k pages albeit with higher page table overhead. With the
     // correct madvise information, allocating 4 contiguous pages and mapping them together, might
     // be a useful future optimization.
-    //
-    // TODO: Readahead backwards, i.e. MAP_GROWSDOWN.
+
+    // Pages that have been paged out are read back from swap, which requires unlocking the address
+    // space while waiting for the swap daemon.
+    if faulting_frame_opt.is_none()
+        && let Provider::Allocated { .. } = grant_info.provider
+        && let Some(slot) = addr_space.grants.swapped(faulting_page)
+    {
+        drop(flusher);
+        charge_frame(addr_space_lock, &addr_space.grants)?;
+        drop(addr_space_guard);
+
+        let frame = swap::swap_in(slot).inspect_err(|_| addr_space_lock.uncharge_memory(1))?;
+
+        addr_space_guard = addr_space_lock.acquire_write();
+        addr_space = &mut *addr_space_guard;
+
+        // Another context may have paged it in, or unmapped it, in the meantime.
+        if addr_space.grants.swapped(faulting_page) != Some(slot)
+            || addr_space
+                .table
+                .utable
+                .translate(faulting_page.start_address())
+                .is_some()
+        {
+            drop(frame);
+            addr_space_lock.uncharge_memory(1);
+            return correct_inner(
+                addr_space_lock,
+                addr_space_guard,
+                faulting_page,
+                access,
+                recursion_level,
+            );
+        }
+
+        let Some(flush) = (unsafe {
+            addr_space.table.utable.map_phys(
+                faulting_page.start_address(),
+                frame.get().base(),
+                grant_flags,
+            )
+        }) else {
+            addr_space_lock.uncharge_memory(1);
+            return Err(PfError::Oom);
+        };
+        addr_space.grants.take_swapped(faulting_page);
+        addr_space.grants.add_resident(faulting_page, 1);
+        swap::release(slot);
+
+        return Ok((frame.take(), flush, addr_space_guard));
+    }
 
     let mut allow_writable = true;
 
@@ -2440,19 +4465,28 @@ fn correct_inner<'l>(
                     if info.allows_writable() {
                         frame
                     } else {
-                        let result = cow(frame, info, RefKind::Cow)?;
+                        charge_frame(addr_space_lock, &addr_space.grants)?;
+                        let result = cow(frame, info, RefKind::Cow)
+                            .inspect_err(|_| addr_space_lock.uncharge_memory(1))?;
                         if let Some(old_frame) = result.old_frame {
                             flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+                        } else {
+                            // The frame was no longer shared, and is now owned as it is.
+                            addr_space_lock.uncharge_memory(1);
                         }
                         result.new_frame
                     }
                 }
-                _ => map_zeroed(
-                    &mut addr_space.table.utable,
-                    faulting_page,
-                    grant_flags,
-                    true,
-                )?,
+                _ => {
+                    charge_frame(addr_space_lock, &addr_space.grants)?;
+                    map_zeroed(
+                        &mut addr_space.table.utable,
+                        faulting_page,
+                        grant_flags,
+                        true,
+                    )
+                    .inspect_err(|_| addr_space_lock.uncharge_memory(1))?
+                }
             }
         }
 
@@ -2473,16 +4507,24 @@ fn correct_inner<'l>(
 
                 None => {
                     // TODO: the zeroed page first, readonly?
+                    charge_frame(addr_space_lock, &addr_space.grants)?;
                     map_zeroed(
                         &mut addr_space.table.utable,
                         faulting_page,
                         grant_flags,
                         false,
-                    )?
+                    )
+                    .inspect_err(|_| addr_space_lock.uncharge_memory(1))?
                 }
             }
         }
         Provider::PhysBorrowed { base } => base.next_by(pages_from_grant_start),
+        // Pool huge pages are mapped eagerly, and only remain mapped by smaller pages if split.
+        Provider::HugePool { .. } => faulting_frame_opt.ok_or(PfError::Segv)?,
+        Provider::Guard => {
+            log::debug!("Access to guard reservation");
+            return Err(PfError::Segv);
+        }
         Provider::External {
             address_space: ref foreign_address_space,
             src_base,
@@ -2497,9 +4539,30 @@ fn correct_inner<'l>(
             let mut guard = foreign_address_space.acquire_upgradeable_read();
             let src_page = src_base.next_by(pages_from_grant_start);
 
+            // Pool huge pages can be borrowed as they are, as their frames are never CoW.
+            if huge::lookup(&guard.table.utable, src_page).is_some()
+                && !guard
+                    .grants
+                    .contains(src_page)
+                    .is_some_and(|(_, info)| matches!(info.provider, Provider::HugePool { .. }))
+            {
+                let mut write_guard = RwLockUpgradableGuard::upgrade(guard);
+                let foreign = &mut *write_guard;
+                split_huge_pages(
+                    &mut foreign.table.utable,
+                    PageSpan::new(src_page, 1),
+                    &mut Flusher::with_cpu_set(
+                        &mut foreign.used_by,
+                        &foreign_address_space.tlb_ack,
+                    ),
+                )
+                .map_err(|_| PfError::Oom)?;
+                guard = write_guard.downgrade_to_upgradeable();
+            }
+
             if let Some(_) = guard.grants.contains(src_page) {
                 let src_frame = if let Some((phys, _)) =
-                    guard.table.utable.translate(src_page.start_address())
+                    huge::translate(&guard.table.utable, src_page.start_address())
                 {
                     Frame::containing(phys)
                 } else {
@@ -2543,14 +4606,18 @@ fn correct_inner<'l>(
                 match info.add_ref(RefKind::Shared) {
                     Ok(()) => src_frame,
                     Err(AddRefError::CowToShared) => {
+                        charge_frame(&foreign_address_space, &guard.grants)?;
                         let CowResult {
                             new_frame,
                             old_frame,
-                        } = cow(src_frame, info, RefKind::Shared)?;
+                        } = cow(src_frame, info, RefKind::Shared)
+                            .inspect_err(|_| foreign_address_space.uncharge_memory(1))?;
 
                         if let Some(old_frame) = old_frame {
                             flusher.queue(old_frame, None, TlbShootdownActions::FREE);
                             flusher.flush();
+                        } else {
+                            foreign_address_space.uncharge_memory(1);
                         }
 
                         let mut guard = RwLockUpgradableGuard::upgrade(guard);
@@ -2564,6 +4631,9 @@ fn correct_inner<'l>(
                                     (new_frame.base(), f)
                                 });
                         }
+                        if src_frame == the_zeroed_frame().0 {
+                            guard.grants.add_resident(src_page, 1);
+                        }
 
                         new_frame
                     }
@@ -2588,9 +4658,33 @@ fn correct_inner<'l>(
             }
         }
         // TODO: NonfatalInternalError if !MAP_LAZY and this page fault occurs.
-        Provider::FmapBorrowed { ref file_ref, .. } => {
+        Provider::FmapBorrowed {
+            ref file_ref,
+            readahead,
+            ..
+        } => {
             let file_ref = file_ref.clone();
             let flags = map_flags(grant_info.flags());
+
+            // Request the following pages of the grant along with the faulting page, up to the
+            // first one that is already mapped.
+            let page_count = 1 + PageSpan::new(
+                faulting_page,
+                cmp::min(
+                    readahead.page_count(),
+                    grant_info.page_count - pages_from_grant_start,
+                ),
+            )
+            .pages()
+            .skip(1)
+            .take_while(|page| {
+                addr_space
+                    .table
+                    .utable
+                    .translate(page.start_address())
+                    .is_none()
+            })
+            .count();
             drop(flusher);
             drop(addr_space_guard);
 
@@ -2608,10 +4702,11 @@ fn correct_inner<'l>(
                 })
                 .ok_or(PfError::Segv)?;
 
-            let offset = file_ref.base_offset as u64 + (pages_from_grant_start * PAGE_SIZE) as u64;
+            let offset = file_ref.base_offset + pages_from_grant_start * PAGE_SIZE;
             user_inner
-                .request_fmap(scheme_number, offset, 1, flags)
+                .request_fmap(scheme_number, offset as u64, page_count, flags)
                 .unwrap();
+            let description = Arc::clone(&file_ref.description);
 
             let context_lock = crate::context::current();
             context_lock
@@ -2620,11 +4715,13 @@ fn correct_inner<'l>(
 
             super::switch();
 
-            let frame = context_lock
+            let mut frames = context_lock
                 .write()
                 .fmap_ret
                 .take()
-                .ok_or(PfError::NonfatalInternalError)?;
+                .ok_or(PfError::NonfatalInternalError)?
+                .into_iter();
+            let frame = frames.next().ok_or(PfError::NonfatalInternalError)?;
 
             addr_space_guard = addr_space_lock.acquire_write();
             addr_space = &mut *addr_space_guard;
@@ -2632,6 +4729,46 @@ fn correct_inner<'l>(
 
             log::info!("Got frame {:?} from external fmap", frame);
 
+            // Map the pages read ahead, unless they were unmapped or faulted in meanwhile. As the
+            // address space was unlocked, another mapping may have replaced the grant, in which
+            // case the frames are dropped unless they are still at the same offset of the file.
+            for (i, readahead_frame) in frames.enumerate() {
+                let page = faulting_page.next_by(i + 1);
+                let Some((base, info)) = addr_space.grants.contains(page) else {
+                    continue;
+                };
+                let same_file = match info.provider {
+                    Provider::FmapBorrowed { ref file_ref, .. } => {
+                        Arc::ptr_eq(&file_ref.description, &description)
+                            && file_ref.base_offset + page.offset_from(base) * PAGE_SIZE
+                                == offset + (i + 1) * PAGE_SIZE
+                    }
+                    _ => false,
+                };
+                if !same_file
+                    || addr_space
+                        .table
+                        .utable
+                        .translate(page.start_address())
+                        .is_some()
+                {
+                    continue;
+                }
+                let page_flags = info.flags();
+                if let Some(flush) = unsafe {
+                    addr_space.table.utable.map_phys(
+                        page.start_address(),
+                        readahead_frame.base(),
+                        page_flags,
+                    )
+                } {
+                    unsafe {
+                        flush.ignore();
+                    }
+                    flusher.queue(readahead_frame, None, TlbShootdownActions::NEW_MAPPING);
+                }
+            }
+
             frame
         }
     };
@@ -2646,6 +4783,10 @@ fn correct_inner<'l>(
         // TODO
         return Err(PfError::Oom);
     };
+    let zeroed = the_zeroed_frame().0;
+    if frame != zeroed && faulting_frame_opt.map_or(true, |old| old == zeroed) {
+        addr_space.grants.add_resident(faulting_page, 1);
+    }
 
     drop(flusher);
     Ok((frame, flush, addr_space_guard))
@@ -2702,6 +4843,22 @@ impl GenericFlusher for NopFlusher {
 }
 fn handle_free_action(base: Frame, phys_contiguous_count: Option<NonZeroUsize>) {
     if let Some(count) = phys_contiguous_count {
+        // Huge pages from the pool are returned to it rather than to the frame allocator.
+        if hugepool::release(base, count.get()) {
+            return;
+        }
+        // Huge pages shared copy-on-write on fork may still be mapped elsewhere, in part if they
+        // have been split there since, so their frames are freed one by one.
+        let frames = (0..count.get()).map(|i| base.next_by(i));
+        if frames
+            .clone()
+            .any(|frame| get_page_info(frame).and_then(PageInfo::refcount) != Some(RefCount::One))
+        {
+            for frame in frames {
+                handle_free_action(frame, None);
+            }
+            return;
+        }
         for i in 0..count.get() {
             let new_rc = get_page_info(base.next_by(i))
                 .expect("phys_contiguous frames all need PageInfos")
@@ -2845,6 +5002,14 @@ impl Drop for Flusher<'_, '_> {
         self.flush();
     }
 }
+bitflags::bitflags! {
+    /// Flags of `ADDRSPACE_OP_MLOCKALL`, with the values of the `MCL_*` constants.
+    pub struct MlockallFlags: usize {
+        const CURRENT = 1;
+        const FUTURE = 2;
+        const ONFAULT = 4;
+    }
+}
 bitflags::bitflags! {
     pub struct TlbShootdownActions: usize {
         // Delay the deallocation of one or more contiguous frames.
@@ -2858,6 +5023,9 @@ bitflags::bitflags! {
         // Unmap a page from one address space without deallocating it.
         const MOVE = 1 << 4;
 
+        // Replace a huge page with smaller pages mapping the same frames.
+        const SPLIT = 1 << 5;
+
         // Add a new mapping to an address space.
         // Not really a TLB shootdown action on most architectures, so almost always a no-op.
         const NEW_MAPPING = 1 << 31;
@@ -2,7 +2,12 @@
 //!
 //! For resources on contexts, please consult [wikipedia](https://en.wikipedia.org/wiki/Context_switch) and  [osdev](https://wiki.osdev.org/Context_Switching)
 
-use alloc::{borrow::Cow, collections::BTreeSet, sync::Arc, vec::Vec};
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use spinning_top::RwSpinlock;
@@ -51,12 +56,18 @@ pub mod switch;
 /// File struct - defines a scheme and a file number
 pub mod file;
 
+/// Load averages and per-CPU utilization
+pub mod load;
+
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
 /// Process handling - TODO move to userspace
 pub mod process;
 
+/// CPU bandwidth quotas for process groups and sessions
+pub mod quota;
+
 /// Signal handling
 pub mod signal;
 
@@ -76,6 +87,11 @@ static KMAIN_PROCESS: Once<Arc<RwLock<Process>>> = Once::new();
 // the context file descriptors.
 static CONTEXTS: RwLock<BTreeSet<ContextRef>> = RwLock::new(BTreeSet::new());
 
+// The same contexts by thread ID, so that the owners of PI futexes can be found by the thread ID in
+// their futex word.
+static CONTEXTS_BY_TID: RwLock<BTreeMap<usize, Weak<RwSpinlock<Context>>>> =
+    RwLock::new(BTreeMap::new());
+
 pub fn init() {
     let pid = ProcessId::new(0);
     let process = KMAIN_PROCESS.call_once(|| {
@@ -100,10 +116,9 @@ pub fn init() {
     context.cpu_id = Some(crate::cpu_id());
 
     let context_lock = Arc::new(RwSpinlock::new(context));
+    context_lock.write().self_ref = Arc::downgrade(&context_lock);
 
-    CONTEXTS
-        .write()
-        .insert(ContextRef(Arc::clone(&context_lock)));
+    insert(&context_lock);
 
     unsafe {
         let percpu = PercpuBlock::current();
@@ -114,6 +129,30 @@ pub fn init() {
     }
 }
 
+fn insert(context_lock: &Arc<RwSpinlock<Context>>) {
+    let tid = context_lock.read().tid;
+    CONTEXTS
+        .write()
+        .insert(ContextRef(Arc::clone(context_lock)));
+    CONTEXTS_BY_TID
+        .write()
+        .insert(tid, Arc::downgrade(context_lock));
+}
+
+/// Remove an exiting context, so that it is no longer scheduled or found by its thread ID.
+pub fn remove(context_lock: &Arc<RwSpinlock<Context>>) {
+    let tid = context_lock.read().tid;
+    CONTEXTS_BY_TID.write().remove(&tid);
+    CONTEXTS
+        .write()
+        .remove(&ContextRef(Arc::clone(context_lock)));
+}
+
+/// Find the context with thread ID `tid`, if it has not exited.
+pub fn by_tid(tid: usize) -> Option<Arc<RwSpinlock<Context>>> {
+    CONTEXTS_BY_TID.read().get(&tid)?.upgrade()
+}
+
 /// Get the global schemes list, const
 pub fn contexts() -> RwLockReadGuard<'static, BTreeSet<ContextRef>> {
     CONTEXTS.read()
@@ -163,6 +202,18 @@ impl PartialEq for ContextRef {
 }
 impl Eq for ContextRef {}
 
+/// Spawn a kernel thread in the kmain process, running `func`, which must never return.
+pub fn spawn_kernel(name: &'static str, func: extern "C" fn()) -> Result<Arc<RwSpinlock<Context>>> {
+    let process = Arc::clone(KMAIN_PROCESS.get().expect("kmain process not initialized"));
+    let context_lock = spawn(false, process, func)?;
+    {
+        let mut context = context_lock.write();
+        context.name = Cow::Borrowed(name);
+        context.set_runnable();
+    }
+    Ok(context_lock)
+}
+
 /// Spawn a context from a function.
 pub fn spawn(
     userspace_allowed: bool,
@@ -177,13 +228,12 @@ pub fn spawn(
     )?))
     .map_err(|_| Error::new(ENOMEM))?;
 
-    CONTEXTS
-        .write()
-        .insert(ContextRef(Arc::clone(&context_lock)));
+    insert(&context_lock);
 
     process.write().threads.push(Arc::downgrade(&context_lock));
     {
         let mut context = context_lock.write();
+        context.self_ref = Arc::downgrade(&context_lock);
         let _ = context.set_addr_space(Some(AddrSpaceWrapper::new()?));
         context
             .arch
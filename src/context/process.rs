@@ -54,6 +54,10 @@ pub struct ProcessInfo {
     pub egid: u32,
     /// The effective namespace id
     pub ens: SchemeNamespace,
+    /// Adjustment of the OOM score, from -1000 (never killed) to 1000
+    pub oom_score_adj: i16,
+    /// Whether address spaces created by this process for exec have their layout randomized
+    pub aslr: bool,
 }
 impl Deref for Process {
     type Target = ProcessInfo;
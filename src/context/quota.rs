@@ -0,0 +1,140 @@
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::context::{process::ProcessId, Context};
+
+/// A group of processes that can share a CPU quota.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum QuotaGroup {
+    ProcessGroup(ProcessId),
+    Session(ProcessId),
+}
+
+#[derive(Debug)]
+pub struct CpuQuota {
+    /// CPU time the group may use per period, summed over all CPUs, in nanoseconds
+    pub quota: u128,
+    /// Length of a period, in nanoseconds
+    pub period: u128,
+    /// Start of the current period
+    pub period_start: u128,
+    /// CPU time used in the current period
+    pub used: u128,
+    /// CPU time used since the quota was set
+    pub total_used: u128,
+    /// Number of periods in which the group was throttled
+    pub throttled_periods: u64,
+    throttled_this_period: bool,
+}
+impl CpuQuota {
+    fn roll_period(&mut self, now: u128) {
+        if now.saturating_sub(self.period_start) >= self.period {
+            // Periods are aligned to when the quota was set, so that usage is comparable between
+            // periods.
+            let elapsed = now - self.period_start;
+            self.period_start = now - elapsed % self.period;
+            self.used = 0;
+            self.throttled_this_period = false;
+        }
+    }
+    fn throttled_until(&mut self, now: u128) -> Option<u128> {
+        self.roll_period(now);
+        if self.used < self.quota {
+            return None;
+        }
+        if !self.throttled_this_period {
+            self.throttled_this_period = true;
+            self.throttled_periods += 1;
+        }
+        Some(self.period_start + self.period)
+    }
+}
+
+// Number of quotas, to skip looking up the process groups of contexts when there are none.
+static QUOTA_COUNT: AtomicUsize = AtomicUsize::new(0);
+static QUOTAS: RwLock<BTreeMap<QuotaGroup, Arc<Mutex<CpuQuota>>>> = RwLock::new(BTreeMap::new());
+
+/// Set the CPU quota of a group, replacing any previous one, or remove it if `quota` is `None`.
+pub fn set(group: QuotaGroup, quota: Option<(u128, u128)>) {
+    let mut quotas = QUOTAS.write();
+
+    match quota {
+        Some((quota, period)) => {
+            let now = crate::time::monotonic();
+            let entry = quotas.entry(group).or_insert_with(|| {
+                Arc::new(Mutex::new(CpuQuota {
+                    quota,
+                    period,
+                    period_start: now,
+                    used: 0,
+                    total_used: 0,
+                    throttled_periods: 0,
+                    throttled_this_period: false,
+                }))
+            });
+            let mut entry = entry.lock();
+            entry.quota = quota;
+            entry.period = period;
+        }
+        None => {
+            quotas.remove(&group);
+        }
+    }
+    QUOTA_COUNT.store(quotas.len(), Ordering::Relaxed);
+}
+
+/// Whether any quota is set, in which case running contexts need to be charged regularly.
+pub fn is_active() -> bool {
+    QUOTA_COUNT.load(Ordering::Relaxed) > 0
+}
+
+/// Call `f` with every group and its quota.
+pub fn for_each(mut f: impl FnMut(QuotaGroup, &mut CpuQuota)) {
+    let now = crate::time::monotonic();
+
+    for (group, quota) in QUOTAS.read().iter() {
+        let mut quota = quota.lock();
+        quota.roll_period(now);
+        f(*group, &mut quota);
+    }
+}
+
+/// Call `f` with the quota of every group `context` belongs to, returning the latest time.
+fn with_quotas(
+    context: &Context,
+    mut f: impl FnMut(&mut CpuQuota) -> Option<u128>,
+) -> Option<u128> {
+    if !is_active() {
+        return None;
+    }
+    let (pgid, session_id) = {
+        let process = context.process.read();
+        (process.pgid, process.session_id)
+    };
+    let quotas = QUOTAS.read();
+
+    [
+        QuotaGroup::ProcessGroup(pgid),
+        QuotaGroup::Session(session_id),
+    ]
+    .iter()
+    .filter_map(|group| quotas.get(group))
+    .fold(None, |acc, quota| f(&mut quota.lock()).max(acc))
+}
+
+/// Charge `delta` nanoseconds of CPU time used by `context` to its groups. Returns the end of the
+/// current period, if one of the groups has now exceeded its quota.
+pub fn charge(context: &Context, delta: u128, now: u128) -> Option<u128> {
+    with_quotas(context, |quota| {
+        quota.roll_period(now);
+        quota.used += delta;
+        quota.total_used += delta;
+        quota.throttled_until(now)
+    })
+}
+
+/// If one of the groups of `context` has exceeded its quota, returns when it may run again.
+pub fn throttled_until(context: &Context, now: u128) -> Option<u128> {
+    with_quotas(context, |quota| quota.throttled_until(now))
+}
@@ -1,61 +1,429 @@
 use core::{
     cell::{Cell, RefCell},
+    cmp::Reverse,
     mem,
-    ops::Bound,
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
-use alloc::sync::Arc;
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+use spin::Mutex;
 use spinning_top::{guard::ArcRwSpinlockWriteGuard, RwSpinlock};
 use syscall::PtraceFlags;
 
 use crate::{
-    context::{arch, contexts, Context},
+    context::{arch, load::CpuStats, quota, Context},
     cpu_set::LogicalCpuId,
     interrupt,
+    ipi::{ipi_single, IpiKind},
+    percpu,
     percpu::PercpuBlock,
-    ptrace, time,
+    ptrace,
 };
 
-use super::ContextRef;
+/// Upper bound on how many queued contexts are inspected on another CPU when trying to steal
+/// work, so that an idle CPU never spins for long with a remote run queue locked.
+const STEAL_SCAN_LIMIT: usize = 8;
+
+/// How far behind the least-served queued context a woken context may be placed, in weighted
+/// nanoseconds. This lets contexts that mostly sleep, such as interactive ones, run soon after
+/// waking up, without letting a long sleep turn into a long monopoly of the CPU.
+const WAKEUP_GRANULARITY: u128 = 3_000_000;
+
+/// Default time slice, which is 3 PIT ticks (about 6.75 ms).
+const DEFAULT_TIME_SLICE: u64 = 6_750_000;
+/// Default lower bound on the time slice when it is shortened due to load, which is 1 PIT tick.
+const DEFAULT_MIN_TIME_SLICE: u64 = 2_250_000;
+
+/// Time slice in nanoseconds for CPUs without their own setting. When other contexts are
+/// waiting, the slice is divided between all of them, so that each gets to run within about one
+/// time slice, but never below [`MIN_TIME_SLICE`]. Setting both to the same value disables this
+/// scaling, trading latency for throughput.
+pub static TIME_SLICE: AtomicU64 = AtomicU64::new(DEFAULT_TIME_SLICE);
+pub static MIN_TIME_SLICE: AtomicU64 = AtomicU64::new(DEFAULT_MIN_TIME_SLICE);
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+pub const RT_PRIORITY_MIN: u8 = 1;
+pub const RT_PRIORITY_MAX: u8 = 99;
+
+/// Static priority of a time-sharing context with the default nice value of 0. Lower is more
+/// important, and real-time contexts use the priorities from 0 to 98, above all time-sharing ones.
+pub const DEFAULT_PRIORITY: u8 = 120;
+
+/// Default time a round-robin context may run before yielding to others of the same priority.
+pub const DEFAULT_RR_QUANTUM: u128 = 100_000_000;
+/// Lower bound on the round-robin quantum, as anything shorter is below the timer resolution.
+pub const MIN_RR_QUANTUM: u128 = 1_000_000;
+
+/// Real-time contexts may only use [`RT_RUNTIME`] out of every [`RT_PERIOD`] nanoseconds on each
+/// CPU, so that a runaway real-time context cannot starve everything else, including the shell
+/// that would be used to kill it.
+const RT_PERIOD: u128 = 1_000_000_000;
+const RT_RUNTIME: u128 = 950_000_000;
+
+pub const SCHED_OTHER: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+pub const SCHED_RR: usize = 2;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SchedPolicy {
+    /// Time-sharing, where contexts get CPU time in proportion to the weight of their nice value.
+    #[default]
+    Other,
+    /// Real-time, running until blocking or yielding, unless preempted by a higher priority.
+    Fifo,
+    /// Real-time like [`SchedPolicy::Fifo`], but taking turns with contexts of the same priority
+    /// once the round-robin quantum has been used up.
+    RoundRobin,
+}
+impl SchedPolicy {
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        Some(match raw {
+            SCHED_OTHER => Self::Other,
+            SCHED_FIFO => Self::Fifo,
+            SCHED_RR => Self::RoundRobin,
+            _ => return None,
+        })
+    }
+    pub fn to_raw(self) -> usize {
+        match self {
+            Self::Other => SCHED_OTHER,
+            Self::Fifo => SCHED_FIFO,
+            Self::RoundRobin => SCHED_RR,
+        }
+    }
+    pub fn is_realtime(self) -> bool {
+        self != Self::Other
+    }
+}
+
+const NICE_0_WEIGHT: u128 = 1024;
+
+/// Scheduling weight for each nice value from [`NICE_MIN`] to [`NICE_MAX`], where each step
+/// corresponds to roughly 10% of CPU time relative to a context with an adjacent nice value.
+/// These are the same weights as used by Linux.
+const NICE_TO_WEIGHT: [u32; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+fn nice_weight(nice: i8) -> u128 {
+    let idx = (nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize;
+    NICE_TO_WEIGHT
+        .get(idx)
+        .map_or(NICE_0_WEIGHT, |w| u128::from(*w))
+}
+
+/// Charge the time since the context was last accounted for, to both its CPU time and its
+/// weighted virtual runtime. Returns the time charged.
+fn update_times(context: &mut Context, now: u128) -> u128 {
+    let delta = now.saturating_sub(context.switch_time);
+
+    context.cpu_time += delta;
+    context.sched_vruntime += delta * NICE_0_WEIGHT / nice_weight(context.sched_nice);
+    context.sched_slice_used += delta;
+    context.switch_time = now;
+
+    delta
+}
+
+/// Whether a round-robin context has used up its quantum, and should go to the back of the queue.
+fn rr_quantum_expired(context: &Context) -> bool {
+    context.sched_policy == SchedPolicy::RoundRobin
+        && context.sched_slice_used >= context.sched_rr_quantum
+}
 
-enum UpdateResult {
-    CanSwitch,
-    Skip,
+/// What the context currently running on a CPU is, when looking for a context to preempt it.
+#[derive(Clone, Copy)]
+enum PrevClass {
+    /// The previous context cannot continue, or is the idle context.
+    None,
+    Normal {
+        vruntime: u128,
+    },
+    RealTime {
+        priority: u8,
+        quantum_expired: bool,
+    },
 }
 
-unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> UpdateResult {
-    // Ignore already running contexts
-    if context.running {
-        return UpdateResult::Skip;
+type ContextLock = Arc<RwSpinlock<Context>>;
+
+#[derive(Default)]
+struct RunQueueInner {
+    /// Queued contexts ordered by virtual runtime, and then by address to make keys unique.
+    entries: BTreeMap<(u128, usize), ContextLock>,
+    /// Monotonically increasing virtual runtime of the contexts picked from this queue.
+    min_vruntime: u128,
+    /// Queued real-time contexts, ordered by priority, and then by a sequence number which is
+    /// decremented when queueing at the front and incremented when queueing at the back.
+    rt_entries: BTreeMap<(Reverse<u8>, i64), ContextLock>,
+    rt_front: i64,
+    rt_back: i64,
+}
+impl RunQueueInner {
+    fn len(&self) -> usize {
+        self.entries.len() + self.rt_entries.len()
     }
+}
 
-    // Ignore contexts assigned to other CPUs
-    if !context.sched_affinity.contains(cpu_id) {
-        return UpdateResult::Skip;
+/// Lock the first queued context in `entries` that may be moved to `cpu_id`, without blocking.
+fn find_stealable<'a, K: Copy + 'a>(
+    entries: impl Iterator<Item = (&'a K, &'a ContextLock)>,
+    cpu_id: LogicalCpuId,
+) -> Option<(K, ArcRwSpinlockWriteGuard<Context>)> {
+    for (key, context_lock) in entries.take(STEAL_SCAN_LIMIT) {
+        // Lock order is context before run queue, so never block on the context lock here.
+        let Some(guard) = context_lock.try_write_arc() else {
+            continue;
+        };
+        if guard.running || !guard.status.is_runnable() || !guard.sched_affinity.contains(cpu_id) {
+            continue;
+        }
+        return Some((*key, guard));
     }
+    None
+}
+
+/// A per-CPU queue of contexts that are runnable, but not currently running.
+///
+/// Real-time contexts are always picked first, in order of priority. Other contexts are picked in
+/// order of least virtual runtime, i.e. CPU time weighted by their nice value, so that every
+/// context receives a share of the CPU proportional to its weight.
+///
+/// Contexts are only guaranteed to be runnable at the time they were queued. They may have been
+/// blocked again since, in which case they are dropped from the queue when popped, and queued
+/// again when unblocked.
+#[derive(Default)]
+pub struct RunQueue {
+    inner: Mutex<RunQueueInner>,
+    len: AtomicUsize,
+}
+impl RunQueue {
+    fn push(&self, context: &mut Context, context_lock: ContextLock, at_front: bool) {
+        let mut inner = self.inner.lock();
 
-    //TODO: HACK TO WORKAROUND HANGS BY PINNING TO ONE CPU
-    if !context.cpu_id.map_or(true, |x| x == cpu_id) {
-        return UpdateResult::Skip;
+        if let Some(priority) = context.rt_priority() {
+            let seq = if at_front {
+                inner.rt_front -= 1;
+                inner.rt_front
+            } else {
+                inner.rt_back += 1;
+                inner.rt_back
+            };
+            inner
+                .rt_entries
+                .insert((Reverse(priority), seq), context_lock);
+        } else {
+            context.sched_vruntime = context
+                .sched_vruntime
+                .max(inner.min_vruntime.saturating_sub(WAKEUP_GRANULARITY));
+            inner.entries.insert(
+                (context.sched_vruntime, Arc::as_ptr(&context_lock) as usize),
+                context_lock,
+            );
+        }
+        self.len.store(inner.len(), Ordering::Relaxed);
     }
+    /// Pop the next context that should preempt `prev`. Real-time contexts are only considered
+    /// if `rt_allowed`, i.e. if this CPU has not exceeded its real-time budget.
+    fn pop(&self, prev: PrevClass, rt_allowed: bool) -> Option<ContextLock> {
+        let mut inner = self.inner.lock();
 
-    // Wake from sleep
-    if context.status.is_soft_blocked() && context.wake.is_some() {
-        let wake = context.wake.expect("context::switch: wake not set");
+        if rt_allowed && let Some(&(Reverse(priority), _)) = inner.rt_entries.keys().next() {
+            let preempts = match prev {
+                PrevClass::RealTime {
+                    priority: prev_priority,
+                    quantum_expired,
+                } => priority > prev_priority || (priority == prev_priority && quantum_expired),
+                PrevClass::None | PrevClass::Normal { .. } => true,
+            };
+            if !preempts {
+                return None;
+            }
+            let (_, context) = inner.rt_entries.pop_first()?;
+            self.len.store(inner.len(), Ordering::Relaxed);
+            return Some(context);
+        }
 
-        let current = time::monotonic();
-        if current >= wake {
-            context.wake = None;
-            context.unblock_no_ipi();
+        let before = match prev {
+            PrevClass::None => None,
+            PrevClass::Normal { vruntime } => Some(vruntime),
+            // Time-sharing contexts never preempt real-time ones.
+            PrevClass::RealTime { .. } => return None,
+        };
+        let &(vruntime, _) = inner.entries.first_key_value()?.0;
+        if before.is_some_and(|before| vruntime >= before) {
+            return None;
         }
+        let (_, context) = inner.entries.pop_first()?;
+        inner.min_vruntime = inner.min_vruntime.max(vruntime);
+        self.len.store(inner.len(), Ordering::Relaxed);
+        Some(context)
+    }
+    /// Remove a context from the queue, returning whether it was queued here.
+    fn remove(&self, context_lock: &ContextLock) -> bool {
+        let mut inner = self.inner.lock();
+
+        let len = inner.len();
+        inner
+            .entries
+            .retain(|_, queued| !Arc::ptr_eq(queued, context_lock));
+        inner
+            .rt_entries
+            .retain(|_, queued| !Arc::ptr_eq(queued, context_lock));
+        self.len.store(inner.len(), Ordering::Relaxed);
+        inner.len() != len
+    }
+    /// Approximate number of queued contexts, only used as a load estimate.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
     }
+    fn min_vruntime(&self) -> u128 {
+        self.inner.lock().min_vruntime
+    }
+    /// Try to take a runnable context that is allowed to run on `cpu_id`. The highest priority
+    /// real-time contexts are taken first if `rt_allowed`, and otherwise contexts are taken from the back of the
+    /// queue, where the ones least likely to still be cache-hot are. The virtual runtime of a
+    /// stolen context is rebased onto `dst_min_vruntime`, the clock of the stealing CPU's queue.
+    fn steal(
+        &self,
+        cpu_id: LogicalCpuId,
+        dst_min_vruntime: u128,
+        rt_allowed: bool,
+    ) -> Option<ArcRwSpinlockWriteGuard<Context>> {
+        let mut inner = self.inner.lock();
+
+        let rt_found = if rt_allowed {
+            find_stealable(inner.rt_entries.iter(), cpu_id)
+        } else {
+            None
+        };
+        let mut guard = if let Some((key, guard)) = rt_found {
+            drop(inner.rt_entries.remove(&key));
+            guard
+        } else {
+            let (key, mut guard) = find_stealable(inner.entries.iter().rev(), cpu_id)?;
+            drop(inner.entries.remove(&key));
+            guard.sched_vruntime =
+                guard.sched_vruntime.saturating_sub(inner.min_vruntime) + dst_min_vruntime;
+            guard
+        };
+        guard.sched_queue = None;
+        self.len.store(inner.len(), Ordering::Relaxed);
+        Some(guard)
+    }
+}
+
+/// Choose the run queue for a context that has become runnable.
+fn select_cpu(context: &mut Context) -> LogicalCpuId {
+    let current = crate::cpu_id();
+    // Claim idle CPUs, so that contexts woken at the same time are spread across them.
+    let claim_idle =
+        |cpu_id| percpu::get(cpu_id).is_some_and(|block| block.switch_internals.claim_idle());
 
-    // Switch to context if it needs to run
-    if context.status.is_runnable() {
-        UpdateResult::CanSwitch
+    // Prefer the CPU the context last ran on, since its caches are probably still warm.
+    let preferred = if let Some(cpu_id) = context.cpu_id
+        && context.sched_affinity.contains(cpu_id)
+        && percpu::get(cpu_id).is_some()
+    {
+        Some(cpu_id)
+    } else if context.sched_affinity.contains(current) {
+        Some(current)
     } else {
-        UpdateResult::Skip
+        None
+    };
+    if let Some(cpu_id) = preferred
+        && claim_idle(cpu_id)
+    {
+        return cpu_id;
+    }
+
+    // Otherwise, an idle CPU can run the context right away, rather than making it wait for
+    // another context to be preempted.
+    let mut allowed = context
+        .sched_affinity
+        .iter_mut()
+        .take_while(|id| id.get() < crate::cpu_count())
+        .filter(|id| percpu::get(*id).is_some());
+    let first_allowed = allowed.next();
+    if let Some(idle) = first_allowed
+        .into_iter()
+        .chain(allowed)
+        .find(|id| claim_idle(*id))
+    {
+        return idle;
+    }
+    preferred.or(first_allowed).unwrap_or(current)
+}
+
+/// Put a runnable context on a run queue, unless it is already queued or running. Must be called
+/// with the context lock held.
+pub fn enqueue(context: &mut Context) {
+    enqueue_at(context, false);
+}
+
+/// Queue a context again after its priority changed, so that it is picked according to the new
+/// one. Must be called with the context lock held.
+pub fn requeue(context: &mut Context) {
+    let Some(cpu_id) = context.sched_queue else {
+        return;
+    };
+    let (Some(context_lock), Some(percpu)) = (context.self_ref.upgrade(), percpu::get(cpu_id))
+    else {
+        return;
+    };
+    // Contexts throttled by their CPU quota are marked as queued, but are not in the run queue.
+    if percpu.switch_internals.run_queue.remove(&context_lock) {
+        context.sched_queue = None;
+        enqueue(context);
+    }
+}
+
+/// Like [`enqueue`], but real-time contexts that were preempted before using up their quantum are
+/// put at the front of their priority instead, so that they keep their turn.
+fn enqueue_at(context: &mut Context, at_front: bool) {
+    if context.running || context.sched_queue.is_some() || !context.status.is_runnable() {
+        return;
+    }
+    let Some(context_lock) = context.self_ref.upgrade() else {
+        return;
+    };
+    let cpu_id = select_cpu(context);
+    let Some(percpu) = percpu::get(cpu_id).or_else(|| percpu::get(crate::cpu_id())) else {
+        // Not even the current CPU is registered yet, which only happens during early boot.
+        return;
+    };
+
+    if !at_front {
+        context.sched_slice_used = 0;
+    }
+    // Real-time contexts preempt right away rather than at the end of the time slice, unless this
+    // is the current context being queued again while switching away from it.
+    let preempts = percpu.cpu_id == crate::cpu_id()
+        && context.rt_priority().is_some()
+        && !super::is_current(&context_lock);
+    context.sched_queue = Some(percpu.cpu_id);
+    percpu
+        .switch_internals
+        .run_queue
+        .push(context, context_lock, at_front);
+
+    // The context that is running now may have to be preempted, which requires the tick. Other
+    // CPUs restart their tick when receiving the wakeup IPI.
+    if preempts {
+        // The switch IPI preempts as soon as interrupts are enabled, where available, and the
+        // flag otherwise makes the next tick or syscall return switch.
+        percpu.switch_internals.need_resched.set(true);
+        ipi_single(IpiKind::Switch, percpu.cpu_id);
+    }
+    if percpu.cpu_id == crate::cpu_id() {
+        restart_tick();
     }
 }
 
@@ -64,17 +432,127 @@ struct SwitchResultInner {
     _next_guard: ArcRwSpinlockWriteGuard<Context>,
 }
 
+/// Read the default time slices from the `SCHED_SLICE_US` and `SCHED_MIN_SLICE_US` boot
+/// environment variables, if set. Both are left at their defaults if the minimum would exceed the
+/// time slice.
+pub fn init_time_slice() {
+    let [slice, min_slice] = [
+        ("SCHED_SLICE_US", DEFAULT_TIME_SLICE),
+        ("SCHED_MIN_SLICE_US", DEFAULT_MIN_TIME_SLICE),
+    ]
+    .map(|(name, default)| {
+        let Some(value) = crate::init_env_var(name) else {
+            return default;
+        };
+        match value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|&micros| micros > 0)
+            .and_then(|micros| micros.checked_mul(1000))
+        {
+            Some(nanos) => nanos,
+            None => {
+                log::warn!("Invalid {}={:?}, using default", name, value);
+                default
+            }
+        }
+    });
+    if min_slice > slice {
+        log::warn!("SCHED_MIN_SLICE_US exceeds SCHED_SLICE_US, using defaults for both");
+        return;
+    }
+    TIME_SLICE.store(slice, Ordering::Relaxed);
+    MIN_TIME_SLICE.store(min_slice, Ordering::Relaxed);
+}
+
+/// Switch if a real-time context that may preempt the current one has been queued on this CPU.
+/// Called when returning from syscalls, where no locks are held.
+pub fn resched_if_needed() {
+    if PercpuBlock::current().switch_internals.need_resched.get() {
+        let _ = switch();
+    }
+}
+
 pub fn tick() {
-    let ticks_cell = &PercpuBlock::current().switch_internals.pit_ticks;
+    let switch_internals = &PercpuBlock::current().switch_internals;
+    let now = crate::time::monotonic();
 
-    let new_ticks = ticks_cell.get() + 1;
-    ticks_cell.set(new_ticks);
+    super::load::tick(now);
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if new_ticks >= 3 {
+    let elapsed = now.saturating_sub(switch_internals.slice_start.get());
+    if elapsed >= switch_internals.time_slice() || switch_internals.need_resched.get() {
         switch();
         crate::context::signal::signal_handler();
     }
+
+    // With a single runnable context, there is nothing to preempt it for until the next
+    // deadline, or until another context is queued.
+    if switch_internals.run_queue.len() == 0 {
+        stop_tick(false);
+    }
+}
+
+/// Stop the periodic tick of this CPU, and program its timer for the next deadline instead, as
+/// long as there are no queued contexts that would need to preempt the current one. `idle` is
+/// set when called from the idle context right before halting.
+///
+/// Must be called with interrupts disabled.
+pub fn stop_tick(idle: bool) {
+    let percpu = PercpuBlock::current();
+    let switch_internals = &percpu.switch_internals;
+
+    switch_internals.idle.store(idle, Ordering::Relaxed);
+
+    if switch_internals.run_queue.len() > 0 {
+        return;
+    }
+    if !idle && quota::is_active() {
+        // The running context may exceed the CPU quota of its group.
+        return;
+    }
+    if idle {
+        // Keep ticking in order to steal work, if other CPUs have any.
+        let other_work = (0..crate::cpu_count())
+            .map(LogicalCpuId::new)
+            .filter(|&id| id != percpu.cpu_id)
+            .filter_map(percpu::get)
+            .any(|block| block.switch_internals.run_queue.len() > 0);
+        if other_work {
+            return;
+        }
+    }
+
+    let deadline = switch_internals.next_deadline();
+    if !crate::arch::time::stop_tick(deadline) {
+        return;
+    }
+    switch_internals.next_deadline.store(
+        deadline.map_or(u64::MAX, |deadline| {
+            u64::try_from(deadline).unwrap_or(u64::MAX)
+        }),
+        Ordering::Relaxed,
+    );
+    switch_internals.tick_stopped.store(true, Ordering::SeqCst);
+}
+
+/// Restart the periodic tick of this CPU, if it was stopped. Unlike `stop_tick`, this is also
+/// called with interrupts enabled, when queueing a context or registering a timeout, so they are
+/// disabled while the timer is reprogrammed, as its interrupt handler reprograms it as well.
+pub fn restart_tick() {
+    let were_enabled = interrupt::are_enabled();
+    unsafe { interrupt::disable() };
+
+    let switch_internals = &PercpuBlock::current().switch_internals;
+
+    switch_internals.idle.store(false, Ordering::Relaxed);
+    if switch_internals.tick_stopped.swap(false, Ordering::SeqCst) {
+        crate::arch::time::restart_tick();
+    }
+
+    if were_enabled {
+        unsafe { interrupt::enable_and_nop() };
+    }
 }
 
 pub unsafe extern "C" fn switch_finish_hook() {
@@ -84,7 +562,6 @@ pub unsafe extern "C" fn switch_finish_hook() {
         // TODO: unreachable_unchecked()?
         crate::arch::stop::emergency_reset();
     }
-    arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
     crate::percpu::switch_arch_hook();
 }
 
@@ -94,94 +571,218 @@ pub enum SwitchResult {
     AllContextsIdle,
 }
 
+/// Pop the next context to run from this CPU's run queue, or steal one from the busiest other
+/// CPU if the local queue is empty.
+///
+/// Unless `prev` is [`PrevClass::None`], the previous context can continue running, and only
+/// contexts that should preempt it are picked.
+///
+/// Contexts whose process group or session has used up its CPU quota are set aside until the
+/// next period.
+fn pick_next(
+    percpu: &PercpuBlock,
+    prev_context_lock: &ContextLock,
+    prev: PrevClass,
+    rt_allowed: bool,
+    now: u128,
+) -> Option<ArcRwSpinlockWriteGuard<Context>> {
+    let cpu_id = percpu.cpu_id;
+    let run_queue = &percpu.switch_internals.run_queue;
+
+    while let Some(next_context_lock) = run_queue.pop(prev, rt_allowed) {
+        if Arc::ptr_eq(&next_context_lock, prev_context_lock) {
+            // Already locked, and will be queued again if still runnable.
+            continue;
+        }
+        let mut next_context_guard = next_context_lock.write_arc();
+        next_context_guard.sched_queue = None;
+
+        if next_context_guard.running || !next_context_guard.status.is_runnable() {
+            // Queued again once unblocked
+            continue;
+        }
+        if !next_context_guard.sched_affinity.contains(cpu_id) {
+            // The affinity was changed while the context was queued.
+            enqueue(&mut next_context_guard);
+            continue;
+        }
+        if let Some(until) = quota::throttled_until(&next_context_guard, now) {
+            percpu
+                .switch_internals
+                .add_throttled(cpu_id, until, &mut next_context_guard);
+            continue;
+        }
+        return Some(next_context_guard);
+    }
+
+    if !matches!(prev, PrevClass::None) {
+        // Not idle, so leave other CPUs' work alone.
+        return None;
+    }
+
+    let victim = (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&id| id != cpu_id)
+        .filter_map(percpu::get)
+        .filter(|block| block.switch_internals.run_queue.len() > 0)
+        .max_by_key(|block| block.switch_internals.run_queue.len())?;
+
+    let mut next_context_guard =
+        victim
+            .switch_internals
+            .run_queue
+            .steal(cpu_id, run_queue.min_vruntime(), rt_allowed)?;
+    if let Some(until) = quota::throttled_until(&next_context_guard, now) {
+        percpu
+            .switch_internals
+            .add_throttled(cpu_id, until, &mut next_context_guard);
+        return None;
+    }
+    Some(next_context_guard)
+}
+
 /// Switch to the next context, picked by the scheduler.
 ///
 /// This is not memory-unsafe to call, but do NOT call this while holding locks!
 pub fn switch() -> SwitchResult {
     let percpu = PercpuBlock::current();
 
-    //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
-    percpu.switch_internals.pit_ticks.set(0);
+    // The next context may need to be preempted, or sleep until a different deadline.
+    restart_tick();
+    percpu.switch_internals.need_resched.set(false);
 
-    // Set the global lock to avoid the unsafe operations below from causing issues
-    // TODO: Better memory orderings?
-    while arch::CONTEXT_SWITCH_LOCK
-        .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
-        .is_err()
-    {
-        interrupt::pause();
-        percpu.maybe_handle_tlb_shootdown();
-    }
+    // No global lock is needed, as the context locks held until `switch_finish_hook` keep both
+    // contexts from being switched to by other CPUs in the meantime. Only contexts that are not
+    // running are queued, and the previous context is only queued again once the next one has
+    // been locked, so a CPU waiting for a context lock here waits for a switch that never blocks.
 
     let cpu_id = crate::cpu_id();
     let switch_time = crate::time::monotonic();
 
+    // Start a new time slice, whether for the next context or the previous one continuing
+    percpu.switch_internals.slice_start.set(switch_time);
+
+    // Must happen before the previous context is locked, as it may have a stale sleeper entry.
+    percpu.switch_internals.wake_sleepers(switch_time);
+    percpu.switch_internals.release_throttled(switch_time);
+
+    let idle_context = percpu.switch_internals.idle_context();
+    let prev_is_idle = crate::context::is_current(&idle_context);
+
     let mut switch_context_opt = None;
     {
-        let contexts = contexts();
-
         // Lock previous context
         let prev_context_lock = crate::context::current();
-        let prev_context_guard = prev_context_lock.write_arc();
-
-        let idle_context = percpu.switch_internals.idle_context();
-        let mut skip_idle = true;
-
-        // Locate next context
-        for next_context_lock in contexts
-            // Include all contexts with IDs greater than the current...
-            .range((
-                Bound::Excluded(ContextRef(Arc::clone(&prev_context_lock))),
-                Bound::Unbounded,
-            ))
-            .chain(
-                contexts
-                    // ... and all contexts with IDs less than the current...
-                    .range((
-                        Bound::Unbounded,
-                        Bound::Excluded(ContextRef(Arc::clone(&prev_context_lock))),
-                    )),
-            )
-            .filter_map(|r| r.upgrade())
-            .chain(Some(Arc::clone(&idle_context)))
-        // ... but not the current context, which is already locked
-        {
-            if Arc::ptr_eq(&next_context_lock, &idle_context) && skip_idle {
-                // Skip idle process the first time it shows up
-                skip_idle = false;
-                continue;
-            }
+        let mut prev_context_guard = prev_context_lock.write_arc();
 
-            // Lock next context
-            let mut next_context_guard = next_context_lock.write_arc();
+        // Update CPU time
+        let delta = update_times(&mut prev_context_guard, switch_time);
+        let rt_allowed = percpu.switch_internals.charge_rt(
+            switch_time,
+            if prev_context_guard.rt_priority().is_some() {
+                delta
+            } else {
+                0
+            },
+        );
 
-            // Update state of next context and check if runnable
-            if let UpdateResult::CanSwitch =
-                unsafe { update_runnable(&mut *next_context_guard, cpu_id) }
-            {
-                // Store locks for previous and next context
-                switch_context_opt = Some((prev_context_guard, next_context_guard));
-                break;
+        // Whether the process group or session of the previous context is out of CPU quota
+        let prev_throttled_until = if prev_is_idle {
+            None
+        } else {
+            quota::charge(&prev_context_guard, delta, switch_time)
+        };
+
+        let prev_can_continue = !prev_is_idle
+            && prev_context_guard.status.is_runnable()
+            && prev_context_guard.sched_affinity.contains(cpu_id)
+            && prev_throttled_until.is_none();
+        let prev_class = if !prev_can_continue {
+            PrevClass::None
+        } else if let Some(priority) = prev_context_guard.rt_priority() {
+            if rt_allowed {
+                PrevClass::RealTime {
+                    priority,
+                    quantum_expired: rr_quantum_expired(&prev_context_guard),
+                }
             } else {
-                continue;
+                // Throttled, so let anything else run, if there is anything.
+                PrevClass::None
+            }
+        } else {
+            PrevClass::Normal {
+                vruntime: prev_context_guard.sched_vruntime,
             }
+        };
+
+        if let Some(next_context_guard) = pick_next(
+            percpu,
+            &prev_context_lock,
+            prev_class,
+            rt_allowed,
+            switch_time,
+        ) {
+            switch_context_opt =
+                Some((prev_context_guard, next_context_guard, prev_throttled_until));
+        } else if prev_is_idle || prev_can_continue {
+            // Nothing else should run, so keep running the previous context.
+        } else {
+            let next_context_guard = idle_context.write_arc();
+            switch_context_opt =
+                Some((prev_context_guard, next_context_guard, prev_throttled_until));
         }
     };
 
     // Switch process states, TSS stack pointer, and store new context ID
-    if let Some((mut prev_context_guard, mut next_context_guard)) = switch_context_opt {
+    if let Some((mut prev_context_guard, mut next_context_guard, prev_throttled_until)) =
+        switch_context_opt
+    {
         // TODO: Update timestamps in switch_to
 
-        // Set old context as not running and update CPU time
-        let prev_context = &mut *prev_context_guard;
-        prev_context.running = false;
-        prev_context.cpu_time += switch_time.saturating_sub(prev_context.switch_time);
+        // Set old context as not running
+        prev_context_guard.running = false;
+        let prev_runnable = prev_context_guard.status.is_runnable();
+        prev_context_guard
+            .sched_stats
+            .switched_out(prev_runnable, switch_time);
+
+        // Keep the previous context schedulable, unless it is the idle context, which is never
+        // queued.
+        if !prev_is_idle {
+            if prev_context_guard.status.is_soft_blocked()
+                && let Some(wake) = prev_context_guard.wake
+            {
+                percpu
+                    .switch_internals
+                    .add_sleeper(wake, ArcRwSpinlockWriteGuard::rwlock(&prev_context_guard));
+            }
+            if let Some(until) = prev_throttled_until
+                && prev_context_guard.status.is_runnable()
+            {
+                percpu
+                    .switch_internals
+                    .add_throttled(cpu_id, until, &mut prev_context_guard);
+            } else {
+                let at_front = prev_context_guard.rt_priority().is_some()
+                    && !rr_quantum_expired(&prev_context_guard);
+                enqueue_at(&mut prev_context_guard, at_front);
+            }
+        }
+
+        percpu.switch_internals.cpu_stats.switched(
+            switch_time,
+            Arc::ptr_eq(
+                ArcRwSpinlockWriteGuard::rwlock(&next_context_guard),
+                &idle_context,
+            ),
+        );
 
         // Set new context as running and set switch time
         let next_context = &mut *next_context_guard;
         next_context.running = true;
         next_context.cpu_id = Some(cpu_id);
         next_context.switch_time = switch_time;
+        next_context.sched_stats.switched_in(switch_time);
 
         let percpu = PercpuBlock::current();
         unsafe {
@@ -243,9 +844,6 @@ pub fn switch() -> SwitchResult {
 
         SwitchResult::Switched
     } else {
-        // No target was found, unset global lock and return
-        arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
-
         SwitchResult::AllContextsIdle
     }
 }
@@ -253,14 +851,49 @@ pub fn switch() -> SwitchResult {
 #[derive(Default)]
 pub struct ContextSwitchPercpu {
     switch_result: Cell<Option<SwitchResultInner>>,
-    pit_ticks: Cell<usize>,
+    slice_start: Cell<u128>,
+
+    /// Time slice and minimum time slice of this CPU in nanoseconds, overriding [`TIME_SLICE`]
+    /// and [`MIN_TIME_SLICE`] unless 0.
+    pub time_slice: AtomicU64,
+    pub min_time_slice: AtomicU64,
+
+    /// Time spent busy, idle and handling interrupts
+    pub cpu_stats: CpuStats,
+
+    // Whether the periodic tick has been stopped, the time at which it must fire again, and
+    // whether this CPU is halted in its idle context.
+    tick_stopped: AtomicBool,
+    next_deadline: AtomicU64,
+    idle: AtomicBool,
 
     current_ctxt: RefCell<Option<Arc<RwSpinlock<Context>>>>,
 
     // The idle process
     idle_ctxt: RefCell<Option<Arc<RwSpinlock<Context>>>>,
 
+    /// Runnable contexts waiting for this CPU. Other CPUs push to it when waking contexts, and
+    /// steal from it when idle.
+    pub run_queue: RunQueue,
+
+    // Contexts that blocked on this CPU with a wake time, keyed by that time. Entries may be
+    // stale, if the context was woken up by other means in the meantime.
+    sleepers: RefCell<BTreeMap<(u128, usize), Weak<RwSpinlock<Context>>>>,
+
+    // Runnable contexts whose process group or session is out of CPU quota, keyed by the end of
+    // the quota period. These are marked as queued on this CPU, so that they are not queued
+    // elsewhere in the meantime.
+    throttled: RefCell<BTreeMap<(u128, usize), Weak<RwSpinlock<Context>>>>,
+
+    // Start of the current real-time throttling period, and real-time CPU time used within it.
+    rt_period_start: Cell<u128>,
+    rt_used: Cell<u128>,
+
     pub(crate) being_sigkilled: Cell<bool>,
+
+    /// Whether a real-time context has been queued on this CPU since the last switch, which may
+    /// have to preempt the current context before its time slice has elapsed.
+    need_resched: Cell<bool>,
 }
 impl ContextSwitchPercpu {
     pub fn with_context<T>(&self, f: impl FnOnce(&Arc<RwSpinlock<Context>>) -> T) -> T {
@@ -284,4 +917,125 @@ impl ContextSwitchPercpu {
                 .expect("no idle context present"),
         )
     }
+    /// The configured time slice and minimum time slice of this CPU, in nanoseconds.
+    pub fn time_slice_config(&self) -> (u64, u64) {
+        let get = |local: &AtomicU64, global: &AtomicU64| match local.load(Ordering::Relaxed) {
+            0 => global.load(Ordering::Relaxed),
+            slice => slice,
+        };
+        (
+            get(&self.time_slice, &TIME_SLICE),
+            get(&self.min_time_slice, &MIN_TIME_SLICE),
+        )
+    }
+    /// The time slice of the current context, shortened according to the number of contexts
+    /// waiting for this CPU.
+    fn time_slice(&self) -> u128 {
+        let (slice, min_slice) = self.time_slice_config();
+        let runnable = self.run_queue.len() as u64 + 1;
+        u128::from((slice / runnable).max(min_slice))
+    }
+    /// Whether this CPU is in its idle context, waiting for something to become runnable.
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Relaxed)
+    }
+    fn claim_idle(&self) -> bool {
+        self.idle.swap(false, Ordering::Relaxed)
+    }
+    /// Whether this CPU should receive a tick forwarded from another CPU at time `now`, which is
+    /// the case unless its tick is stopped and the next deadline has not yet passed.
+    pub fn needs_tick(&self, now: u128) -> bool {
+        !self.tick_stopped.load(Ordering::SeqCst)
+            || u128::from(self.next_deadline.load(Ordering::Relaxed)) <= now
+    }
+    /// The earliest time at which this CPU needs to wake up a sleeping context or trigger a
+    /// timeout.
+    fn next_deadline(&self) -> Option<u128> {
+        let next_wake = self
+            .sleepers
+            .borrow()
+            .first_key_value()
+            .map(|(&(wake, _), _)| wake);
+        let next_release = self
+            .throttled
+            .borrow()
+            .first_key_value()
+            .map(|(&(until, _), _)| until);
+        [next_wake, next_release, super::timeout::next_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+    fn add_sleeper(&self, wake: u128, context_lock: &Arc<RwSpinlock<Context>>) {
+        self.sleepers.borrow_mut().insert(
+            (wake, Arc::as_ptr(context_lock) as usize),
+            Arc::downgrade(context_lock),
+        );
+    }
+    /// Charge `rt_time` nanoseconds of real-time CPU time, and return whether this CPU may still
+    /// run real-time contexts in the current period.
+    fn charge_rt(&self, now: u128, rt_time: u128) -> bool {
+        if now.saturating_sub(self.rt_period_start.get()) >= RT_PERIOD {
+            self.rt_period_start.set(now);
+            self.rt_used.set(0);
+        }
+        self.rt_used.set(self.rt_used.get() + rt_time);
+        self.rt_used.get() < RT_RUNTIME
+    }
+    fn add_throttled(
+        &self,
+        cpu_id: LogicalCpuId,
+        until: u128,
+        context_guard: &mut ArcRwSpinlockWriteGuard<Context>,
+    ) {
+        let context_lock = ArcRwSpinlockWriteGuard::rwlock(context_guard);
+        self.throttled.borrow_mut().insert(
+            (until, Arc::as_ptr(context_lock) as usize),
+            Arc::downgrade(context_lock),
+        );
+        context_guard.sched_queue = Some(cpu_id);
+    }
+    /// Queue all contexts on this CPU that were throttled until a quota period that has ended.
+    fn release_throttled(&self, now: u128) {
+        loop {
+            let context_lock = {
+                let mut throttled = self.throttled.borrow_mut();
+                match throttled.first_key_value() {
+                    Some((&(until, _), _)) if until <= now => {
+                        throttled.pop_first().map(|(_, context)| context)
+                    }
+                    _ => break,
+                }
+            };
+            let Some(context_lock) = context_lock.and_then(|c| c.upgrade()) else {
+                continue;
+            };
+            let mut context = context_lock.write();
+            context.sched_queue = None;
+            enqueue(&mut context);
+        }
+    }
+    /// Wake up all contexts on this CPU whose wake time has passed.
+    fn wake_sleepers(&self, now: u128) {
+        loop {
+            let context_lock = {
+                let mut sleepers = self.sleepers.borrow_mut();
+                match sleepers.first_key_value() {
+                    Some((&(wake, _), _)) if wake <= now => {
+                        sleepers.pop_first().map(|(_, context)| context)
+                    }
+                    _ => break,
+                }
+            };
+            let Some(context_lock) = context_lock.and_then(|c| c.upgrade()) else {
+                continue;
+            };
+            let mut context = context_lock.write();
+
+            if context.status.is_soft_blocked() && context.wake.is_some_and(|wake| wake <= now) {
+                context.wake = None;
+                context.unblock();
+            }
+        }
+    }
 }
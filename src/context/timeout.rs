@@ -41,6 +41,25 @@ pub fn register(scheme_id: SchemeId, event_id: usize, clock: usize, time: TimeSp
         clock,
         time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128),
     });
+    drop(registry);
+
+    // This CPU's timer may have been programmed for a later deadline.
+    crate::context::switch::restart_tick();
+}
+
+/// The earliest time on the monotonic clock at which a timeout needs to be triggered, if any
+pub fn next_deadline() -> Option<u128> {
+    let start = *time::START.lock();
+
+    registry()
+        .iter()
+        .map(|timeout| match timeout.clock {
+            CLOCK_MONOTONIC => timeout.time,
+            CLOCK_REALTIME => timeout.time.saturating_sub(start),
+            // Triggered right away
+            _ => 0,
+        })
+        .min()
 }
 
 pub fn trigger() {
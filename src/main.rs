@@ -136,6 +136,9 @@ mod percpu;
 /// Process tracing
 mod ptrace;
 
+/// Kernel entropy
+mod random;
+
 /// Performance profiling of the kernel
 #[cfg(feature = "profiling")]
 pub mod profiling;
@@ -177,6 +180,15 @@ fn init_env() -> &'static [u8] {
     crate::BOOTSTRAP.get().expect("BOOTSTRAP was not set").env
 }
 
+/// Get the value of a `NAME=value` variable in the boot environment
+fn init_env_var(name: &str) -> Option<&'static str> {
+    core::str::from_utf8(init_env())
+        .ok()?
+        .lines()
+        .find_map(|line| line.split_once('=').filter(|(n, _)| *n == name))
+        .map(|(_, value)| value)
+}
+
 extern "C" fn userspace_init() {
     let bootstrap = crate::BOOTSTRAP.get().expect("BOOTSTRAP was not set");
     unsafe { crate::syscall::process::usermode_bootstrap(bootstrap) }
@@ -206,6 +218,11 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
 
     BOOTSTRAP.call_once(|| bootstrap);
 
+    context::switch::init_time_slice();
+    memory::hugepool::init();
+    memory::ksm::init();
+    memory::pressure::init();
+
     #[cfg(feature = "profiling")]
     profiling::ready_for_profiling();
 
@@ -220,6 +237,8 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
         egid: 0,
         rns: SchemeNamespace::new(0),
         ens: SchemeNamespace::new(0),
+        oom_score_adj: 0,
+        aslr: true,
     })
     .expect("failed to create init process");
 
@@ -227,7 +246,7 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
         Ok(context_lock) => {
             {
                 let mut context = context_lock.write();
-                context.status = context::Status::Runnable;
+                context.set_runnable();
                 context.name = "bootstrap".into();
 
                 let mut process = context.process.write();
@@ -280,6 +299,9 @@ fn run_userspace() -> ! {
                     interrupt::enable_and_nop();
                 }
                 SwitchResult::AllContextsIdle => {
+                    // Only wake up for the next deadline, instead of on every tick.
+                    context::switch::stop_tick(true);
+
                     // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
                     interrupt::enable_and_halt();
                 }
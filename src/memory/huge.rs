@@ -0,0 +1,343 @@
+//! # Transparent huge pages
+//! Private anonymous memory is backed by huge pages when a page fault hits a suitably aligned,
+//! entirely unmapped region of a grant, so that fewer TLB entries are needed to cover it.
+//!
+//! A huge page is a single page table entry at a level above the last, mapping a power-of-two
+//! sized frame allocated with [`allocate_p2frame`](super::allocate_p2frame), and every frame in it
+//! has a refcount of one, unless the huge page has been shared copy-on-write as a whole on fork.
+//! Huge pages are only created on write faults in writable `Provider::Allocated` grants that are
+//! neither file-backed nor physically contiguous, and anything that operates on individual pages,
+//! such as sharing them, copying them on write, swapping them out or changing the flags or
+//! mappings of part of a huge page, first splits it into smaller pages mapping the same frames.
+//!
+//! Only x86_64 is currently supported. The 1 GiB page size is only used if the frame allocator
+//! supports allocations of that order.
+
+use rmm::{
+    FrameAllocator, PageEntry, PageFlags, PageFlush, PageTable, PhysicalAddress, VirtualAddress,
+};
+
+use crate::{
+    context::memory::PageSpan,
+    paging::{entry::EntryFlags, Page, PageMapper, RmmA, RmmArch, PAGE_SIZE},
+};
+
+use super::{Frame, MAX_ORDER};
+
+/// Orders of the supported huge page sizes, largest first.
+#[cfg(target_arch = "x86_64")]
+const ORDERS: &[u32] = &[18, 9];
+#[cfg(not(target_arch = "x86_64"))]
+const ORDERS: &[u32] = &[];
+
+const HUGE_PAGE: usize = EntryFlags::HUGE_PAGE.bits();
+
+const TABLE_FLAGS: usize =
+    RmmA::ENTRY_FLAG_DEFAULT_TABLE | RmmA::ENTRY_FLAG_READWRITE | RmmA::ENTRY_FLAG_TABLE_USER;
+
+/// The usable huge page orders, largest first.
+pub fn orders() -> impl Iterator<Item = u32> {
+    ORDERS.iter().copied().filter(|&order| order <= MAX_ORDER)
+}
+
+/// Whether huge pages of the given order can be mapped at all, even if they cannot be allocated
+/// by the frame allocator.
+pub fn supports(order: u32) -> bool {
+    ORDERS.contains(&order)
+}
+
+/// The alignment, in pages, with which a private mapping of `page_count` pages should be placed,
+/// in order to be backed by the largest possible huge pages.
+pub fn alignment(page_count: usize) -> usize {
+    ORDERS
+        .iter()
+        .map(|order| 1 << order)
+        .find(|&size| size <= page_count)
+        .unwrap_or(1)
+}
+
+/// The huge page of the given order that contains `page`.
+pub fn aligned_span(page: Page, order: u32) -> PageSpan {
+    let size = PAGE_SIZE << order;
+    PageSpan::new(
+        Page::containing_address(VirtualAddress::new(
+            page.start_address().data() & !(size - 1),
+        )),
+        1 << order,
+    )
+}
+
+/// Whether `span` contains a whole huge page of any usable size.
+pub fn fits(span: PageSpan) -> bool {
+    orders().any(|order| {
+        let size = PAGE_SIZE << order;
+        let first = span.base.start_address().data().next_multiple_of(size);
+        first + size <= span.end().start_address().data()
+    })
+}
+
+/// A huge page mapped in an address space
+#[derive(Clone, Copy)]
+pub struct HugeMapping {
+    pub base: Page,
+    pub frame: Frame,
+    /// Flags of the entry, without the huge page flag
+    pub flags: PageFlags<RmmA>,
+    pub order: u32,
+}
+impl HugeMapping {
+    pub fn page_count(&self) -> usize {
+        1 << self.order
+    }
+    pub fn span(&self) -> PageSpan {
+        PageSpan::new(self.base, self.page_count())
+    }
+}
+
+fn level_of(order: u32) -> usize {
+    order as usize / RmmA::PAGE_ENTRY_SHIFT
+}
+
+fn is_huge(entry: PageEntry<RmmA>) -> bool {
+    HUGE_PAGE != 0 && entry.present() && entry.data() & HUGE_PAGE != 0
+}
+
+/// The table at `level` that `page` is mapped through, if it exists and no huge page above that
+/// level maps `page`.
+unsafe fn table_at(mapper: &PageMapper, page: Page, level: usize) -> Option<PageTable<RmmA>> {
+    let virt = page.start_address();
+    let mut table = mapper.table();
+    while table.level() > level {
+        let i = table.index_of(virt)?;
+        if is_huge(table.entry(i)?) {
+            return None;
+        }
+        table = table.next(i)?;
+    }
+    Some(table)
+}
+
+/// The huge page containing `page`, if any.
+pub fn lookup(mapper: &PageMapper, page: Page) -> Option<HugeMapping> {
+    let virt = page.start_address();
+    let mut table = mapper.table();
+    while table.level() > 0 {
+        let i = table.index_of(virt)?;
+        let entry = unsafe { table.entry(i)? };
+        if is_huge(entry) {
+            let order = (table.level() * RmmA::PAGE_ENTRY_SHIFT) as u32;
+            return Some(HugeMapping {
+                base: aligned_span(page, order).base,
+                frame: Frame::containing(entry.address().ok()?),
+                flags: entry.flags().custom_flag(HUGE_PAGE, false),
+                order,
+            });
+        }
+        table = unsafe { table.next(i)? };
+    }
+    None
+}
+
+/// Like [`PageMapper::translate`], but also translates addresses within huge pages.
+pub fn translate(
+    mapper: &PageMapper,
+    virt: VirtualAddress,
+) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+    match lookup(mapper, Page::containing_address(virt)) {
+        Some(mapping) => Some((
+            mapping
+                .frame
+                .base()
+                .add(virt.data() - mapping.base.start_address().data()),
+            mapping.flags,
+        )),
+        None => mapper.translate(virt),
+    }
+}
+
+/// Whether nothing at all is mapped in the huge page of `order` starting at `page`.
+pub fn is_unmapped(mapper: &PageMapper, page: Page, order: u32) -> bool {
+    let virt = page.start_address();
+    let level = level_of(order);
+    let mut table = mapper.table();
+    loop {
+        let Some(i) = table.index_of(virt) else {
+            return false;
+        };
+        let Some(entry) = (unsafe { table.entry(i) }) else {
+            return false;
+        };
+        if !entry.present() {
+            return true;
+        }
+        if table.level() == level || is_huge(entry) {
+            return false;
+        }
+        let Some(next) = (unsafe { table.next(i) }) else {
+            return false;
+        };
+        table = next;
+    }
+}
+
+/// Call `f` for every mapping in `span`, with its first page within `span`, the physical address
+/// and flags that page is mapped to, and how many of its pages are within `span`, which is only
+/// more than one for huge pages. Regions without page tables are skipped as a whole.
+pub fn for_each_mapped(
+    mapper: &PageMapper,
+    span: PageSpan,
+    mut f: impl FnMut(Page, PhysicalAddress, PageFlags<RmmA>, usize),
+) {
+    let mut page = span.base;
+    while page < span.end() {
+        let virt = page.start_address();
+        let mut table = mapper.table();
+        // Number of pages from `page` to the end of the region covered by the entry found.
+        let skip = loop {
+            let entry_pages = 1 << (table.level() * RmmA::PAGE_ENTRY_SHIFT);
+            let rest = entry_pages - (virt.data() / PAGE_SIZE) % entry_pages;
+            let Some(i) = table.index_of(virt) else {
+                break rest;
+            };
+            let Some(entry) = (unsafe { table.entry(i) }).filter(|entry| entry.present()) else {
+                break rest;
+            };
+            if table.level() == 0 || is_huge(entry) {
+                if let Ok(base) = entry.address() {
+                    f(
+                        page,
+                        base.add((entry_pages - rest) * PAGE_SIZE),
+                        entry.flags().custom_flag(HUGE_PAGE, false),
+                        rest.min(span.end().offset_from(page)),
+                    );
+                }
+                break rest;
+            }
+            let Some(next) = (unsafe { table.next(i) }) else {
+                break rest;
+            };
+            table = next;
+        };
+        page = page.next_by(skip);
+    }
+}
+
+/// Number of pages in `span` that are mapped, including within huge pages, to frames other than
+/// `except`, which is never part of a huge page. Regions without page tables are skipped as a
+/// whole.
+pub fn count_mapped(mapper: &PageMapper, span: PageSpan, except: Frame) -> usize {
+    let mut count = 0;
+    for_each_mapped(mapper, span, |_, phys, _, page_count| {
+        if page_count > 1 || phys != except.base() {
+            count += page_count;
+        }
+    });
+    count
+}
+
+/// Map `frame`, of the given order, as a huge page at `page`, which must be aligned to that order
+/// and entirely unmapped. Missing page tables are allocated.
+pub unsafe fn map(
+    mapper: &mut PageMapper,
+    page: Page,
+    frame: Frame,
+    flags: PageFlags<RmmA>,
+    order: u32,
+) -> Option<PageFlush<RmmA>> {
+    let virt = page.start_address();
+    let level = level_of(order);
+    let mut table = mapper.table();
+    while table.level() > level {
+        let i = table.index_of(virt)?;
+        let entry = table.entry(i)?;
+        if is_huge(entry) {
+            return None;
+        }
+        if !entry.present() {
+            let phys = mapper.allocator_mut().allocate_one()?;
+            table.set_entry(i, PageEntry::new(phys.data(), TABLE_FLAGS))?;
+        }
+        table = table.next(i)?;
+    }
+    let i = table.index_of(virt)?;
+    if table.entry(i)?.present() {
+        return None;
+    }
+    table.set_entry(
+        i,
+        PageEntry::new(frame.base().data(), flags.data() | HUGE_PAGE),
+    )?;
+    Some(PageFlush::new(virt))
+}
+
+/// Clear the entry for `virt` at `level`, freeing the tables below `table` that become empty.
+/// Returns whether `table` itself is now empty.
+unsafe fn clear_entry(
+    mapper: &mut PageMapper,
+    mut table: PageTable<RmmA>,
+    virt: VirtualAddress,
+    level: usize,
+) -> Option<bool> {
+    let i = table.index_of(virt)?;
+    if table.level() > level {
+        let next = table.next(i)?;
+        let next_phys = next.phys();
+        if !clear_entry(mapper, next, virt, level)? {
+            return Some(false);
+        }
+        mapper.allocator_mut().free_one(next_phys);
+    }
+    table.set_entry(i, PageEntry::new(0, 0))?;
+    Some((0..RmmA::PAGE_ENTRIES).all(|j| table.entry(j).map_or(true, |entry| !entry.present())))
+}
+
+/// Unmap the huge page containing `page`. The caller is responsible for flushing the TLB and
+/// freeing the frame.
+pub unsafe fn unmap(mapper: &mut PageMapper, page: Page) -> Option<HugeMapping> {
+    let mapping = lookup(mapper, page)?;
+    let table = mapper.table();
+    clear_entry(
+        mapper,
+        table,
+        mapping.base.start_address(),
+        level_of(mapping.order),
+    )?;
+    Some(mapping)
+}
+
+/// Change the flags of the huge page containing `page`.
+pub unsafe fn remap(
+    mapper: &mut PageMapper,
+    page: Page,
+    flags: PageFlags<RmmA>,
+) -> Option<(HugeMapping, PageFlush<RmmA>)> {
+    let mapping = lookup(mapper, page)?;
+    let virt = mapping.base.start_address();
+    let mut table = table_at(mapper, mapping.base, level_of(mapping.order))?;
+    let i = table.index_of(virt)?;
+    table.set_entry(
+        i,
+        PageEntry::new(mapping.frame.base().data(), flags.data() | HUGE_PAGE),
+    )?;
+    Some((mapping, PageFlush::new(virt)))
+}
+
+/// Replace a huge page with a table of pages of the next smaller size, mapping the same frames
+/// with the same flags. The caller is responsible for flushing the TLB.
+pub unsafe fn split(mapper: &mut PageMapper, mapping: &HugeMapping) -> Option<()> {
+    let level = level_of(mapping.order);
+    let mut parent = table_at(mapper, mapping.base, level)?;
+    let i = parent.index_of(mapping.base.start_address())?;
+
+    let phys = mapper.allocator_mut().allocate_one()?;
+    let mut table = PageTable::new(parent.entry_base(i)?, phys, level - 1);
+
+    let sub_order = mapping.order - RmmA::PAGE_ENTRY_SHIFT as u32;
+    let sub_flags = mapping.flags.data() | if sub_order > 0 { HUGE_PAGE } else { 0 };
+    for j in 0..RmmA::PAGE_ENTRIES {
+        let frame = mapping.frame.next_by(j << sub_order);
+        table.set_entry(j, PageEntry::new(frame.base().data(), sub_flags))?;
+    }
+    parent.set_entry(i, PageEntry::new(phys.data(), TABLE_FLAGS))?;
+    Some(())
+}
@@ -0,0 +1,164 @@
+//! # Huge page pool
+//! Physically contiguous huge pages reserved at boot, as many as given by the `HUGEPAGES_2M` and
+//! `HUGEPAGES_1G` boot environment variables, which can be mapped through `memory:hugepage` and
+//! `memory:hugepage@?1g` (or `memory:hugepage@wb?1g`). Unlike transparent huge pages, these
+//! mappings are always backed by huge pages, and fail with ENOMEM once the pool is exhausted rather
+//! than falling back to smaller pages.
+//!
+//! All frames of a reserved huge page have a refcount of one while it is in the pool. When a huge
+//! page is unmapped, it is returned here instead of to the frame allocator, unless some of its
+//! frames are still borrowed by another address space, in which case it is removed from the pool
+//! and its frames are freed individually once no longer used.
+
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+use super::{
+    allocate_p2frame_large, deallocate_frame, get_page_info, huge, Frame, RefCount, RmmA, RmmArch,
+    PAGE_SIZE,
+};
+
+/// A size of huge pages that can be reserved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+impl HugePageSize {
+    const ALL: [Self; 2] = [Self::Size2M, Self::Size1G];
+
+    pub fn order(self) -> u32 {
+        match self {
+            Self::Size2M => 9,
+            Self::Size1G => 18,
+        }
+    }
+    pub fn page_count(self) -> usize {
+        1 << self.order()
+    }
+    fn env_var(self) -> &'static str {
+        match self {
+            Self::Size2M => "HUGEPAGES_2M",
+            Self::Size1G => "HUGEPAGES_1G",
+        }
+    }
+    fn pool(self) -> &'static Mutex<Pool> {
+        &POOLS[self as usize]
+    }
+}
+
+struct Pool {
+    /// All huge pages that belong to the pool, sorted by address
+    reserved: Vec<Frame>,
+    free: Vec<Frame>,
+}
+
+static POOLS: [Mutex<Pool>; 2] = [const {
+    Mutex::new(Pool {
+        reserved: Vec::new(),
+        free: Vec::new(),
+    })
+}; 2];
+
+/// Reserve the huge pages requested in the boot environment. Called once during boot, while
+/// physical memory is not yet fragmented.
+pub fn init() {
+    for size in HugePageSize::ALL {
+        let Some(value) = crate::init_env_var(size.env_var()) else {
+            continue;
+        };
+        let Ok(requested) = value.trim().parse::<usize>() else {
+            log::warn!("Invalid {}={:?}", size.env_var(), value);
+            continue;
+        };
+        if !huge::supports(size.order()) {
+            log::warn!("{:?} pages are not supported on this architecture", size);
+            continue;
+        }
+
+        let mut pool = size.pool().lock();
+        for _ in 0..requested {
+            let Some(frame) = allocate_p2frame_large(size.order()) else {
+                break;
+            };
+            for i in 0..size.page_count() {
+                get_page_info(frame.next_by(i))
+                    .expect("PageInfo must exist for allocated frame")
+                    .refcount
+                    .store(RefCount::One.to_raw(), Ordering::Relaxed);
+            }
+            pool.reserved.push(frame);
+        }
+        pool.reserved.sort_unstable();
+        pool.free = pool.reserved.clone();
+
+        if pool.reserved.len() < requested {
+            log::warn!(
+                "Only reserved {} of {} requested {:?} pages",
+                pool.reserved.len(),
+                requested,
+                size
+            );
+        } else {
+            log::info!("Reserved {} {:?} pages", requested, size);
+        }
+    }
+}
+
+/// Take a zeroed huge page from the pool.
+pub fn alloc(size: HugePageSize) -> Option<Frame> {
+    let frame = size.pool().lock().free.pop()?;
+    unsafe {
+        (RmmA::phys_to_virt(frame.base()).data() as *mut u8)
+            .write_bytes(0, PAGE_SIZE << size.order());
+    }
+    Some(frame)
+}
+
+/// Put back a huge page that was taken from the pool but never mapped.
+pub fn free(frame: Frame, size: HugePageSize) {
+    size.pool().lock().free.push(frame);
+}
+
+/// Return a huge page that has been unmapped to its pool, if it belongs to one. Called instead of
+/// freeing it to the frame allocator, after the TLB has been flushed.
+pub fn release(base: Frame, page_count: usize) -> bool {
+    let Some(size) = HugePageSize::ALL
+        .into_iter()
+        .find(|size| size.page_count() == page_count)
+    else {
+        return false;
+    };
+    let mut pool = size.pool().lock();
+    let Ok(index) = pool.reserved.binary_search(&base) else {
+        return false;
+    };
+
+    let frames = (0..page_count).map(|i| base.next_by(i));
+    if frames
+        .clone()
+        .all(|frame| get_page_info(frame).and_then(|info| info.refcount()) == Some(RefCount::One))
+    {
+        pool.free.push(base);
+        return true;
+    }
+
+    log::warn!(
+        "{:?} page {:?} is still borrowed, removing it from the pool",
+        size,
+        base
+    );
+    pool.reserved.remove(index);
+    drop(pool);
+
+    for frame in frames {
+        let info = get_page_info(frame).expect("pool frames must have PageInfos");
+        if info.remove_ref() == None {
+            unsafe {
+                deallocate_frame(frame);
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,249 @@
+//! # Kernel same-page merging
+//! A kernel thread periodically scans the pages of grants marked with `MADV_MERGEABLE`, and
+//! merges pages with identical contents into a single frame, which is then CoW-shared between
+//! them. Writing to a merged page copies it again through the usual CoW page fault.
+//!
+//! Frames that pages are merged into are "stable": KSM holds a reference to each of them, so that
+//! their refcount never drops to one while they are mapped, and they are thus never written to.
+//! Pages that are not merged yet are remembered by the hash of their contents during each full
+//! scan, and when another page with the same hash is found, the page is turned into a stable
+//! frame and the other one is merged into it, if the contents are still identical. Stable frames
+//! are dropped once KSM holds the only reference.
+//!
+//! So as not to wake up idle CPUs for nothing, the scanner sleeps until a grant is marked with
+//! `MADV_MERGEABLE`, and again once a full scan finds neither mergeable pages nor stable frames.
+//!
+//! The scanner is tuned by root through `sys:ksm`.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{
+    context::{self, memory::AddrSpaceWrapper},
+    paging::{Page, RmmA, RmmArch, VirtualAddress},
+    sync::WaitCondition,
+    time,
+};
+
+use super::{deallocate_frame, get_page_info, Frame, RefCount, PAGE_SIZE};
+
+/// Number of pages to scan every time the scanner wakes up, or 0 to stop scanning.
+static PAGES_TO_SCAN: AtomicUsize = AtomicUsize::new(100);
+/// How long the scanner sleeps between scans, in milliseconds.
+static SLEEP_MS: AtomicUsize = AtomicUsize::new(20);
+
+/// Number of stable frames that pages have been merged into.
+static PAGES_SHARED: AtomicUsize = AtomicUsize::new(0);
+/// Number of pages mapping stable frames, as of the last full scan.
+static PAGES_SHARING: AtomicUsize = AtomicUsize::new(0);
+static FULL_SCANS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the scanner has been woken up since it last went to sleep without a timeout.
+static WOKEN: Mutex<bool> = Mutex::new(false);
+static WAKE: WaitCondition = WaitCondition::new();
+
+pub struct Stats {
+    pub pages_to_scan: usize,
+    pub sleep_ms: usize,
+    pub pages_shared: usize,
+    pub pages_sharing: usize,
+    pub full_scans: usize,
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        pages_to_scan: PAGES_TO_SCAN.load(Ordering::Relaxed),
+        sleep_ms: SLEEP_MS.load(Ordering::Relaxed),
+        pages_shared: PAGES_SHARED.load(Ordering::Relaxed),
+        pages_sharing: PAGES_SHARING.load(Ordering::Relaxed),
+        full_scans: FULL_SCANS.load(Ordering::Relaxed),
+    }
+}
+pub fn set_pages_to_scan(page_count: usize) {
+    PAGES_TO_SCAN.store(page_count, Ordering::Relaxed);
+    wake();
+}
+pub fn set_sleep_ms(ms: usize) {
+    SLEEP_MS.store(ms, Ordering::Relaxed);
+}
+/// Wake up the scanner if it is sleeping without a timeout, as there may be pages to scan.
+pub fn wake() {
+    *WOKEN.lock() = true;
+    WAKE.notify();
+}
+
+fn contents(frame: Frame) -> &'static [u64] {
+    unsafe {
+        core::slice::from_raw_parts(
+            RmmA::phys_to_virt(frame.base()).data() as *const u64,
+            PAGE_SIZE / 8,
+        )
+    }
+}
+
+/// Hash the contents of a frame, which is not cryptographically strong, as pages with the same
+/// hash are always compared before being merged.
+pub fn hash(frame: Frame) -> u64 {
+    // FNV-1a, a word at a time.
+    contents(frame)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, word| {
+            (hash ^ word).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+pub fn same_contents(a: Frame, b: Frame) -> bool {
+    contents(a) == contents(b)
+}
+
+struct Scanner {
+    /// Frames pages have been merged into, by the hash of their contents.
+    stable: BTreeMap<u64, Frame>,
+    /// Pages seen during the current full scan that have not been merged, by the hash of their
+    /// contents.
+    unstable: BTreeMap<u64, (Weak<AddrSpaceWrapper>, Page)>,
+    /// The address space and page to continue scanning from.
+    cursor: (usize, Page),
+    /// Whether any mergeable pages have been found during the current full scan.
+    found: bool,
+    /// Whether the last full scan found neither mergeable pages nor stable frames.
+    idle: bool,
+}
+impl Scanner {
+    fn scan(&mut self, mut budget: usize) {
+        let addr_spaces = context::memory::all_addr_spaces();
+
+        let first = addr_spaces
+            .partition_point(|addr_space| (Arc::as_ptr(addr_space) as usize) < self.cursor.0);
+        for addr_space in &addr_spaces[first..] {
+            let ptr = Arc::as_ptr(addr_space) as usize;
+            let from = if ptr == self.cursor.0 {
+                self.cursor.1
+            } else {
+                Page::containing_address(VirtualAddress::new(0))
+            };
+            let pages = addr_space.mergeable_pages(from, budget);
+            budget -= pages.len();
+            self.found |= !pages.is_empty();
+
+            for &page in &pages {
+                self.scan_page(addr_space, page);
+            }
+            if budget == 0 {
+                let last = *pages.last().expect("budget cannot run out without pages");
+                self.cursor = (ptr, last.next_by(1));
+                return;
+            }
+        }
+        self.finish_full_scan();
+    }
+    fn scan_page(&mut self, addr_space: &Arc<AddrSpaceWrapper>, page: Page) {
+        let Some(hash) = addr_space.ksm_hash(page) else {
+            return;
+        };
+        if let Some(&shared) = self.stable.get(&hash) {
+            addr_space.ksm_merge(page, shared);
+            return;
+        }
+        let Some((other_space, other_page)) = self.unstable.remove(&hash) else {
+            self.unstable
+                .insert(hash, (Arc::downgrade(addr_space), page));
+            return;
+        };
+        if other_space.as_ptr() == Arc::as_ptr(addr_space) && other_page == page {
+            self.unstable.insert(hash, (other_space, page));
+            return;
+        }
+        let Some(other_space) = other_space.upgrade() else {
+            self.unstable
+                .insert(hash, (Arc::downgrade(addr_space), page));
+            return;
+        };
+        // Merge the page found earlier into this one, which becomes stable even if the other page
+        // has changed in the meantime, as there is likely more of the same.
+        let Some(shared) = addr_space.ksm_promote(page, hash) else {
+            return;
+        };
+        self.stable.insert(hash, shared);
+        other_space.ksm_merge(other_page, shared);
+    }
+    fn finish_full_scan(&mut self) {
+        self.cursor = (0, Page::containing_address(VirtualAddress::new(0)));
+        self.unstable.clear();
+
+        let mut pages_sharing = 0;
+        self.stable.retain(|_, frame| {
+            let info = get_page_info(*frame).expect("KSM frames must have a PageInfo");
+            match info.refcount() {
+                Some(RefCount::Cow(count)) => {
+                    pages_sharing += count.get() - 1;
+                    true
+                }
+                // Only KSM refers to the frame.
+                Some(RefCount::One) => {
+                    info.remove_ref();
+                    unsafe {
+                        deallocate_frame(*frame);
+                    }
+                    false
+                }
+                // KSM frames are always CoW, so the frame is forgotten rather than freed, as
+                // whatever else refers to it is not known.
+                refcount @ (Some(RefCount::Shared(_)) | None) => {
+                    log::error!("KSM frame {:?} has refcount {:?}", frame, refcount);
+                    false
+                }
+            }
+        });
+
+        self.idle = !self.found && self.stable.is_empty();
+        self.found = false;
+
+        PAGES_SHARED.store(self.stable.len(), Ordering::Relaxed);
+        PAGES_SHARING.store(pages_sharing, Ordering::Relaxed);
+        FULL_SCANS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+extern "C" fn scanner_thread() {
+    let mut scanner = Scanner {
+        stable: BTreeMap::new(),
+        unstable: BTreeMap::new(),
+        cursor: (0, Page::containing_address(VirtualAddress::new(0))),
+        found: false,
+        idle: true,
+    };
+    loop {
+        let pages_to_scan = PAGES_TO_SCAN.load(Ordering::Relaxed);
+        {
+            let mut woken = WOKEN.lock();
+            if pages_to_scan == 0 || (scanner.idle && !*woken) {
+                WAKE.wait(woken, "ksm");
+                continue;
+            }
+            *woken = false;
+            scanner.idle = false;
+        }
+        scanner.scan(pages_to_scan);
+
+        let sleep_ns = SLEEP_MS.load(Ordering::Relaxed) as u128 * 1_000_000;
+        {
+            let current = context::current();
+            let mut context = current.write();
+            context.wake = Some(time::monotonic() + sleep_ns);
+            context.block("ksm");
+        }
+        context::switch();
+    }
+}
+
+/// Start the scanner thread.
+pub fn init() {
+    if let Err(err) = context::spawn_kernel("ksm", scanner_thread) {
+        log::warn!("Failed to start the KSM scanner: {:?}", err);
+    }
+}
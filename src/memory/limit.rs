@@ -0,0 +1,202 @@
+//! # Memory limits
+//! Root can cap the memory used by a process group or session through `sys:memory_limit`, like the
+//! CPU quotas of `sys:cpu_quota`. Frames allocated for an address space, by page faults or by
+//! physically contiguous mappings, are charged to the limits of the groups of the process that
+//! allocates them. As frames are not uncharged individually when freed, the charge of an address
+//! space is recounted from its resident memory whenever a limit would be exceeded, and given back
+//! entirely when the address space is dropped.
+//!
+//! If a limit would still be exceeded after reclaiming memory of the group, anonymous mappings
+//! fail with ENOMEM, and page faults kill the process of the group with the highest OOM score.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+use crate::context::{self, quota::QuotaGroup};
+
+#[derive(Debug)]
+pub struct MemoryLimit {
+    pub group: QuotaGroup,
+    /// Number of pages the group may use
+    limit: AtomicUsize,
+    /// Number of pages charged to the group
+    usage: AtomicUsize,
+    /// Highest usage since the limit was set
+    max_usage: AtomicUsize,
+    /// Number of times an allocation would have exceeded the limit
+    failures: AtomicUsize,
+}
+impl MemoryLimit {
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+    pub fn max_usage(&self) -> usize {
+        self.max_usage.load(Ordering::Relaxed)
+    }
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+    /// Whether charging `page_count` more pages would exceed the limit, where no pages means
+    /// whether the limit has been reached.
+    fn exceeded_by(&self, page_count: usize) -> bool {
+        self.usage().saturating_add(page_count.max(1)) > self.limit()
+    }
+    fn add(&self, page_count: usize) {
+        let usage = self.usage.fetch_add(page_count, Ordering::Relaxed) + page_count;
+        self.max_usage.fetch_max(usage, Ordering::Relaxed);
+    }
+    fn sub(&self, page_count: usize) {
+        self.usage.fetch_sub(page_count, Ordering::Relaxed);
+    }
+}
+
+/// The limits an address space is charged to, at most one for the process group and one for the
+/// session.
+pub type Limits = ArrayVec<Arc<MemoryLimit>, 2>;
+
+// Number of limits, to skip looking up the process groups of allocating processes when there are
+// none.
+static LIMIT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static LIMITS: RwLock<BTreeMap<QuotaGroup, Arc<MemoryLimit>>> = RwLock::new(BTreeMap::new());
+
+/// Set the memory limit of a group in pages, replacing any previous one, or remove it if `limit`
+/// is `None`. Memory already charged is not affected by a lower limit.
+pub fn set(group: QuotaGroup, limit: Option<usize>) {
+    let mut limits = LIMITS.write();
+
+    match limit {
+        Some(limit) => {
+            limits
+                .entry(group)
+                .or_insert_with(|| {
+                    Arc::new(MemoryLimit {
+                        group,
+                        limit: AtomicUsize::new(limit),
+                        usage: AtomicUsize::new(0),
+                        max_usage: AtomicUsize::new(0),
+                        failures: AtomicUsize::new(0),
+                    })
+                })
+                .limit
+                .store(limit, Ordering::Relaxed);
+        }
+        None => {
+            limits.remove(&group);
+        }
+    }
+    LIMIT_COUNT.store(limits.len(), Ordering::Relaxed);
+}
+
+/// All limits that are set.
+pub fn all() -> Vec<Arc<MemoryLimit>> {
+    LIMITS.read().values().cloned().collect()
+}
+
+/// The limits of the groups the current process belongs to.
+pub fn current() -> Limits {
+    let mut current = Limits::new();
+    if LIMIT_COUNT.load(Ordering::Relaxed) == 0 {
+        return current;
+    }
+    let process = Arc::clone(&context::current().read().process);
+    let (pgid, session_id) = {
+        let process = process.read();
+        (process.pgid, process.session_id)
+    };
+    let limits = LIMITS.read();
+
+    current.extend(
+        [
+            QuotaGroup::ProcessGroup(pgid),
+            QuotaGroup::Session(session_id),
+        ]
+        .iter()
+        .filter_map(|group| limits.get(group))
+        .cloned(),
+    );
+    current
+}
+
+/// The pages charged by an address space, and the limits they are charged to.
+#[derive(Debug, Default)]
+pub struct Charge {
+    limits: Limits,
+    page_count: usize,
+}
+impl Charge {
+    /// Charge `page_count` pages about to be allocated to `limits`, which are those of the
+    /// allocating process, given the number of pages the address space has resident. Returns the
+    /// limit that would be exceeded otherwise.
+    pub fn try_charge(
+        &mut self,
+        limits: Limits,
+        page_count: usize,
+        resident: impl Fn() -> usize,
+    ) -> Result<(), Arc<MemoryLimit>> {
+        if limits.is_empty() && self.limits.is_empty() {
+            return Ok(());
+        }
+        let same_limits = limits.len() == self.limits.len()
+            && limits
+                .iter()
+                .zip(&self.limits)
+                .all(|(a, b)| Arc::ptr_eq(a, b));
+        if !same_limits {
+            // The process has moved to another group, or the limits of its groups have changed,
+            // so all of the address space is charged anew.
+            self.uncharge(self.page_count);
+            self.limits = limits;
+            self.recount(resident());
+        }
+
+        let exceeded = |this: &Self| {
+            this.limits
+                .iter()
+                .find(|limit| limit.exceeded_by(page_count))
+                .cloned()
+        };
+        if exceeded(self).is_some() {
+            // The charge only grows between recounts, so it may be too high.
+            self.recount(resident());
+        }
+        if let Some(limit) = exceeded(self) {
+            limit.failures.fetch_add(1, Ordering::Relaxed);
+            return Err(limit);
+        }
+
+        self.page_count += page_count;
+        for limit in &self.limits {
+            limit.add(page_count);
+        }
+        Ok(())
+    }
+    /// Replace the charge with the number of pages the address space has resident.
+    pub fn recount(&mut self, resident: usize) {
+        self.uncharge(self.page_count);
+        self.page_count = resident;
+        for limit in &self.limits {
+            limit.add(resident);
+        }
+    }
+    /// Give back the charge for `page_count` pages that were charged but not allocated.
+    pub fn uncharge(&mut self, page_count: usize) {
+        let page_count = page_count.min(self.page_count);
+        self.page_count -= page_count;
+        for limit in &self.limits {
+            limit.sub(page_count);
+        }
+    }
+    pub fn is_charged_to(&self, limit: &Arc<MemoryLimit>) -> bool {
+        self.limits.iter().any(|other| Arc::ptr_eq(other, limit))
+    }
+}
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.uncharge(self.page_count);
+    }
+}
@@ -0,0 +1,66 @@
+//! # Locked memory accounting
+//! Memory locked with mlock is charged to the user that locked it, until it is unlocked or
+//! unmapped. Users other than root can only lock up to a limit, which root can change through
+//! `sys:mlock`.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::syscall::error::{Error, Result, ENOMEM};
+
+use super::PAGE_SIZE;
+
+/// Number of pages each user can lock by default, 8 MiB.
+const DEFAULT_LIMIT: usize = 8 * 1024 * 1024 / PAGE_SIZE;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_LIMIT);
+
+/// Number of locked pages charged to each user that has locked any.
+static LOCKED: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+/// The number of pages each user other than root can lock.
+pub fn limit() -> usize {
+    LIMIT.load(Ordering::Relaxed)
+}
+/// Change the limit, which does not affect memory that is already locked.
+pub fn set_limit(page_count: usize) {
+    LIMIT.store(page_count, Ordering::Relaxed);
+}
+
+/// Charge `page_count` newly locked pages to `uid`, failing with ENOMEM if that would exceed the
+/// limit.
+pub fn charge(uid: u32, page_count: usize) -> Result<()> {
+    let mut locked = LOCKED.lock();
+    let current = locked.get(&uid).copied().unwrap_or(0);
+    let new = current
+        .checked_add(page_count)
+        .filter(|&new| uid == 0 || new <= limit())
+        .ok_or(Error::new(ENOMEM))?;
+
+    if new != 0 {
+        locked.insert(uid, new);
+    }
+    Ok(())
+}
+/// Give back the charge for `page_count` pages that are no longer locked.
+pub fn uncharge(uid: u32, page_count: usize) {
+    let mut locked = LOCKED.lock();
+    let Some(current) = locked.get_mut(&uid) else {
+        log::warn!("Uncharging locked pages of uid {} that has none", uid);
+        return;
+    };
+    *current = current.saturating_sub(page_count);
+    if *current == 0 {
+        locked.remove(&uid);
+    }
+}
+
+/// The number of locked pages of each user that has locked any.
+pub fn usage() -> Vec<(u32, usize)> {
+    LOCKED
+        .lock()
+        .iter()
+        .map(|(&uid, &page_count)| (uid, page_count))
+        .collect()
+}
@@ -1,7 +1,15 @@
 //! # Memory management
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/allocating-frames.html)
 
+pub mod huge;
+pub mod hugepool;
 mod kernel_mapper;
+pub mod ksm;
+pub mod limit;
+pub mod mlock;
+pub mod oom;
+pub mod pressure;
+pub mod swap;
 
 use core::{
     cell::SyncUnsafeCell,
@@ -21,7 +29,10 @@ use crate::{
     },
     kernel_executable_offsets::{__usercopy_end, __usercopy_start},
     paging::Page,
-    syscall::error::{Error, ENOMEM},
+    syscall::{
+        error::{Error, ENOMEM},
+        flag::SIGKILL,
+    },
 };
 use rmm::{BumpAllocator, FrameAllocator, FrameCount, FrameUsage, TableKind, VirtualAddress};
 
@@ -64,6 +75,63 @@ pub fn allocate_p2frame(order: u32) -> Option<Frame> {
 pub fn allocate_frame() -> Option<Frame> {
     allocate_p2frame(0)
 }
+/// Allocate a range of frames of an order possibly larger than the largest order of the frame
+/// allocator, made up of adjacent free blocks of that order. Such allocations are only likely to
+/// succeed early during boot, before memory becomes fragmented. The range must be freed in blocks
+/// of at most the largest order.
+pub fn allocate_p2frame_large(order: u32) -> Option<Frame> {
+    if order <= MAX_ORDER {
+        return allocate_p2frame(order);
+    }
+    let block_count = 1_usize << (order - MAX_ORDER);
+    let is_free_block = |frame: Frame| {
+        get_page_info(frame)
+            .and_then(PageInfo::as_free)
+            .is_some_and(|info| info.next().order() == MAX_ORDER)
+    };
+
+    let mut freelist = FREELIST.lock();
+
+    // Look for a free block, all other blocks in the aligned range of which are free too.
+    let mut cursor = freelist.for_orders[MAX_ORDER as usize];
+    let base = loop {
+        let block = cursor?;
+        cursor = get_free_alloc_page_info(block).next().frame();
+
+        let base = Frame::containing(PhysicalAddress::new(
+            block.base().data() & !((PAGE_SIZE << order) - 1),
+        ));
+        if (0..block_count).all(|i| is_free_block(base.next_by(i << MAX_ORDER))) {
+            break base;
+        }
+    };
+
+    for i in 0..block_count {
+        let block = base.next_by(i << MAX_ORDER);
+        let info = get_free_alloc_page_info(block);
+        let (prev, next) = (info.prev(), info.next());
+
+        if let Some(prev) = prev.frame() {
+            get_free_alloc_page_info(prev).set_next(next);
+        } else {
+            debug_assert_eq!(freelist.for_orders[MAX_ORDER as usize], Some(block));
+            freelist.for_orders[MAX_ORDER as usize] = next.frame();
+        }
+        if let Some(next) = next.frame() {
+            get_free_alloc_page_info(next).set_prev(prev);
+        }
+        info.mark_used();
+    }
+    freelist.used_frames += 1 << order;
+    pressure::update(freelist.used_frames);
+    drop(freelist);
+
+    unsafe {
+        (RmmA::phys_to_virt(base.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE << order);
+    }
+
+    Some(base)
+}
 // TODO: Flags, strategy
 pub fn allocate_p2frame_complex(
     _req_order: u32,
@@ -132,6 +200,7 @@ pub fn allocate_p2frame_complex(
     }
 
     freelist.used_frames += 1 << min_order;
+    pressure::update(freelist.used_frames);
 
     info.mark_used();
     drop(freelist);
@@ -227,6 +296,7 @@ pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
 
     //log::info!("FREED {frame:?}+2^{order}");
     freelist.used_frames -= 1 << order;
+    pressure::update(freelist.used_frames);
 }
 
 pub unsafe fn deallocate_frame(frame: Frame) {
@@ -348,6 +418,12 @@ impl RaiiFrame {
     pub fn get(&self) -> Frame {
         self.inner
     }
+    /// Give up ownership of the frame, e.g. when it has been mapped.
+    pub fn take(self) -> Frame {
+        let frame = self.inner;
+        core::mem::forget(self);
+        frame
+    }
 }
 
 impl Drop for RaiiFrame {
@@ -985,9 +1061,25 @@ pub fn page_fault_handler(
     }
 
     if address_is_user && (caused_by_user || is_usercopy) {
-        match context::memory::try_correcting_page_tables(faulting_page, mode) {
+        let result = context::memory::try_correcting_page_tables(faulting_page, mode);
+        if caused_by_user {
+            pressure::trigger_pending();
+        }
+        match result {
             Ok(()) => return Ok(()),
-            Err(PfError::Oom) => todo!("oom"),
+            // Memory could not be reclaimed by paging out other pages or killing other processes.
+            Err(PfError::Oom | PfError::OverLimit(_)) => {
+                log::warn!("Out of memory handling page fault at {faulting_address:?}");
+
+                // The OOM killer may have chosen the faulting process itself.
+                if caused_by_user && context::current().read().being_sigkilled {
+                    crate::syscall::process::exit(SIGKILL << 8);
+                }
+            }
+            // Paging in is given up on when the process is killed.
+            Err(PfError::Segv) if caused_by_user && context::current().read().being_sigkilled => {
+                crate::syscall::process::exit(SIGKILL << 8);
+            }
             Err(PfError::Segv | PfError::RecursionLimitExceeded) => (),
             Err(PfError::NonfatalInternalError) => todo!(),
         }
@@ -0,0 +1,149 @@
+//! # OOM killer
+//! When a page fault cannot be resolved because memory has run out, even after paging out other
+//! pages, a process is killed so that its memory can be freed. Processes are scored by the number
+//! of pages of allocated memory they have resident, adjusted by their `oom_score_adj`, which can be
+//! set through `proc:<pid>/oom_score_adj`, and the process with the highest score is sent SIGKILL.
+//! When a process group or session exceeds its memory limit, only its processes are considered.
+
+use alloc::sync::{Arc, Weak};
+use spin::{Mutex, RwLock};
+
+use crate::{
+    context::{
+        self,
+        process::{self, Process, ProcessStatus, INIT},
+        quota::QuotaGroup,
+    },
+    syscall::{
+        flag::SIGKILL,
+        process::{send_signal, KillMode, KillTarget},
+    },
+    time::NANOS_PER_SEC,
+};
+
+use syscall::SenderInfo;
+
+/// Processes with this adjustment are never killed.
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
+
+/// How long to wait for a victim to exit, before assuming it is stuck and killing another process.
+const VICTIM_TIMEOUT: u128 = NANOS_PER_SEC;
+
+/// The last process that was killed, and when.
+static VICTIM: Mutex<Option<(Weak<RwLock<Process>>, u128)>> = Mutex::new(None);
+
+/// The score of a process, or `None` if it must not be killed.
+pub fn score(process_lock: &Arc<RwLock<Process>>) -> Option<usize> {
+    let (pid, adj, thread) = {
+        let process = process_lock.read();
+        if !matches!(process.status, ProcessStatus::PossiblyRunnable) {
+            return None;
+        }
+        (
+            process.pid,
+            process.oom_score_adj,
+            process.threads.iter().find_map(Weak::upgrade),
+        )
+    };
+    if pid == INIT || adj <= OOM_SCORE_ADJ_MIN {
+        return None;
+    }
+    // Kernel contexts have no address space.
+    let addr_space = Arc::clone(thread?.read().addr_space().ok()?);
+    let resident = addr_space.acquire_read().resident_pages();
+
+    // Like on Linux, each unit of adjustment counts as a thousandth of all memory.
+    let adjustment = isize::from(adj) * (super::total_frames() / 1000) as isize;
+    Some((resident as isize + adjustment).max(1) as usize)
+}
+
+/// The victim that was killed last, if it is still expected to exit and free its memory.
+fn pending_victim(
+    victim: &Option<(Weak<RwLock<Process>>, u128)>,
+    now: u128,
+) -> Option<Arc<RwLock<Process>>> {
+    let (process_lock, killed_at) = victim.as_ref()?;
+    let process_lock = process_lock.upgrade()?;
+
+    if matches!(process_lock.read().status, ProcessStatus::Exited(_))
+        || now.saturating_sub(*killed_at) >= VICTIM_TIMEOUT
+    {
+        return None;
+    }
+    Some(process_lock)
+}
+
+/// Kill the process with the highest score, and yield so that it can exit. Returns whether memory
+/// may have been freed, in which case the allocation should be retried. If the current process is
+/// killed, it returns false, as the current context must exit instead.
+pub fn kill_victim() -> bool {
+    kill_victim_where(|_| true)
+}
+/// Like [`kill_victim`], but only kill a process of `group`, which has exceeded its memory limit.
+pub fn kill_victim_in(group: QuotaGroup) -> bool {
+    kill_victim_where(|process| match group {
+        QuotaGroup::ProcessGroup(pgid) => process.pgid == pgid,
+        QuotaGroup::Session(session_id) => process.session_id == session_id,
+    })
+}
+fn kill_victim_where(is_candidate: impl Fn(&Process) -> bool) -> bool {
+    let now = crate::time::monotonic();
+    let current_pid = context::current().read().pid;
+
+    let pending = pending_victim(&VICTIM.lock(), now);
+    if let Some(victim) = pending {
+        if victim.read().pid == current_pid {
+            return false;
+        }
+        context::switch();
+        return true;
+    }
+
+    let (points, victim) = {
+        let processes = process::PROCESSES.read();
+        let Some((points, victim)) = processes
+            .values()
+            .filter(|process_lock| is_candidate(&process_lock.read()))
+            .filter_map(|process_lock| Some((score(process_lock)?, process_lock)))
+            .max_by_key(|(points, _)| *points)
+        else {
+            return false;
+        };
+        (points, Arc::clone(victim))
+    };
+    {
+        let mut last_victim = VICTIM.lock();
+        // Another CPU may have killed a process in the meantime.
+        if pending_victim(&last_victim, now).is_some() {
+            drop(last_victim);
+            context::switch();
+            return true;
+        }
+        *last_victim = Some((Arc::downgrade(&victim), now));
+    }
+
+    let victim_pid = victim.read().pid;
+    log::warn!(
+        "Out of memory: killing process {} with score {}",
+        victim_pid.get(),
+        points
+    );
+    let mut killed_self = false;
+    if let Err(err) = send_signal(
+        KillTarget::Process(victim),
+        SIGKILL,
+        KillMode::Idempotent,
+        false,
+        &mut killed_self,
+        SenderInfo { pid: 0, ruid: 0 },
+    ) {
+        log::warn!("Failed to kill OOM victim: {:?}", err);
+        return false;
+    }
+    if victim_pid == current_pid {
+        return false;
+    }
+    context::switch();
+    true
+}
@@ -0,0 +1,98 @@
+//! # Memory pressure
+//! The amount of free memory is classified into pressure levels, by thresholds that root can set
+//! through `sys:mempressure`. The frame allocator updates the level whenever it allocates or frees
+//! frames, and when it changes, the `sys:mempressure` handles registered with `event:` are notified
+//! so that userspace caches can be shrunk before memory has to be paged out or processes killed.
+//!
+//! As the allocator may be called with any lock held, it only marks the event as pending, and it is
+//! triggered at the next safe point, when a syscall or a page fault of userspace returns.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::syscall::error::{Error, Result, EINVAL};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    None,
+    Low,
+    Medium,
+    Critical,
+}
+impl Level {
+    const ALL: [Self; 4] = [Self::None, Self::Low, Self::Medium, Self::Critical];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// Numbers of free frames below which the level is low, medium and critical respectively, set
+/// relative to the total amount of memory during boot.
+static THRESHOLDS: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+/// Total number of frames, as the allocator only knows how many are used.
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+/// The level as of the last allocation or free.
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::None as usize);
+/// Whether the level has changed since the event was last triggered.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+pub fn thresholds() -> [usize; 3] {
+    THRESHOLDS
+        .each_ref()
+        .map(|threshold| threshold.load(Ordering::Relaxed))
+}
+/// Set the low, medium and critical thresholds, in frames, which must not increase with the level.
+pub fn set_thresholds(thresholds: [usize; 3]) -> Result<()> {
+    if !thresholds.is_sorted_by(|a, b| a >= b) {
+        return Err(Error::new(EINVAL));
+    }
+    for (threshold, value) in THRESHOLDS.iter().zip(thresholds) {
+        threshold.store(value, Ordering::Relaxed);
+    }
+    update(super::used_frames());
+    Ok(())
+}
+
+/// The level for a number of free frames, given by the highest threshold it is below.
+fn level_of(free: usize) -> Level {
+    let above = thresholds()
+        .iter()
+        .filter(|&&threshold| free < threshold)
+        .count();
+    Level::ALL[above]
+}
+/// The current level.
+pub fn level() -> Level {
+    level_of(super::free_frames())
+}
+
+/// Update the level after the number of used frames has changed. Called by the allocator, so this
+/// must not take any lock.
+pub fn update(used_frames: usize) {
+    let level = level_of(TOTAL.load(Ordering::Relaxed).saturating_sub(used_frames));
+    if LEVEL.swap(level as usize, Ordering::Relaxed) != level as usize {
+        PENDING.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Notify the `sys:mempressure` handles if the level has changed. Must be called without holding
+/// any lock.
+pub fn trigger_pending() {
+    if PENDING.load(Ordering::Relaxed) && PENDING.swap(false, Ordering::Relaxed) {
+        let level = Level::ALL[LEVEL.load(Ordering::Relaxed)];
+        log::debug!("Memory pressure is now {}", level.name());
+        crate::scheme::sys::trigger_event("mempressure");
+    }
+}
+
+/// Set the default thresholds of 10%, 5% and 2% of all memory.
+pub fn init() {
+    let total = super::total_frames();
+    TOTAL.store(total, Ordering::Relaxed);
+    let _ = set_thresholds([total / 10, total / 20, total / 50]);
+}
@@ -0,0 +1,548 @@
+//! # Swap
+//! Anonymous memory can be paged out to a swap device, which is served by a userspace daemon
+//! through the `memory:swap` handle. The daemon sets the size of the device with `ftruncate`, and
+//! then reads requests from the handle. Every request starts with a header of two native-endian
+//! `u64`s, the kind of request and the slot it refers to:
+//!
+//! - [`SWAP_OUT`] is followed by the contents of the page, which the daemon stores in the slot.
+//! - [`SWAP_IN`] asks for the contents of a slot, which the daemon answers by writing the same
+//!   header followed by the contents of the page.
+//! - [`SWAP_FREE`] tells the daemon that the slot is no longer used, and needs no answer.
+//!
+//! The daemon must serve requests in order, so that a slot is only read back after it was stored.
+//! Its own address space is never paged out, and neither is any memory on architectures whose page
+//! tables have no accessed bit to tell cold pages apart.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    context::{
+        self,
+        context::HardBlockedReason,
+        memory::{copy_frame_to_frame_directly, AddrSpace, AddrSpaceWrapper, PfError},
+        Context, Status,
+    },
+    paging::{RmmA, RmmArch},
+    sync::WaitCondition,
+    syscall::{
+        error::{Error, Result, EAGAIN, EBUSY, EINTR, EINVAL, ENODEV},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{Frame, RaiiFrame, PAGE_SIZE};
+
+pub const SWAP_OUT: u64 = 1;
+pub const SWAP_IN: u64 = 2;
+pub const SWAP_FREE: u64 = 3;
+
+/// Size of the header of swap requests and replies.
+pub const SWAP_HEADER_SIZE: usize = 2 * core::mem::size_of::<u64>();
+
+/// Number of pages to page out at once when memory runs out.
+const SWAP_CLUSTER: usize = 32;
+
+/// The location of a paged out page on the swap device. Slots remember the device they belong to,
+/// as the swap daemon may be restarted while pages are still paged out to its previous device,
+/// whose contents are then lost.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SwapSlot {
+    device: u64,
+    index: u64,
+}
+
+enum Request {
+    Out { index: u64, frame: RaiiFrame },
+    In { index: u64 },
+    Free { index: u64 },
+}
+
+/// A context waiting for the contents of a slot to be read into `frame`.
+struct PageIn {
+    index: u64,
+    frame: Frame,
+    context: Weak<RwSpinlock<Context>>,
+}
+
+struct SwapDevice {
+    id: u64,
+    /// Address space of the swap daemon
+    daemon: Weak<AddrSpaceWrapper>,
+    slot_count: u64,
+    /// Slots at or above this index have never been used
+    next_slot: u64,
+    free_slots: Vec<u64>,
+    /// Number of paged out pages referring to each slot in use
+    refs: BTreeMap<u64, usize>,
+    requests: VecDeque<Request>,
+    page_ins: Vec<PageIn>,
+    /// Contexts waiting for page-outs to free memory
+    frame_waiters: Vec<Weak<RwSpinlock<Context>>>,
+}
+
+impl SwapDevice {
+    fn index_of(&self, slot: SwapSlot) -> Option<u64> {
+        (slot.device == self.id).then_some(slot.index)
+    }
+    fn alloc_slot(&mut self) -> Option<SwapSlot> {
+        let index = match self.free_slots.pop() {
+            Some(index) => index,
+            None if self.next_slot < self.slot_count => {
+                self.next_slot += 1;
+                self.next_slot - 1
+            }
+            None => return None,
+        };
+        self.refs.insert(index, 1);
+
+        Some(SwapSlot {
+            device: self.id,
+            index,
+        })
+    }
+    fn release(&mut self, index: u64) {
+        let Some(refs) = self.refs.get_mut(&index) else {
+            return;
+        };
+        *refs -= 1;
+        if *refs > 0 {
+            return;
+        }
+        self.refs.remove(&index);
+
+        // If the daemon has not read the page yet, it never has to.
+        let pending_out = self
+            .requests
+            .iter()
+            .position(|request| matches!(request, Request::Out { index: i, .. } if *i == index));
+        match pending_out {
+            Some(position) => {
+                self.requests.remove(position);
+            }
+            None => self.send(Request::Free { index }),
+        }
+        self.free_slots.push(index);
+    }
+    fn send(&mut self, request: Request) {
+        self.requests.push_back(request);
+        REQUESTS.notify();
+    }
+}
+
+static SWAP: Mutex<Option<SwapDevice>> = Mutex::new(None);
+static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
+static REQUESTS: WaitCondition = WaitCondition::new();
+
+/// Index of the address space to start reclaiming from, so that the pressure is spread over all of
+/// them.
+static RECLAIM_CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Wake up a context blocked in [`swap_in`] or [`reclaim`]. This is also done when it is killed,
+/// as those waits cannot be interrupted by signals otherwise.
+pub fn wake(context: &mut Context) {
+    if let Status::HardBlocked {
+        reason: HardBlockedReason::AwaitingSwap,
+    } = context.status
+    {
+        context.set_runnable();
+    }
+}
+
+/// Start swapping to a device served by the daemon using `daemon` as its address space.
+pub fn swap_on(daemon: &Arc<AddrSpaceWrapper>) -> Result<()> {
+    let mut swap = SWAP.lock();
+    if swap.is_some() {
+        return Err(Error::new(EBUSY));
+    }
+    *swap = Some(SwapDevice {
+        id: NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed),
+        daemon: Arc::downgrade(daemon),
+        slot_count: 0,
+        next_slot: 0,
+        free_slots: Vec::new(),
+        refs: BTreeMap::new(),
+        requests: VecDeque::new(),
+        page_ins: Vec::new(),
+        frame_waiters: Vec::new(),
+    });
+    Ok(())
+}
+
+/// Stop swapping, when the swap daemon closes its handle. Pages that are still paged out are
+/// lost, and contexts faulting on them are killed.
+pub fn swap_off() {
+    let Some(device) = SWAP.lock().take() else {
+        return;
+    };
+    if !device.refs.is_empty() {
+        log::warn!(
+            "swap device stopped with {} slots in use",
+            device.refs.len()
+        );
+    }
+    for page_in in device.page_ins {
+        if let Some(context) = page_in.context.upgrade() {
+            let mut context = context.write();
+            context.swap_ret = Some(false);
+            wake(&mut context);
+        }
+    }
+    for context in device.frame_waiters {
+        if let Some(context) = context.upgrade() {
+            wake(&mut context.write());
+        }
+    }
+}
+
+/// Size of the swap device in bytes.
+pub fn size() -> Result<u64> {
+    let swap = SWAP.lock();
+    let device = swap.as_ref().ok_or(Error::new(ENODEV))?;
+    Ok(device.slot_count * PAGE_SIZE as u64)
+}
+
+/// Set the size of the swap device in bytes. The device can only grow, as slots beyond a smaller
+/// size may still be in use.
+pub fn set_size(size: usize) -> Result<()> {
+    let mut swap = SWAP.lock();
+    let device = swap.as_mut().ok_or(Error::new(ENODEV))?;
+    let slot_count = (size / PAGE_SIZE) as u64;
+    if slot_count < device.slot_count {
+        return Err(Error::new(EINVAL));
+    }
+    device.slot_count = slot_count;
+    Ok(())
+}
+
+/// Number of slots in use, and the total number of slots.
+pub fn usage() -> (u64, u64) {
+    SWAP.lock().as_ref().map_or((0, 0), |device| {
+        (device.refs.len() as u64, device.slot_count)
+    })
+}
+
+/// Allocate up to `count` free slots to page out to.
+pub fn alloc_slots(count: usize) -> Vec<SwapSlot> {
+    let mut swap = SWAP.lock();
+    let Some(device) = swap.as_mut() else {
+        return Vec::new();
+    };
+    (0..count).map_while(|_| device.alloc_slot()).collect()
+}
+
+/// Add a reference to a slot, for a paged out page that is being copied on write.
+pub fn dup(slot: SwapSlot) -> SwapSlot {
+    if let Some(device) = SWAP.lock().as_mut()
+        && let Some(index) = device.index_of(slot)
+        && let Some(refs) = device.refs.get_mut(&index)
+    {
+        *refs += 1;
+    }
+    slot
+}
+
+/// Drop a reference to a slot, freeing it if it was the last one.
+pub fn release(slot: SwapSlot) {
+    if let Some(device) = SWAP.lock().as_mut()
+        && let Some(index) = device.index_of(slot)
+    {
+        device.release(index);
+    }
+}
+
+/// Queue the frames of pages that have been unmapped to be written to their slots, freeing the
+/// frames once the daemon has read them. Fails without queueing anything if the device the slots
+/// were allocated on has been stopped in the meantime.
+pub fn page_out(pages: Vec<(SwapSlot, Frame)>) -> Result<(), Vec<(SwapSlot, Frame)>> {
+    let mut swap = SWAP.lock();
+    let Some(device) = swap
+        .as_mut()
+        .filter(|device| pages.iter().all(|(slot, _)| slot.device == device.id))
+    else {
+        return Err(pages);
+    };
+    for (slot, frame) in pages {
+        device.send(Request::Out {
+            index: slot.index,
+            // SAFETY: The frame was exclusively owned by the page table entry it was unmapped
+            // from, whose reference is transferred here.
+            frame: unsafe { RaiiFrame::new_unchecked(frame) },
+        });
+    }
+    Ok(())
+}
+
+/// Read a paged out page back into a new frame, blocking until the swap daemon has provided its
+/// contents. The slot is not released, as the caller has to check that the page is still paged
+/// out to it first.
+pub fn swap_in(slot: SwapSlot) -> Result<RaiiFrame, PfError> {
+    let frame = RaiiFrame::allocate().map_err(|_| PfError::Oom)?;
+    let context_lock = context::current();
+
+    {
+        let mut swap = SWAP.lock();
+        let device = swap.as_mut().ok_or(PfError::Segv)?;
+        let index = device.index_of(slot).ok_or(PfError::Segv)?;
+
+        // The page may not have been written out yet, in which case its old frame is still around.
+        // It cannot be taken over, since other pages may be paged out to the same slot.
+        let pending_out = device.requests.iter().find_map(|request| match request {
+            Request::Out { index: i, frame } if *i == index => Some(frame.get()),
+            _ => None,
+        });
+        if let Some(old_frame) = pending_out {
+            unsafe {
+                copy_frame_to_frame_directly(frame.get(), old_frame);
+            }
+            return Ok(frame);
+        }
+
+        device.page_ins.push(PageIn {
+            index,
+            frame: frame.get(),
+            context: Arc::downgrade(&context_lock),
+        });
+        device.send(Request::In { index });
+        let mut context = context_lock.write();
+        if !context.being_sigkilled {
+            context.hard_block(HardBlockedReason::AwaitingSwap);
+        }
+    }
+
+    let succeeded = loop {
+        context::switch();
+
+        let mut context = context_lock.write();
+        if let Some(succeeded) = context.swap_ret.take() {
+            break succeeded;
+        }
+        if context.being_sigkilled {
+            drop(context);
+            // Unless the daemon is already reading the page into the frame, which then has to be
+            // waited for, the page is no longer needed.
+            if let Some(device) = SWAP.lock().as_mut()
+                && let Some(i) = device
+                    .page_ins
+                    .iter()
+                    .position(|page_in| page_in.frame == frame.get())
+            {
+                device.page_ins.swap_remove(i);
+                return Err(PfError::Segv);
+            }
+            context = context_lock.write();
+            if let Some(succeeded) = context.swap_ret.take() {
+                break succeeded;
+            }
+        }
+        context.hard_block(HardBlockedReason::AwaitingSwap);
+    };
+
+    if succeeded {
+        Ok(frame)
+    } else {
+        Err(PfError::Segv)
+    }
+}
+
+/// Page out cold anonymous pages to free memory, and wait until at least one of their frames has
+/// been freed. Returns whether allocating memory is worth retrying.
+pub fn reclaim() -> bool {
+    let daemon = {
+        let swap = SWAP.lock();
+        let Some(device) = swap.as_ref() else {
+            return false;
+        };
+        device.daemon.clone()
+    };
+    // The swap daemon would end up waiting for itself.
+    let is_daemon =
+        |addr_space: &Arc<AddrSpaceWrapper>| Weak::as_ptr(&daemon) == Arc::as_ptr(addr_space);
+    if AddrSpace::current().is_ok_and(|addrsp| is_daemon(&addrsp)) {
+        return false;
+    }
+
+    let mut addr_spaces = context::contexts()
+        .iter()
+        .filter_map(|context_ref| context_ref.0.read().addr_space().ok().cloned())
+        .filter(|addr_space| !is_daemon(addr_space))
+        .collect::<Vec<_>>();
+    addr_spaces.sort_unstable_by_key(Arc::as_ptr);
+    addr_spaces.dedup_by(|a, b| Arc::ptr_eq(a, b));
+    if addr_spaces.is_empty() {
+        return false;
+    }
+
+    // The first pass clears the accessed bit of pages that were used since they were last looked
+    // at, so that the second pass pages them out unless they have been used again.
+    let start = RECLAIM_CURSOR.fetch_add(1, Ordering::Relaxed) % addr_spaces.len();
+    let mut paged_out = 0;
+    'passes: for _ in 0..2 {
+        let (head, tail) = addr_spaces.split_at(start);
+        for addr_space in tail.iter().chain(head) {
+            paged_out += addr_space.page_out_cold(SWAP_CLUSTER - paged_out);
+            if paged_out >= SWAP_CLUSTER {
+                break 'passes;
+            }
+        }
+    }
+    if paged_out == 0 {
+        return false;
+    }
+
+    let context_lock = context::current();
+    {
+        let mut swap = SWAP.lock();
+        let Some(device) = swap.as_mut() else {
+            return true;
+        };
+        if !device
+            .requests
+            .iter()
+            .any(|request| matches!(request, Request::Out { .. }))
+        {
+            return true;
+        }
+        device.frame_waiters.push(Arc::downgrade(&context_lock));
+        context_lock
+            .write()
+            .hard_block(HardBlockedReason::AwaitingSwap);
+    }
+    context::switch();
+
+    true
+}
+
+/// Read the next request into `buf`, which must fit a header and a page.
+pub fn read_request(buf: UserSliceWo, block: bool) -> Result<usize> {
+    if buf.len() < SWAP_HEADER_SIZE + PAGE_SIZE {
+        return Err(Error::new(EINVAL));
+    }
+
+    let (device_id, request) = loop {
+        let mut swap = SWAP.lock();
+        let device = swap.as_mut().ok_or(Error::new(ENODEV))?;
+
+        if let Some(request) = device.requests.pop_front() {
+            break (device.id, request);
+        } else if !block {
+            return Err(Error::new(EAGAIN));
+        } else if !REQUESTS.wait(swap, "swap::read_request") {
+            return Err(Error::new(EINTR));
+        }
+    };
+
+    // The swap lock cannot be held while copying to the daemon, which may fault.
+    let (kind, index, frame) = match request {
+        Request::Out { index, ref frame } => (SWAP_OUT, index, Some(frame.get())),
+        Request::In { index } => (SWAP_IN, index, None),
+        Request::Free { index } => (SWAP_FREE, index, None),
+    };
+    let copied = (|| {
+        let (header_buf, page_buf) = buf.split_at(SWAP_HEADER_SIZE).ok_or(Error::new(EINVAL))?;
+        let mut header = [0_u8; SWAP_HEADER_SIZE];
+        header[..8].copy_from_slice(&kind.to_ne_bytes());
+        header[8..].copy_from_slice(&index.to_ne_bytes());
+        header_buf.copy_from_slice(&header)?;
+
+        let Some(frame) = frame else {
+            return Ok(SWAP_HEADER_SIZE);
+        };
+        let page = unsafe {
+            core::slice::from_raw_parts(
+                RmmA::phys_to_virt(frame.base()).data() as *const u8,
+                PAGE_SIZE,
+            )
+        };
+        page_buf
+            .limit(PAGE_SIZE)
+            .ok_or(Error::new(EINVAL))?
+            .copy_from_slice(page)?;
+        Ok(SWAP_HEADER_SIZE + PAGE_SIZE)
+    })();
+
+    let mut swap = SWAP.lock();
+    let Some(device) = swap.as_mut().filter(|device| device.id == device_id) else {
+        return copied;
+    };
+    if copied.is_err() {
+        device.requests.push_front(request);
+        return copied;
+    }
+    if let Request::Out { frame, .. } = request {
+        drop(frame);
+        for context in device.frame_waiters.drain(..) {
+            if let Some(context) = context.upgrade() {
+                wake(&mut context.write());
+            }
+        }
+    }
+    copied
+}
+
+/// Handle a reply to a [`SWAP_IN`] request, providing the contents of the slot.
+pub fn write_reply(buf: UserSliceRo) -> Result<usize> {
+    let (header_buf, page_buf) = buf.split_at(SWAP_HEADER_SIZE).ok_or(Error::new(EINVAL))?;
+    let page_buf = page_buf.limit(PAGE_SIZE).ok_or(Error::new(EINVAL))?;
+    if page_buf.len() != PAGE_SIZE {
+        return Err(Error::new(EINVAL));
+    }
+    let mut header = [0_u8; SWAP_HEADER_SIZE];
+    header_buf.copy_to_slice(&mut header)?;
+    let kind = u64::from_ne_bytes(header[..8].try_into().unwrap());
+    let index = u64::from_ne_bytes(header[8..].try_into().unwrap());
+    if kind != SWAP_IN {
+        return Err(Error::new(EINVAL));
+    }
+
+    // Several contexts may be waiting for the same slot, as paged out pages can be shared.
+    let mut waiters = {
+        let mut swap = SWAP.lock();
+        let device = swap.as_mut().ok_or(Error::new(ENODEV))?;
+        let (waiters, others) = core::mem::take(&mut device.page_ins)
+            .into_iter()
+            .partition::<Vec<_>, _>(|page_in| page_in.index == index);
+        device.page_ins = others;
+        waiters
+    }
+    .into_iter();
+
+    let Some(first) = waiters.next() else {
+        return Err(Error::new(EINVAL));
+    };
+    let first_frame = first.frame;
+    let page = unsafe {
+        core::slice::from_raw_parts_mut(
+            RmmA::phys_to_virt(first_frame.base()).data() as *mut u8,
+            PAGE_SIZE,
+        )
+    };
+    if let Err(err) = page_buf.copy_to_slice(page) {
+        if let Some(device) = SWAP.lock().as_mut() {
+            device.page_ins.push(first);
+            device.page_ins.extend(waiters);
+        }
+        return Err(err);
+    }
+
+    for page_in in core::iter::once(first).chain(waiters) {
+        if page_in.frame != first_frame {
+            unsafe {
+                copy_frame_to_frame_directly(page_in.frame, first_frame);
+            }
+        }
+        if let Some(context) = page_in.context.upgrade() {
+            let mut context = context.write();
+            context.swap_ret = Some(true);
+            wake(&mut context);
+        }
+    }
+
+    Ok(SWAP_HEADER_SIZE + PAGE_SIZE)
+}
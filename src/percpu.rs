@@ -52,6 +52,19 @@ pub unsafe fn init_tlb_shootdown(id: LogicalCpuId, block: *mut PercpuBlock) {
     ALL_PERCPU_BLOCKS[id.get() as usize].store(block, Ordering::Release)
 }
 
+/// Get the percpu block of any CPU, if that CPU has been initialized.
+///
+/// Only fields that are safe to access from other CPUs, such as atomics or the run queue, may be
+/// used through the returned reference.
+pub fn get(id: LogicalCpuId) -> Option<&'static PercpuBlock> {
+    unsafe {
+        ALL_PERCPU_BLOCKS
+            .get(id.get() as usize)?
+            .load(Ordering::Acquire)
+            .as_ref()
+    }
+}
+
 // PercpuBlock::current() is implemented somewhere in the arch-specific modules
 
 #[cfg(not(feature = "multi_core"))]
@@ -0,0 +1,57 @@
+//! # Kernel entropy
+//! A fast source of random numbers for the kernel's own use, such as address space layout
+//! randomization. It mixes RDRAND, where available, and the jitter of the monotonic clock into a
+//! splitmix64 generator. The output is unpredictable enough to defeat guessing of addresses, but
+//! is not suitable for cryptography.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static STATE: AtomicU64 = AtomicU64::new(0x2545_f491_4f6c_dd1d);
+
+#[cfg(target_arch = "x86_64")]
+fn hardware() -> Option<u64> {
+    static HAS_RDRAND: spin::Once<bool> = spin::Once::new();
+
+    let has_rdrand = *HAS_RDRAND.call_once(|| {
+        crate::cpuid::cpuid()
+            .get_feature_info()
+            .map_or(false, |info| info.has_rdrand())
+    });
+    if !has_rdrand {
+        return None;
+    }
+
+    // RDRAND can fail transiently when the hardware generator is drained, in which case Intel
+    // recommends retrying up to ten times.
+    (0..10).find_map(|_| {
+        let mut value = 0;
+        (unsafe { core::arch::x86_64::_rdrand64_step(&mut value) } == 1).then_some(value)
+    })
+}
+#[cfg(not(target_arch = "x86_64"))]
+fn hardware() -> Option<u64> {
+    None
+}
+
+/// Return a random 64-bit number.
+pub fn next_u64() -> u64 {
+    let entropy = hardware().unwrap_or(0) ^ crate::time::monotonic() as u64;
+
+    // splitmix64, with the entropy added to the increment so that the sequence cannot be replayed
+    // from a known state.
+    let increment = 0x9e37_79b9_7f4a_7c15 ^ (entropy << 1);
+    let mut z = STATE
+        .fetch_add(increment, Ordering::Relaxed)
+        .wrapping_add(increment);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Return a random number in `0..bound`, or 0 if `bound` is 0.
+pub fn below(bound: usize) -> usize {
+    if bound == 0 {
+        return 0;
+    }
+    (next_u64() % bound as u64) as usize
+}
@@ -8,7 +8,7 @@ use crate::{
         file::InternalFlags,
         memory::{handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
     },
-    memory::{free_frames, used_frames, Frame, PAGE_SIZE},
+    memory::{free_frames, hugepool::HugePageSize, swap, used_frames, Frame, PAGE_SIZE},
     paging::VirtualAddress,
 };
 
@@ -17,8 +17,8 @@ use crate::paging::entry::EntryFlags;
 use crate::syscall::{
     data::{Map, StatVfs},
     error::*,
-    flag::MapFlags,
-    usercopy::UserSliceWo,
+    flag::{MapFlags, O_NONBLOCK},
+    usercopy::{UserSliceRo, UserSliceWo},
 };
 
 use super::{CallerCtx, KernelScheme, OpenResult};
@@ -31,6 +31,9 @@ pub struct MemoryScheme;
 enum HandleTy {
     Allocated = 0,
     PhysBorrow = 1,
+    Swap = 2,
+    HugePage = 3,
+    Guard = 4,
 }
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -45,14 +48,29 @@ bitflags! {
     struct HandleFlags: u16 {
         // TODO: below 32 bits?
         const PHYS_CONTIGUOUS = 1;
+        // 1 GiB rather than 2 MiB huge pages
+        const HUGE_1G = 2;
+        // A stack that grows down into the rest of the mapping
+        const GROWS_DOWN = 4;
     }
 }
 
+fn handle_ty(id: usize) -> Result<HandleTy> {
+    u32::try_from(id)
+        .ok()
+        .and_then(from_raw)
+        .map(|(handle_ty, _, _)| handle_ty)
+        .ok_or(Error::new(EBADF))
+}
+
 fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
     Some((
         match raw & 0xFF {
             0 => HandleTy::Allocated,
             1 => HandleTy::PhysBorrow,
+            2 => HandleTy::Swap,
+            3 => HandleTy::HugePage,
+            4 => HandleTy::Guard,
 
             _ => return None,
         },
@@ -85,6 +103,104 @@ impl MemoryScheme {
             return Err(Error::new(EOPNOTSUPP));
         }
 
+        // Physically contiguous memory is allocated right away, whereas other memory is only
+        // allocated when faulted in, and thus only refused once the memory limit is reached.
+        let charged = if is_phys_contiguous { span.count } else { 0 };
+        addr_space.charge_memory(charged)?;
+
+        let page = addr_space
+            .acquire_write()
+            .mmap(
+                &addr_space,
+                (map.address != 0).then_some(span.base),
+                page_count,
+                map.flags,
+                &mut notify_files,
+                |dst_page, flags, mapper, flusher| {
+                    let span = PageSpan::new(dst_page, page_count.get());
+                    if is_phys_contiguous {
+                        Ok(Grant::zeroed_phys_contiguous(span, flags, mapper, flusher)?)
+                    } else {
+                        Ok(Grant::zeroed(
+                            span,
+                            flags,
+                            mapper,
+                            flusher,
+                            map.flags.contains(MapFlags::MAP_SHARED),
+                        )?)
+                    }
+                },
+            )
+            .inspect_err(|_| addr_space.uncharge_memory(charged))?;
+
+        handle_notify_files(notify_files);
+
+        Ok(page.start_address().data())
+    }
+    /// Map a stack that grows down on demand into the rest of the mapping, leaving guard pages at
+    /// its bottom. Fails with EINVAL for MAP_SHARED.
+    pub fn fmap_grows_down(addr_space: &Arc<AddrSpaceWrapper>, map: &Map) -> Result<usize> {
+        let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
+            .ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(span.count).ok_or(Error::new(EINVAL))?;
+
+        let mut notify_files = Vec::new();
+
+        let page = addr_space.acquire_write().mmap_grows_down(
+            &addr_space,
+            (map.address != 0).then_some(span.base),
+            page_count,
+            map.flags,
+            &mut notify_files,
+        )?;
+
+        handle_notify_files(notify_files);
+
+        Ok(page.start_address().data())
+    }
+    /// Reserve address space where nothing is mapped, and which faults when accessed.
+    pub fn fmap_guard(addr_space: &Arc<AddrSpaceWrapper>, map: &Map) -> Result<usize> {
+        let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
+            .ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(span.count).ok_or(Error::new(EINVAL))?;
+
+        let mut notify_files = Vec::new();
+
+        let page = addr_space.acquire_write().mmap_guard(
+            &addr_space,
+            (map.address != 0).then_some(span.base),
+            page_count,
+            map.flags,
+            &mut notify_files,
+        )?;
+
+        handle_notify_files(notify_files);
+
+        Ok(page.start_address().data())
+    }
+    /// Map huge pages from the pool reserved at boot. Fails with ENOMEM if there are not enough
+    /// free huge pages, and with EINVAL unless the address and size are multiples of the huge
+    /// page size.
+    pub fn fmap_hugepages(
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        size: HugePageSize,
+    ) -> Result<usize> {
+        let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
+            .ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(span.count).ok_or(Error::new(EINVAL))?;
+        let huge_page_size = size.page_count() * PAGE_SIZE;
+
+        if map.address % huge_page_size != 0 || map.size % huge_page_size != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if map.flags.contains(MapFlags::MAP_SHARED) {
+            // TODO: Should this be supported?
+            return Err(Error::new(EOPNOTSUPP));
+        }
+
+        let mut notify_files = Vec::new();
+
         let page = addr_space.acquire_write().mmap(
             &addr_space,
             (map.address != 0).then_some(span.base),
@@ -92,18 +208,12 @@ impl MemoryScheme {
             map.flags,
             &mut notify_files,
             |dst_page, flags, mapper, flusher| {
-                let span = PageSpan::new(dst_page, page_count.get());
-                if is_phys_contiguous {
-                    Ok(Grant::zeroed_phys_contiguous(span, flags, mapper, flusher)?)
-                } else {
-                    Ok(Grant::zeroed(
-                        span,
-                        flags,
-                        mapper,
-                        flusher,
-                        map.flags.contains(MapFlags::MAP_SHARED),
-                    )?)
+                // No suitably aligned free region was found.
+                if dst_page.start_address().data() % huge_page_size != 0 {
+                    return Err(Error::new(ENOMEM));
                 }
+                let span = PageSpan::new(dst_page, page_count.get());
+                Ok(Grant::zeroed_hugepages(span, size, flags, mapper, flusher)?)
             },
         )?;
 
@@ -183,12 +293,17 @@ impl KernelScheme for MemoryScheme {
         }
         let path = path.trim_start_matches('/');
 
+        // Paths are `<kind>@<memory type>?<flags>`, where the memory type and flags can be left
+        // out, e.g. `zeroed`, `zeroed@?growsdown` or `hugepage@wb?1g`.
         let (before_memty, memty_str) = path.split_once('@').unwrap_or((path, ""));
         let (before_ty, type_str) = memty_str.split_once('?').unwrap_or((memty_str, ""));
 
         let handle_ty = match before_memty {
             "" | "zeroed" => HandleTy::Allocated,
             "physical" => HandleTy::PhysBorrow,
+            "swap" => HandleTy::Swap,
+            "hugepage" => HandleTy::HugePage,
+            "guard" => HandleTy::Guard,
 
             _ => return Err(Error::new(ENOENT)),
         };
@@ -206,6 +321,8 @@ impl KernelScheme for MemoryScheme {
             .filter_map(|ty_str| match ty_str {
                 //"32" => HandleFlags::BELOW_4G,
                 "phys_contiguous" => Some(Some(HandleFlags::PHYS_CONTIGUOUS)),
+                "1g" => Some(Some(HandleFlags::HUGE_1G)),
+                "growsdown" => Some(Some(HandleFlags::GROWS_DOWN)),
                 "" => None,
                 _ => Some(None),
             })
@@ -214,15 +331,38 @@ impl KernelScheme for MemoryScheme {
 
         // TODO: Support arches with other default memory types?
         if ctx.uid != 0
-            && (!flags.is_empty()
+            && (!flags.difference(HandleFlags::GROWS_DOWN).is_empty()
                 || !matches!(
                     (handle_ty, mem_ty),
-                    (HandleTy::Allocated, MemoryType::Writeback)
+                    (HandleTy::Allocated | HandleTy::Guard, MemoryType::Writeback)
                 ))
         {
             return Err(Error::new(EACCES));
         }
 
+        if flags.contains(HandleFlags::HUGE_1G) && handle_ty != HandleTy::HugePage {
+            return Err(Error::new(EINVAL));
+        }
+        if flags.contains(HandleFlags::GROWS_DOWN)
+            && (handle_ty != HandleTy::Allocated
+                || mem_ty != MemoryType::Writeback
+                || flags != HandleFlags::GROWS_DOWN)
+        {
+            return Err(Error::new(EINVAL));
+        }
+        if handle_ty == HandleTy::Guard && (mem_ty != MemoryType::Writeback || !flags.is_empty()) {
+            return Err(Error::new(EINVAL));
+        }
+
+        // The swap daemon serves the swap device through its handle, and it is swapped to until the
+        // handle is closed.
+        if handle_ty == HandleTy::Swap {
+            if mem_ty != MemoryType::Writeback || !flags.is_empty() {
+                return Err(Error::new(EINVAL));
+            }
+            swap::swap_on(&AddrSpace::current()?)?;
+        }
+
         Ok(OpenResult::SchemeLocal(
             (handle_ty as usize) | ((mem_ty as usize) << 8) | (usize::from(flags.bits()) << 16),
             InternalFlags::empty(),
@@ -242,14 +382,64 @@ impl KernelScheme for MemoryScheme {
             .ok_or(Error::new(EBADF))?;
 
         match handle_ty {
+            HandleTy::Allocated if flags.contains(HandleFlags::GROWS_DOWN) => {
+                Self::fmap_grows_down(addr_space, map)
+            }
             HandleTy::Allocated => Self::fmap_anonymous(
                 addr_space,
                 map,
                 flags.contains(HandleFlags::PHYS_CONTIGUOUS),
             ),
+            HandleTy::Guard => Self::fmap_guard(addr_space, map),
             HandleTy::PhysBorrow => Self::physmap(map.offset, map.size, map.flags, mem_ty),
+            HandleTy::HugePage => Self::fmap_hugepages(
+                addr_space,
+                map,
+                if flags.contains(HandleFlags::HUGE_1G) {
+                    HugePageSize::Size1G
+                } else {
+                    HugePageSize::Size2M
+                },
+            ),
+            HandleTy::Swap => Err(Error::new(EBADF)),
+        }
+    }
+    fn kread(&self, id: usize, buf: UserSliceWo, flags: u32, _stored_flags: u32) -> Result<usize> {
+        match handle_ty(id)? {
+            HandleTy::Swap => swap::read_request(buf, flags & O_NONBLOCK as u32 == 0),
+            _ => Err(Error::new(EBADF)),
+        }
+    }
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        match handle_ty(id)? {
+            HandleTy::Swap => swap::write_reply(buf),
+            _ => Err(Error::new(EBADF)),
+        }
+    }
+    fn ftruncate(&self, id: usize, len: usize) -> Result<()> {
+        match handle_ty(id)? {
+            HandleTy::Swap => swap::set_size(len),
+            _ => Err(Error::new(EBADF)),
         }
     }
+    fn fsize(&self, id: usize) -> Result<u64> {
+        match handle_ty(id)? {
+            HandleTy::Swap => swap::size(),
+            _ => Err(Error::new(ESPIPE)),
+        }
+    }
+    fn close(&self, id: usize) -> Result<()> {
+        if handle_ty(id)? == HandleTy::Swap {
+            swap::swap_off();
+        }
+        Ok(())
+    }
     fn kfstatvfs(&self, _file: usize, dst: UserSliceWo) -> Result<()> {
         let used = used_frames() as u64;
         let free = free_frames() as u64;
@@ -4,16 +4,22 @@ use crate::{
         self,
         context::{HardBlockedReason, SignalState},
         file::{FileDescriptor, InternalFlags},
-        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+        memory::{
+            handle_notify_files, AddrSpaceWrapper, Advice, Grant, MlockallFlags, PageSpan, Provider,
+        },
         process::{self, Process, ProcessId, ProcessInfo, ProcessStatus},
+        switch::{
+            SchedPolicy, DEFAULT_RR_QUANTUM, MIN_RR_QUANTUM, NICE_MAX, NICE_MIN, RT_PRIORITY_MAX,
+            RT_PRIORITY_MIN,
+        },
         Context, Status,
     },
-    memory::PAGE_SIZE,
+    memory::{oom, PAGE_SIZE},
     ptrace,
     scheme::{self, FileHandle, KernelScheme},
     syscall::{
         self,
-        data::{GrantDesc, Map, PtraceEvent, SenderInfo, SetSighandlerData, Stat},
+        data::{GrantDesc, Map, PtraceEvent, SenderInfo, SetSighandlerData, Stat, TimeSpec},
         error::*,
         flag::*,
         usercopy::{UserSliceRo, UserSliceWo},
@@ -39,6 +45,20 @@ use core::{
 use spin::RwLock;
 use spinning_top::RwSpinlock;
 
+/// Advise how a range of an address space will be used, given the range and one of the `MADV_*`
+/// values.
+const ADDRSPACE_OP_MADVISE: usize = 4;
+/// Lock a range of an address space, given the range and flags, which can be `MLOCK_ONFAULT`.
+const ADDRSPACE_OP_MLOCK: usize = 5;
+/// Unlock a range of an address space.
+const ADDRSPACE_OP_MUNLOCK: usize = 6;
+/// Lock all mappings of an address space, given the `MCL_*` flags.
+const ADDRSPACE_OP_MLOCKALL: usize = 7;
+/// Unlock all mappings of an address space.
+const ADDRSPACE_OP_MUNLOCKALL: usize = 8;
+
+const MLOCK_ONFAULT: usize = 1;
+
 fn read_from(dst: UserSliceWo, src: &[u8], offset: u64) -> Result<usize> {
     let avail_src = usize::try_from(offset)
         .ok()
@@ -85,6 +105,7 @@ fn try_stop_context<T>(
     let ret = callback(&mut *context);
 
     context.status = prev_status;
+    context::switch::enqueue(&mut context);
 
     ret
 }
@@ -110,6 +131,9 @@ enum ProcHandle {
     Attr {
         attr: Attr,
     },
+    OomScore,
+    OomScoreAdj,
+    Aslr,
 }
 #[derive(Clone)]
 enum ContextHandle {
@@ -148,9 +172,16 @@ enum ContextHandle {
     // TODO: Remove this once openat is implemented, or allow openat-via-dup via e.g. the top-level
     // directory.
     OpenViaDup,
+    Park,
     SchedAffinity,
+    SchedNice,
+    SchedPolicy,
+    SchedStats,
+    Tid,
 
     MmapMinAddr(Arc<AddrSpaceWrapper>),
+    Statm(Arc<AddrSpaceWrapper>),
+    Smaps(Arc<AddrSpaceWrapper>),
 }
 #[derive(Clone)]
 enum Handle {
@@ -174,7 +205,10 @@ impl Handle {
         matches!(
             self,
             Self::Process {
-                kind: ProcHandle::Trace { .. } | ProcHandle::SessionId,
+                kind: ProcHandle::Trace { .. }
+                    | ProcHandle::SessionId
+                    | ProcHandle::OomScoreAdj
+                    | ProcHandle::Aslr,
                 ..
             } | Self::Context {
                 kind: ContextHandle::Regs(_)
@@ -221,7 +255,7 @@ impl Handle {
                 .iter()
                 .filter_map(|t| t.upgrade())
             {
-                thread.write().status = context::Status::Runnable;
+                thread.write().set_runnable();
             }
         }
         Some(())
@@ -294,6 +328,7 @@ impl<const FULL: bool> ProcScheme<FULL> {
             "sighandler" => (ContextHandle::Sighandler, false),
             "start" => (ContextHandle::Start, false),
             "open_via_dup" => (ContextHandle::OpenViaDup, false),
+            "park" => (ContextHandle::Park, false),
             "mmap-min-addr" => (
                 ContextHandle::MmapMinAddr(Arc::clone(
                     context
@@ -303,9 +338,31 @@ impl<const FULL: bool> ProcScheme<FULL> {
                 )),
                 false,
             ),
+            "statm" => (
+                ContextHandle::Statm(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                false,
+            ),
+            "smaps" => (
+                ContextHandle::Smaps(Arc::clone(
+                    context
+                        .read()
+                        .addr_space()
+                        .map_err(|_| Error::new(ENOENT))?,
+                )),
+                true,
+            ),
             "sched-affinity" => (ContextHandle::SchedAffinity, true),
+            "sched-nice" => (ContextHandle::SchedNice, false),
+            "sched-policy" => (ContextHandle::SchedPolicy, false),
+            "sched-stats" => (ContextHandle::SchedStats, false),
             "status" => (ContextHandle::Status, false),
             "signal" => (ContextHandle::Signal, false),
+            "tid" => (ContextHandle::Tid, false),
             _ => return Ok(None),
         }))
     }
@@ -344,6 +401,9 @@ impl<const FULL: bool> ProcScheme<FULL> {
             "uid" => (ProcHandle::Attr { attr: Attr::Uid }, true),
             "gid" => (ProcHandle::Attr { attr: Attr::Gid }, true),
             "session_id" => (ProcHandle::SessionId, true),
+            "oom_score" => (ProcHandle::OomScore, true),
+            "oom_score_adj" => (ProcHandle::OomScoreAdj, true),
+            "aslr" => (ProcHandle::Aslr, true),
             _ => return Ok(None),
         }))
     }
@@ -567,7 +627,11 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 ));
             }
             Handle::Context {
-                kind: ContextHandle::AddrSpace { addrspace } | ContextHandle::MmapMinAddr(addrspace),
+                kind:
+                    ContextHandle::AddrSpace { addrspace }
+                    | ContextHandle::MmapMinAddr(addrspace)
+                    | ContextHandle::Statm(addrspace)
+                    | ContextHandle::Smaps(addrspace),
                 ..
             } => drop(addrspace),
 
@@ -593,8 +657,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     let Some(context) = thread.upgrade() else {
                         continue;
                     };
-                    let mut context = context.write();
-                    context.status = context::Status::Runnable;
+                    context.write().set_runnable();
                 }
             }
             _ => (),
@@ -738,6 +801,9 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     ProcHandle::Trace { .. } => "trace",
                     ProcHandle::Static { ty, .. } => ty,
                     ProcHandle::SessionId => "session_id",
+                    ProcHandle::OomScore => "oom_score",
+                    ProcHandle::OomScoreAdj => "oom_score_adj",
+                    ProcHandle::Aslr => "aslr",
                 },
             ),
             Handle::Context { context, kind } => format!(
@@ -755,7 +821,14 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     ContextHandle::CurrentFiletable => "current-filetable",
                     ContextHandle::OpenViaDup => "open-via-dup",
                     ContextHandle::MmapMinAddr(_) => "mmap-min-addr",
+                    ContextHandle::Statm(_) => "statm",
+                    ContextHandle::Smaps(_) => "smaps",
+                    ContextHandle::Park => "park",
                     ContextHandle::SchedAffinity => "sched-affinity",
+                    ContextHandle::SchedNice => "sched-nice",
+                    ContextHandle::SchedPolicy => "sched-policy",
+                    ContextHandle::SchedStats => "sched-stats",
+                    ContextHandle::Tid => "tid",
 
                     _ => return Err(Error::new(EOPNOTSUPP)),
                 }
@@ -869,13 +942,22 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 let kind = match buf {
                     // TODO: Better way to obtain new empty address spaces, perhaps using SYS_OPEN. But
                     // in that case, what scheme?
-                    b"empty" => ContextHandle::AddrSpace {
-                        addrspace: AddrSpaceWrapper::new()?,
-                    },
+                    b"empty" => {
+                        // Empty address spaces are created for exec, so they follow the ASLR
+                        // setting of the calling process, which can be changed between fork and
+                        // exec for deterministic debugging.
+                        let addrspace = AddrSpaceWrapper::new()?;
+                        addrspace
+                            .acquire_write()
+                            .set_aslr(process::current()?.read().aslr);
+                        ContextHandle::AddrSpace { addrspace }
+                    }
                     b"exclusive" => ContextHandle::AddrSpace {
                         addrspace: addrspace.try_clone()?,
                     },
                     b"mmap-min-addr" => ContextHandle::MmapMinAddr(Arc::clone(addrspace)),
+                    b"statm" => ContextHandle::Statm(Arc::clone(addrspace)),
+                    b"smaps" => ContextHandle::Smaps(Arc::clone(addrspace)),
 
                     _ if buf.starts_with(GRANT_FD_PREFIX) => {
                         let string = core::str::from_utf8(&buf[GRANT_FD_PREFIX.len()..])
@@ -922,7 +1004,21 @@ extern "C" fn clone_handler() {
 
 fn new_thread() -> Result<Arc<RwSpinlock<Context>>> {
     let current_process = process::current()?;
-    context::spawn(true, current_process, clone_handler)
+    let new_context = context::spawn(true, current_process, clone_handler)?;
+    inherit_sched(&new_context);
+    Ok(new_context)
+}
+
+/// Let a new thread or child start with the same scheduling parameters as its creator.
+fn inherit_sched(new_context: &Arc<RwSpinlock<Context>>) {
+    let current = context::current();
+    let current = current.read();
+    let mut new_context = new_context.write();
+
+    new_context.sched_nice = current.sched_nice;
+    new_context.sched_policy = current.sched_policy;
+    new_context.sched_rt_priority = current.sched_rt_priority;
+    new_context.sched_rr_quantum = current.sched_rr_quantum;
 }
 
 fn new_child() -> Result<Arc<RwSpinlock<Context>>> {
@@ -935,6 +1031,7 @@ fn new_child() -> Result<Arc<RwSpinlock<Context>>> {
         })?;
         context::spawn(true, new_process, clone_handler)?
     };
+    inherit_sched(&new_context);
 
     if ptrace::send_event(crate::syscall::ptrace_event!(
         PTRACE_EVENT_CLONE,
@@ -998,7 +1095,7 @@ impl Handle {
 impl ProcHandle {
     fn kwriteoff(self, process: Arc<RwLock<Process>>, buf: UserSliceRo) -> Result<usize> {
         match self {
-            Self::Static { .. } => Err(Error::new(EBADF)),
+            Self::Static { .. } | Self::OomScore => Err(Error::new(EBADF)),
             Self::Trace { pid, .. } => {
                 let op = buf.read_u64()?;
                 let op = PtraceFlags::from_bits(op).ok_or(Error::new(EINVAL))?;
@@ -1066,6 +1163,45 @@ impl ProcHandle {
                 }
                 Ok(buf.len())
             }
+            Self::OomScoreAdj => {
+                let mut str_buf = [0_u8; 8];
+                let bytes_copied = buf.copy_common_bytes_to_slice(&mut str_buf)?;
+
+                let adj = core::str::from_utf8(&str_buf[..bytes_copied])
+                    .map_err(|_| Error::new(EINVAL))?
+                    .trim()
+                    .parse::<i16>()
+                    .map_err(|_| Error::new(EINVAL))?;
+                if !(oom::OOM_SCORE_ADJ_MIN..=oom::OOM_SCORE_ADJ_MAX).contains(&adj) {
+                    return Err(Error::new(EINVAL));
+                }
+
+                // Only root may make a process less likely to be killed.
+                let is_root = process::current()?.read().euid == 0;
+                let mut process = process.write();
+                if adj < process.oom_score_adj && !is_root {
+                    return Err(Error::new(EACCES));
+                }
+                process.oom_score_adj = adj;
+
+                Ok(buf.len())
+            }
+            Self::Aslr => {
+                let mut str_buf = [0_u8; 2];
+                let bytes_copied = buf.copy_common_bytes_to_slice(&mut str_buf)?;
+
+                let aslr = match core::str::from_utf8(&str_buf[..bytes_copied])
+                    .map_err(|_| Error::new(EINVAL))?
+                    .trim()
+                {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(Error::new(EINVAL)),
+                };
+                process.write().aslr = aslr;
+
+                Ok(buf.len())
+            }
             Self::SessionId => {
                 let session_id = ProcessId::new(buf.read_usize()?);
 
@@ -1169,6 +1305,18 @@ impl ProcHandle {
 
                 read_from(buf, &src_buf, offset)
             }
+            Self::OomScore => {
+                let points = oom::score(&process).unwrap_or(0);
+                read_from(buf, points.to_string().as_bytes(), offset)
+            }
+            Self::OomScoreAdj => {
+                let adj = process.read().oom_score_adj;
+                read_from(buf, adj.to_string().as_bytes(), offset)
+            }
+            Self::Aslr => {
+                let aslr = process.read().aslr;
+                read_from(buf, if aslr { b"1" } else { b"0" }, offset)
+            }
         }
     }
 }
@@ -1228,6 +1376,41 @@ impl ContextHandle {
 
                         addrspace.mprotect(PageSpan::new(page, page_count), flags)?;
                     }
+                    ADDRSPACE_OP_MADVISE => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        let advice = Advice::from_raw(next()??).ok_or(Error::new(EINVAL))?;
+
+                        addrspace.madvise(PageSpan::new(page, page_count), advice)?;
+                    }
+                    ADDRSPACE_OP_MLOCK => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+                        let flags = next()??;
+                        if flags & !MLOCK_ONFAULT != 0 {
+                            return Err(Error::new(EINVAL));
+                        }
+                        let uid = process::current()?.read().euid;
+
+                        addrspace.mlock(
+                            PageSpan::new(page, page_count),
+                            uid,
+                            flags & MLOCK_ONFAULT != 0,
+                        )?;
+                    }
+                    ADDRSPACE_OP_MUNLOCK => {
+                        let (page, page_count) =
+                            crate::syscall::validate_region(next()??, next()??)?;
+
+                        addrspace.munlock(PageSpan::new(page, page_count))?;
+                    }
+                    ADDRSPACE_OP_MLOCKALL => {
+                        let flags = MlockallFlags::from_bits(next()??).ok_or(Error::new(EINVAL))?;
+                        let uid = process::current()?.read().euid;
+
+                        addrspace.mlockall(flags, uid)?;
+                    }
+                    ADDRSPACE_OP_MUNLOCKALL => addrspace.munlockall()?,
                     _ => return Err(Error::new(EINVAL)),
                 }
                 Ok(words_read * mem::size_of::<usize>())
@@ -1335,15 +1518,18 @@ impl ContextHandle {
 
                 Ok(mem::size_of::<SetSighandlerData>())
             }
-            ContextHandle::Start => match context.write().status {
-                ref mut status @ Status::HardBlocked {
-                    reason: HardBlockedReason::NotYetStarted,
-                } => {
-                    *status = Status::Runnable;
-                    Ok(buf.len())
+            ContextHandle::Start => {
+                let mut context = context.write();
+                match context.status {
+                    Status::HardBlocked {
+                        reason: HardBlockedReason::NotYetStarted,
+                    } => {
+                        context.set_runnable();
+                        Ok(buf.len())
+                    }
+                    _ => return Err(Error::new(EINVAL)),
                 }
-                _ => return Err(Error::new(EINVAL)),
-            },
+            }
             ContextHandle::Filetable { .. } | ContextHandle::NewFiletable { .. } => {
                 Err(Error::new(EBADF))
             }
@@ -1436,12 +1622,93 @@ impl ContextHandle {
 
                 Ok(mem::size_of_val(&mask))
             }
+            Self::SchedNice => {
+                let nice = i8::try_from(buf.read_usize()? as isize)
+                    .ok()
+                    .filter(|nice| (NICE_MIN..=NICE_MAX).contains(nice))
+                    .ok_or(Error::new(EINVAL))?;
+                let is_root = process::current()?.read().euid == 0;
+
+                let reschedule = {
+                    let mut context = context.write();
+                    if nice < context.sched_nice && !is_root {
+                        // Only root may raise the priority of a context
+                        return Err(Error::new(EPERM));
+                    }
+                    context.sched_nice = nice;
+                    context.reschedule()
+                };
+                if reschedule {
+                    context::switch();
+                }
+
+                Ok(mem::size_of::<usize>())
+            }
+            Self::SchedPolicy => {
+                let mut args = buf.usizes();
+                let mut words_read = 0;
+                let mut next = || {
+                    words_read += 1;
+                    args.next()
+                };
+
+                let policy = next().ok_or(Error::new(EINVAL))??;
+                let policy = SchedPolicy::from_raw(policy).ok_or(Error::new(EINVAL))?;
+                let priority = next().ok_or(Error::new(EINVAL))??;
+                let quantum = match next().transpose()? {
+                    None | Some(0) => DEFAULT_RR_QUANTUM,
+                    Some(quantum) => (quantum as u128).max(MIN_RR_QUANTUM),
+                };
+
+                let priority = if policy.is_realtime() {
+                    u8::try_from(priority)
+                        .ok()
+                        .filter(|p| (RT_PRIORITY_MIN..=RT_PRIORITY_MAX).contains(p))
+                        .ok_or(Error::new(EINVAL))?
+                } else if priority == 0 {
+                    0
+                } else {
+                    return Err(Error::new(EINVAL));
+                };
+                if policy.is_realtime() && process::current()?.read().euid != 0 {
+                    // Real-time contexts can starve everything else, so only root may create them
+                    return Err(Error::new(EPERM));
+                }
+
+                let reschedule = {
+                    let mut context = context.write();
+                    context.sched_policy = policy;
+                    context.sched_rt_priority = priority;
+                    context.sched_rr_quantum = quantum;
+                    context.reschedule()
+                };
+                if reschedule {
+                    context::switch();
+                }
+
+                Ok(words_read * mem::size_of::<usize>())
+            }
+            // Writing to the handle of the current context parks it, optionally until the
+            // absolute time given as a `TimeSpec`, and writing to that of another unparks it.
+            ContextHandle::Park => {
+                if context::is_current(&context) {
+                    let timeout = if buf.is_empty() {
+                        None
+                    } else {
+                        let TimeSpec { tv_sec, tv_nsec } = unsafe { buf.read_exact::<TimeSpec>()? };
+                        Some(tv_sec as u128 * crate::time::NANOS_PER_SEC + tv_nsec as u128)
+                    };
+                    syscall::park::park(timeout)?;
+                } else {
+                    syscall::park::unpark(&context);
+                }
+                Ok(buf.len())
+            }
             ContextHandle::Status => {
                 let mut args = buf.usizes();
 
                 let user_data = args.next().ok_or(Error::new(EINVAL))??;
                 if user_data != usize::MAX {
-                    // TODO: lwp_park/lwp_unpark?
                     return Err(Error::new(EOPNOTSUPP));
                 }
                 let is_current = context::is_current(&context);
@@ -1513,6 +1780,10 @@ impl ContextHandle {
                 }
             }
             Self::OpenViaDup
+            | Self::SchedStats
+            | Self::Tid
+            | Self::Statm(_)
+            | Self::Smaps(_)
             | Self::AwaitingAddrSpaceChange { .. }
             | Self::AwaitingFiletableChange { .. } => Err(Error::new(EBADF)),
         }
@@ -1616,11 +1887,66 @@ impl ContextHandle {
                 buf.write_usize(addrspace.acquire_read().mmap_min)?;
                 Ok(mem::size_of::<usize>())
             }
+            ContextHandle::Statm(ref addrspace) => {
+                let usage = addrspace.acquire_read().usage();
+                write_usizes(
+                    buf,
+                    &[
+                        usage.size,
+                        usage.resident,
+                        usage.proportional,
+                        usage.shared,
+                        usage.private_clean,
+                        usage.private_dirty,
+                        usage.swapped,
+                        usage.unfaulted,
+                    ],
+                )
+            }
+            ContextHandle::Smaps(ref addrspace) => {
+                read_from(buf, format_smaps(addrspace).as_bytes(), offset)
+            }
             ContextHandle::SchedAffinity => {
                 let mask = context.read().sched_affinity.to_raw();
 
                 buf.copy_exactly(crate::cpu_set::mask_as_bytes(&mask))?;
                 Ok(mem::size_of_val(&mask))
+            }
+            ContextHandle::SchedNice => {
+                buf.write_usize(context.read().sched_nice as isize as usize)?;
+                Ok(mem::size_of::<usize>())
+            }
+            ContextHandle::SchedPolicy => {
+                let words = {
+                    let context = context.read();
+                    [
+                        context.sched_policy.to_raw(),
+                        usize::from(context.sched_rt_priority),
+                        context.sched_rr_quantum as usize,
+                    ]
+                };
+                write_usizes(buf, &words)
+            }
+            ContextHandle::SchedStats => {
+                let words = {
+                    let context = context.read();
+                    let stats = &context.sched_stats;
+                    [
+                        stats.voluntary_switches as usize,
+                        stats.involuntary_switches as usize,
+                        stats.wait_time as usize,
+                        stats.blocked_time as usize,
+                        context.cpu_time as usize,
+                        context
+                            .cpu_id
+                            .map_or(usize::MAX, |cpu_id| cpu_id.get() as usize),
+                    ]
+                };
+                write_usizes(buf, &words)
+            }
+            ContextHandle::Tid => {
+                let tid = context.read().tid;
+                write_usizes(buf, &[tid])
             } // TODO: Replace write() with SYS_DUP_FORWARD.
 
             // TODO: Find a better way to switch address spaces, since they also require switching
@@ -1631,6 +1957,67 @@ impl ContextHandle {
     }
 }
 
+/// List the grants of an address space with the memory usage of each, in KiB.
+fn format_smaps(addrspace: &AddrSpaceWrapper) -> String {
+    use core::fmt::Write;
+
+    let addrspace = addrspace.acquire_read();
+    let mut string = String::new();
+    for (base, info) in addrspace.grants.iter() {
+        let usage = addrspace.grant_usage(base, info);
+        let flags = info.flags();
+        let kind = match info.provider {
+            Provider::Allocated {
+                cow_file_ref: Some(_),
+                ..
+            } => "file-private",
+            Provider::Allocated {
+                phys_contiguous: true,
+                ..
+            } => "contiguous",
+            Provider::Allocated { .. } => "anonymous",
+            Provider::AllocatedShared { .. } => "shared",
+            Provider::PhysBorrowed { .. } => "physmap",
+            Provider::External { .. } => "borrowed",
+            Provider::HugePool { .. } => "hugepage",
+            Provider::FmapBorrowed { .. } => "file-shared",
+            Provider::Guard => "guard",
+        };
+        let _ = writeln!(
+            string,
+            "{:x}-{:x} r{}{} {}",
+            base.start_address().data(),
+            base.next_by(info.page_count()).start_address().data(),
+            if flags.has_write() { 'w' } else { '-' },
+            if flags.has_execute() { 'x' } else { '-' },
+            kind,
+        );
+        for (name, bytes) in [
+            ("Size", usage.size),
+            ("Rss", usage.resident),
+            ("Pss", usage.proportional),
+            ("Shared", usage.shared),
+            ("Private_Clean", usage.private_clean),
+            ("Private_Dirty", usage.private_dirty),
+            ("Swap", usage.swapped),
+            ("Unfaulted", usage.unfaulted),
+        ] {
+            let _ = writeln!(string, "{:<16}{} KiB", format!("{}:", name), bytes / 1024);
+        }
+    }
+    string
+}
+
+/// Write as many of `words` as fit in `buf`, returning the number of bytes written.
+fn write_usizes(buf: UserSliceWo, words: &[usize]) -> Result<usize> {
+    let mut bytes_read = 0;
+    for (dst, word) in buf.in_exact_chunks(mem::size_of::<usize>()).zip(words) {
+        dst.write_usize(*word)?;
+        bytes_read += mem::size_of::<usize>();
+    }
+    Ok(bytes_read)
+}
+
 fn write_env_regs(context: Arc<RwSpinlock<Context>>, regs: EnvRegisters) -> Result<()> {
     if context::is_current(&context) {
         context::current().write().write_current_env_regs(regs)
@@ -3,11 +3,27 @@ use alloc::{
     vec::Vec,
 };
 
-use crate::{context, paging::PAGE_SIZE, syscall::error::Result};
+use crate::{
+    context::{self, switch::SchedPolicy},
+    paging::PAGE_SIZE,
+    syscall::error::Result,
+};
+
+fn format_time(time: u128) -> String {
+    let time_s = time / crate::time::NANOS_PER_SEC;
+    let time_ns = time % crate::time::NANOS_PER_SEC;
+    format!(
+        "{:02}:{:02}:{:02}.{:02}",
+        time_s / 3600,
+        (time_s / 60) % 60,
+        time_s % 60,
+        time_ns / 10_000_000
+    )
+}
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!(
-        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
+        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<4}{:<4}{:<4}{:<12}{:<8}{:<8}{:<12}{:<12}{:<8}{}\n",
         "PID",
         "PGID",
         "PPID",
@@ -21,7 +37,14 @@ pub fn resource() -> Result<Vec<u8>> {
         "STAT",
         "CPU",
         "AFFINITY",
+        "CLS",
+        "PRI",
+        "NI",
         "TIME",
+        "VCSW",
+        "IVCSW",
+        "WAIT",
+        "BLOCKED",
         "MEM",
         "NAME"
     );
@@ -68,15 +91,8 @@ pub fn resource() -> Result<Vec<u8>> {
             };
             let affinity = context.sched_affinity.to_string();
 
-            let cpu_time_s = context.cpu_time / crate::time::NANOS_PER_SEC;
-            let cpu_time_ns = context.cpu_time % crate::time::NANOS_PER_SEC;
-            let cpu_time_string = format!(
-                "{:02}:{:02}:{:02}.{:02}",
-                cpu_time_s / 3600,
-                (cpu_time_s / 60) % 60,
-                cpu_time_s % 60,
-                cpu_time_ns / 10_000_000
-            );
+            let cpu_time_string = format_time(context.cpu_time);
+            let stats = &context.sched_stats;
 
             let mut memory = context.kfx.len();
             if let Some(ref kstack) = context.kstack {
@@ -85,7 +101,11 @@ pub fn resource() -> Result<Vec<u8>> {
             if let Ok(addr_space) = context.addr_space() {
                 for (_base, info) in addr_space.acquire_read().grants.iter() {
                     // TODO: method
-                    if matches!(info.provider, context::memory::Provider::Allocated { .. }) {
+                    if matches!(
+                        info.provider,
+                        context::memory::Provider::Allocated { .. }
+                            | context::memory::Provider::HugePool { .. }
+                    ) {
                         memory += info.page_count() * PAGE_SIZE;
                     }
                 }
@@ -103,7 +123,7 @@ pub fn resource() -> Result<Vec<u8>> {
             let process = context.process.read();
 
             string.push_str(&format!(
-                "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
+                "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<4}{:<4}{:<4}{:<12}{:<8}{:<8}{:<12}{:<12}{:<8}{}\n",
                 context.pid.get(),
                 process.pgid.get(),
                 process.ppid.get(),
@@ -117,7 +137,18 @@ pub fn resource() -> Result<Vec<u8>> {
                 stat_string,
                 cpu_string,
                 affinity,
+                match context.sched_policy {
+                    SchedPolicy::Other => "TS",
+                    SchedPolicy::Fifo => "FF",
+                    SchedPolicy::RoundRobin => "RR",
+                },
+                context.sched_priority(),
+                context.sched_nice,
                 cpu_time_string,
+                stats.voluntary_switches,
+                stats.involuntary_switches,
+                format_time(stats.wait_time),
+                format_time(stats.blocked_time),
                 memory_string,
                 context.name,
             ));
@@ -0,0 +1,78 @@
+use alloc::vec::Vec;
+use core::{fmt::Write, str};
+
+use crate::{
+    context::{
+        process::ProcessId,
+        quota::{self, QuotaGroup},
+    },
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// CPU quotas of process groups and sessions, and their usage, in microseconds.
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!(
+        "{:<8}{:<8}{:<12}{:<12}{:<12}{:<16}{}\n",
+        "GROUP", "ID", "QUOTA", "PERIOD", "USED", "TOTAL", "THROTTLED"
+    );
+
+    quota::for_each(|group, quota| {
+        let (kind, id) = match group {
+            QuotaGroup::ProcessGroup(pgid) => ("pgid", pgid),
+            QuotaGroup::Session(session_id) => ("sid", session_id),
+        };
+        let _ = writeln!(
+            string,
+            "{:<8}{:<8}{:<12}{:<12}{:<12}{:<16}{}",
+            kind,
+            id.get(),
+            quota.quota / 1000,
+            quota.period / 1000,
+            quota.used / 1000,
+            quota.total_used / 1000,
+            quota.throttled_periods,
+        );
+    });
+
+    Ok(string.into_bytes())
+}
+
+/// Set quotas with one `<pgid|sid> <id> <quota> <period>` line per group, in microseconds. A quota
+/// of `max` removes the limit. Nothing is changed unless every line is valid.
+pub fn write(buf: &[u8]) -> Result<()> {
+    let string = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    let mut changes = Vec::new();
+    for line in string.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let mut next = || words.next().ok_or(Error::new(EINVAL));
+
+        let kind = next()?;
+        let id = ProcessId::new(next()?.parse().map_err(|_| Error::new(EINVAL))?);
+        let group = match kind {
+            "pgid" => QuotaGroup::ProcessGroup(id),
+            "sid" => QuotaGroup::Session(id),
+            _ => return Err(Error::new(EINVAL)),
+        };
+
+        let quota = match next()? {
+            "max" => None,
+            quota => {
+                let quota = quota.parse::<u128>().map_err(|_| Error::new(EINVAL))?;
+                let period = next()?.parse::<u128>().map_err(|_| Error::new(EINVAL))?;
+                if quota == 0 || period == 0 {
+                    return Err(Error::new(EINVAL));
+                }
+                // Microseconds are converted to nanoseconds.
+                let to_ns = |us: u128| us.checked_mul(1000).ok_or(Error::new(EINVAL));
+                Some((to_ns(quota)?, to_ns(period)?))
+            }
+        };
+        changes.push((group, quota));
+    }
+
+    for (group, quota) in changes {
+        quota::set(group, quota);
+    }
+    Ok(())
+}
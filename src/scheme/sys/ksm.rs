@@ -0,0 +1,47 @@
+use alloc::vec::Vec;
+use core::str;
+
+use crate::{
+    memory::ksm,
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// Settings and statistics of kernel same-page merging.
+pub fn resource() -> Result<Vec<u8>> {
+    let stats = ksm::stats();
+    Ok(format!(
+        "{:<16}{}\n{:<16}{}\n{:<16}{}\n{:<16}{}\n{:<16}{}\n",
+        "PAGES_TO_SCAN",
+        stats.pages_to_scan,
+        "SLEEP_MS",
+        stats.sleep_ms,
+        "PAGES_SHARED",
+        stats.pages_shared,
+        "PAGES_SHARING",
+        stats.pages_sharing,
+        "FULL_SCANS",
+        stats.full_scans,
+    )
+    .into_bytes())
+}
+
+/// Change settings with one `<pages_to_scan|sleep_ms> <value>` line per setting. Setting
+/// `pages_to_scan` to 0 stops scanning.
+pub fn write(buf: &[u8]) -> Result<()> {
+    let string = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    for line in string.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let mut next = || words.next().ok_or(Error::new(EINVAL));
+
+        let setting = next()?;
+        let value = next()?.parse::<usize>().map_err(|_| Error::new(EINVAL))?;
+        match setting {
+            "pages_to_scan" => ksm::set_pages_to_scan(value),
+            "sleep_ms" => ksm::set_sleep_ms(value),
+            _ => return Err(Error::new(EINVAL)),
+        }
+    }
+
+    Ok(())
+}
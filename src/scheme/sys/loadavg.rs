@@ -0,0 +1,28 @@
+use alloc::vec::Vec;
+
+use crate::{
+    context::{
+        self,
+        load::{self, LOAD_SHIFT},
+    },
+    syscall::error::Result,
+};
+
+/// The 1, 5 and 15 minute load averages, followed by the number of running or runnable contexts
+/// and the total number of contexts.
+pub fn resource() -> Result<Vec<u8>> {
+    let [one, five, fifteen] = load::load_avg().map(|avg| {
+        let frac = ((avg & ((1 << LOAD_SHIFT) - 1)) * 100) >> LOAD_SHIFT;
+        format!("{}.{:02}", avg >> LOAD_SHIFT, frac)
+    });
+
+    Ok(format!(
+        "{} {} {} {}/{}\n",
+        one,
+        five,
+        fifteen,
+        load::nr_active(),
+        context::contexts().len()
+    )
+    .into_bytes())
+}
@@ -0,0 +1,73 @@
+use alloc::vec::Vec;
+use core::{fmt::Write, str};
+
+use crate::{
+    context::{process::ProcessId, quota::QuotaGroup},
+    memory::{limit, PAGE_SIZE},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// Memory limits of process groups and sessions, and their usage, in KiB.
+pub fn resource() -> Result<Vec<u8>> {
+    let kib = |pages: usize| pages * PAGE_SIZE / 1024;
+
+    let mut string = format!(
+        "{:<8}{:<8}{:<12}{:<12}{:<12}{}\n",
+        "GROUP", "ID", "LIMIT", "USAGE", "MAX", "FAILURES"
+    );
+    for limit in limit::all() {
+        let (kind, id) = match limit.group {
+            QuotaGroup::ProcessGroup(pgid) => ("pgid", pgid),
+            QuotaGroup::Session(session_id) => ("sid", session_id),
+        };
+        let _ = writeln!(
+            string,
+            "{:<8}{:<8}{:<12}{:<12}{:<12}{}",
+            kind,
+            id.get(),
+            kib(limit.limit()),
+            kib(limit.usage()),
+            kib(limit.max_usage()),
+            limit.failures(),
+        );
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Set limits with one `<pgid|sid> <id> <limit>` line per group, in KiB. A limit of `max` removes
+/// it. Nothing is changed unless every line is valid.
+pub fn write(buf: &[u8]) -> Result<()> {
+    let string = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    let mut changes = Vec::new();
+    for line in string.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let mut next = || words.next().ok_or(Error::new(EINVAL));
+
+        let kind = next()?;
+        let id = ProcessId::new(next()?.parse().map_err(|_| Error::new(EINVAL))?);
+        let group = match kind {
+            "pgid" => QuotaGroup::ProcessGroup(id),
+            "sid" => QuotaGroup::Session(id),
+            _ => return Err(Error::new(EINVAL)),
+        };
+
+        let limit = match next()? {
+            "max" => None,
+            kib => {
+                let kib = kib.parse::<usize>().map_err(|_| Error::new(EINVAL))?;
+                Some(kib.saturating_mul(1024) / PAGE_SIZE)
+            }
+        };
+        if words.next().is_some() {
+            return Err(Error::new(EINVAL));
+        }
+        changes.push((group, limit));
+    }
+
+    for (group, limit) in changes {
+        limit::set(group, limit);
+    }
+    Ok(())
+}
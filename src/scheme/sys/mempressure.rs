@@ -0,0 +1,50 @@
+use alloc::vec::Vec;
+use core::{fmt::Write, str};
+
+use crate::{
+    memory::{pressure, PAGE_SIZE},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// The memory pressure level, the free memory, and the thresholds of each level, in KiB. Handles
+/// registered with `event:` are notified when the level changes, and read it again from the start.
+pub fn resource() -> Result<Vec<u8>> {
+    let kib = |pages: usize| pages * PAGE_SIZE / 1024;
+    let [low, medium, critical] = pressure::thresholds();
+
+    let mut string = format!("{:<12}{}\n", "LEVEL", pressure::level().name());
+    for (name, pages) in [
+        ("FREE", crate::memory::free_frames()),
+        ("LOW", low),
+        ("MEDIUM", medium),
+        ("CRITICAL", critical),
+    ] {
+        let _ = writeln!(string, "{:<12}{}", name, kib(pages));
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Set thresholds with one `<low|medium|critical> <KiB>` line per level, where each level must
+/// have a threshold no higher than the level below it.
+pub fn write(buf: &[u8]) -> Result<()> {
+    let string = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    let mut thresholds = pressure::thresholds();
+
+    for line in string.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let mut next = || words.next().ok_or(Error::new(EINVAL));
+
+        let level = next()?;
+        let kib = next()?.parse::<usize>().map_err(|_| Error::new(EINVAL))?;
+        let index = match level {
+            "low" => 0,
+            "medium" => 1,
+            "critical" => 2,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        thresholds[index] = kib.saturating_mul(1024) / PAGE_SIZE;
+    }
+
+    pressure::set_thresholds(thresholds)
+}
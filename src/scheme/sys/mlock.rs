@@ -0,0 +1,31 @@
+use alloc::vec::Vec;
+use core::{fmt::Write, str};
+
+use crate::{
+    memory::{mlock, PAGE_SIZE},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+/// The locked memory limit of users other than root, and how much each user has locked, in KiB.
+pub fn resource() -> Result<Vec<u8>> {
+    let kib = |pages: usize| pages * PAGE_SIZE / 1024;
+
+    let mut string = format!("{:<12}{}\n", "LIMIT", kib(mlock::limit()));
+    let _ = writeln!(string, "{:<12}{}", "UID", "LOCKED");
+    for (uid, pages) in mlock::usage() {
+        let _ = writeln!(string, "{:<12}{}", uid, kib(pages));
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Set the locked memory limit, in KiB.
+pub fn write(buf: &[u8]) -> Result<()> {
+    let kib = str::from_utf8(buf)
+        .ok()
+        .and_then(|string| string.trim().parse::<usize>().ok())
+        .ok_or(Error::new(EINVAL))?;
+
+    mlock::set_limit(kib.saturating_mul(1024) / PAGE_SIZE);
+    Ok(())
+}
@@ -13,34 +13,52 @@ use spin::RwLock;
 use crate::arch::interrupt;
 use crate::{
     context::file::InternalFlags,
+    event,
     syscall::{
         data::Stat,
-        error::{Error, Result, EBADF, ENOENT},
-        flag::{MODE_DIR, MODE_FILE},
-        usercopy::UserSliceWo,
+        error::{Error, Result, EACCES, EBADF, ENOENT},
+        flag::{EVENT_READ, MODE_DIR, MODE_FILE, O_ACCMODE, O_RDONLY},
+        usercopy::{UserSliceRo, UserSliceWo},
     },
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
 mod block;
 mod context;
 mod cpu;
+mod cpu_quota;
 mod exe;
 mod iostat;
 mod irq;
+mod ksm;
+mod loadavg;
 mod log;
+mod memory_limit;
+mod mempressure;
+mod mlock;
+mod sched_slice;
 mod scheme;
 mod scheme_num;
+mod stat;
+mod swap;
 mod syscall;
 mod uname;
 
 enum Handle {
     TopLevel,
-    Resource { path: &'static str, data: Vec<u8> },
+    Resource {
+        path: &'static str,
+        data: Vec<u8>,
+        write: Option<SysWriteFn>,
+    },
 }
 
 type SysFn = fn() -> Result<Vec<u8>>;
+type SysWriteFn = fn(&[u8]) -> Result<()>;
+
+/// Upper bound on the size of a single write to a writable resource.
+const MAX_WRITE_SIZE: usize = 4096;
 
 /// System information scheme
 pub struct SysScheme;
@@ -52,12 +70,21 @@ const FILES: &[(&'static str, SysFn)] = &[
     ("block", block::resource),
     ("context", context::resource),
     ("cpu", cpu::resource),
+    ("cpu_quota", cpu_quota::resource),
     ("exe", exe::resource),
     ("iostat", iostat::resource),
     ("irq", irq::resource),
+    ("ksm", ksm::resource),
+    ("loadavg", loadavg::resource),
     ("log", log::resource),
+    ("memory_limit", memory_limit::resource),
+    ("mempressure", mempressure::resource),
+    ("mlock", mlock::resource),
+    ("sched_slice", sched_slice::resource),
     ("scheme", scheme::resource),
     ("scheme_num", scheme_num::resource),
+    ("stat", stat::resource),
+    ("swap", swap::resource),
     ("syscall", syscall::resource),
     ("uname", uname::resource),
     ("env", || Ok(Vec::from(crate::init_env()))),
@@ -72,8 +99,48 @@ const FILES: &[(&'static str, SysFn)] = &[
     */
 ];
 
+/// Resources that root can also write to, in order to change kernel settings at runtime.
+const WRITABLE_FILES: &[(&'static str, SysWriteFn)] = &[
+    ("cpu_quota", cpu_quota::write),
+    ("ksm", ksm::write),
+    ("memory_limit", memory_limit::write),
+    ("mempressure", mempressure::write),
+    ("mlock", mlock::write),
+    ("sched_slice", sched_slice::write),
+];
+
+/// Resources that change while open, and notify handles registered with `event:` when they do.
+/// They are generated again whenever read from the start.
+const EVENT_FILES: &[&'static str] = &["mempressure"];
+
+/// Notify the open handles of the resource at `path` that it has changed.
+pub fn trigger_event(path: &str) {
+    for (id, handle) in HANDLES.read().iter() {
+        if matches!(handle, Handle::Resource { path: other, .. } if *other == path) {
+            event::trigger(GlobalSchemes::Sys.scheme_id(), *id, EVENT_READ);
+        }
+    }
+}
+
+/// Generate the resource of handle `id` again, if it can change while open.
+fn refresh(id: usize) -> Result<()> {
+    let path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+        Handle::Resource { path, .. } if EVENT_FILES.contains(path) => *path,
+        _ => return Ok(()),
+    };
+    let Some((_, resource)) = FILES.iter().find(|(name, _)| *name == path) else {
+        return Ok(());
+    };
+    let new_data = resource()?;
+
+    if let Some(Handle::Resource { data, .. }) = HANDLES.write().get_mut(&id) {
+        *data = new_data;
+    }
+    Ok(())
+}
+
 impl KernelScheme for SysScheme {
-    fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
         let path = path.trim_matches('/');
 
         if path.is_empty() {
@@ -85,6 +152,16 @@ impl KernelScheme for SysScheme {
             //Have to iterate to get the path without allocation
             for entry in FILES.iter() {
                 if &entry.0 == &path {
+                    let write = if flags & O_ACCMODE == O_RDONLY {
+                        None
+                    } else {
+                        let (_, write) = WRITABLE_FILES
+                            .iter()
+                            .find(|(name, _)| *name == path)
+                            .filter(|_| ctx.uid == 0)
+                            .ok_or(Error::new(EACCES))?;
+                        Some(*write)
+                    };
                     let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
                     let data = entry.1()?;
                     HANDLES.write().insert(
@@ -92,6 +169,7 @@ impl KernelScheme for SysScheme {
                         Handle::Resource {
                             path: entry.0,
                             data,
+                            write,
                         },
                     );
                     return Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED));
@@ -140,6 +218,9 @@ impl KernelScheme for SysScheme {
         let Ok(pos) = usize::try_from(pos) else {
             return Ok(0);
         };
+        if pos == 0 {
+            refresh(id)?;
+        }
 
         match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
             Handle::TopLevel => return Err(Error::new(EISDIR)),
@@ -150,6 +231,28 @@ impl KernelScheme for SysScheme {
             }
         }
     }
+    fn kwriteoff(
+        &self,
+        id: usize,
+        buffer: UserSliceRo,
+        _pos: u64,
+        _flags: u32,
+        _stored_flags: u32,
+    ) -> Result<usize> {
+        let write = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel => return Err(Error::new(EISDIR)),
+            Handle::Resource { write, .. } => write.ok_or(Error::new(EBADF))?,
+        };
+
+        let mut data = vec![0_u8; buffer.len().min(MAX_WRITE_SIZE)];
+        buffer
+            .limit(data.len())
+            .ok_or(Error::new(EIO))?
+            .copy_to_slice(&mut data)?;
+        write(&data)?;
+
+        Ok(data.len())
+    }
     fn getdents(
         &self,
         id: usize,
@@ -179,8 +282,12 @@ impl KernelScheme for SysScheme {
 
     fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
         let stat = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
-            Handle::Resource { data, .. } => Stat {
-                st_mode: 0o444 | MODE_FILE,
+            Handle::Resource { path, data, .. } => Stat {
+                st_mode: if WRITABLE_FILES.iter().any(|(name, _)| name == path) {
+                    0o644
+                } else {
+                    0o444
+                } | MODE_FILE,
                 st_uid: 0,
                 st_gid: 0,
                 st_size: data.len() as u64,
@@ -0,0 +1,94 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, str, sync::atomic::Ordering};
+
+use crate::{
+    context::switch::{MIN_TIME_SLICE, TIME_SLICE},
+    cpu_set::LogicalCpuId,
+    percpu,
+    syscall::error::{Error, Result, EINVAL, ENOENT},
+};
+
+/// Time slices in microseconds, first the defaults and then the values in effect on each CPU.
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<6}{:<12}{}\n", "CPU", "SLICE", "MIN_SLICE");
+
+    let _ = writeln!(
+        string,
+        "{:<6}{:<12}{}",
+        "*",
+        TIME_SLICE.load(Ordering::Relaxed) / 1000,
+        MIN_TIME_SLICE.load(Ordering::Relaxed) / 1000,
+    );
+    for block in (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter_map(percpu::get)
+    {
+        let (slice, min_slice) = block.switch_internals.time_slice_config();
+        let _ = writeln!(
+            string,
+            "{:<6}{:<12}{}",
+            block.cpu_id.get(),
+            slice / 1000,
+            min_slice / 1000
+        );
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Set the time slices, with one `<cpu> <slice> [min_slice]` line per change, in microseconds.
+/// Using `*` as the CPU changes the defaults, and a slice of 0 makes a CPU use the default again.
+/// Nothing is changed unless every line is valid.
+pub fn write(buf: &[u8]) -> Result<()> {
+    let string = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+
+    let mut changes = Vec::new();
+    for line in string.lines().filter(|line| !line.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let cpu = words.next().ok_or(Error::new(EINVAL))?;
+        let mut next_micros = || -> Result<Option<u64>> {
+            words
+                .next()
+                .map(|word| {
+                    word.parse::<u64>()
+                        .ok()
+                        .and_then(|micros| micros.checked_mul(1000))
+                        .ok_or(Error::new(EINVAL))
+                })
+                .transpose()
+        };
+        let slice = next_micros()?.ok_or(Error::new(EINVAL))?;
+        let min_slice = next_micros()?;
+        if slice != 0 && min_slice.is_some_and(|min_slice| min_slice > slice) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let (slice_cell, min_slice_cell) = if cpu == "*" {
+            if slice == 0 || min_slice == Some(0) {
+                return Err(Error::new(EINVAL));
+            }
+            (&TIME_SLICE, &MIN_TIME_SLICE)
+        } else {
+            let cpu_id = cpu
+                .parse::<u32>()
+                .ok()
+                .filter(|id| *id < crate::cpu_count())
+                .ok_or(Error::new(EINVAL))?;
+            let block = percpu::get(LogicalCpuId::new(cpu_id)).ok_or(Error::new(ENOENT))?;
+            (
+                &block.switch_internals.time_slice,
+                &block.switch_internals.min_time_slice,
+            )
+        };
+        changes.push((slice_cell, slice, min_slice_cell, min_slice));
+    }
+
+    for (slice_cell, slice, min_slice_cell, min_slice) in changes {
+        slice_cell.store(slice, Ordering::Relaxed);
+        if let Some(min_slice) = min_slice {
+            min_slice_cell.store(min_slice, Ordering::Relaxed);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use alloc::vec::Vec;
+use core::{fmt::Write, sync::atomic::Ordering};
+
+use crate::{cpu_set::LogicalCpuId, percpu, syscall::error::Result};
+
+/// Time spent by each CPU running contexts, idle and handling interrupts, in milliseconds, and the
+/// number of context switches.
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!(
+        "{:<6}{:<14}{:<14}{:<14}{}\n",
+        "CPU", "BUSY", "IDLE", "IRQ", "SWITCHES"
+    );
+
+    let now = crate::time::monotonic();
+    let mut total = [0; 4];
+    for block in (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter_map(percpu::get)
+    {
+        let stats = &block.switch_internals.cpu_stats;
+        let [busy, idle, irq] = stats.times(now);
+        let row = [busy, idle, irq, stats.switches.load(Ordering::Relaxed)];
+        for (total, value) in total.iter_mut().zip(row) {
+            *total += value;
+        }
+        let _ = writeln!(
+            string,
+            "{:<6}{:<14}{:<14}{:<14}{}",
+            block.cpu_id.get(),
+            busy / 1_000_000,
+            idle / 1_000_000,
+            irq / 1_000_000,
+            row[3],
+        );
+    }
+    let _ = writeln!(
+        string,
+        "{:<6}{:<14}{:<14}{:<14}{}",
+        "*",
+        total[0] / 1_000_000,
+        total[1] / 1_000_000,
+        total[2] / 1_000_000,
+        total[3],
+    );
+
+    Ok(string.into_bytes())
+}
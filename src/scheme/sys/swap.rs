@@ -0,0 +1,21 @@
+use alloc::vec::Vec;
+
+use crate::{
+    memory::{swap, PAGE_SIZE},
+    syscall::error::Result,
+};
+
+/// Size of the swap device and how much of it is used, in KiB.
+pub fn resource() -> Result<Vec<u8>> {
+    let (used, total) = swap::usage();
+    let kib = |slots: u64| slots * PAGE_SIZE as u64 / 1024;
+
+    Ok(format!(
+        "{:<12}{}\n{:<12}{}\n",
+        "SIZE",
+        "USED",
+        kib(total),
+        kib(used)
+    )
+    .into_bytes())
+}
@@ -69,7 +69,8 @@ enum State {
         canceling: bool,
     },
     Responded(Response),
-    Fmap(Weak<RwSpinlock<Context>>),
+    /// A page fault waiting for up to the given number of pages of a file-backed grant
+    Fmap(Weak<RwSpinlock<Context>>, usize),
     Placeholder,
 }
 
@@ -378,7 +379,7 @@ impl UserInner {
                     }
 
                     // invalid state
-                    old_state @ (State::Placeholder | State::Fmap(_)) => {
+                    old_state @ (State::Placeholder | State::Fmap(..)) => {
                         *o = old_state;
                         return Err(Error::new(EBADFD));
                     }
@@ -534,10 +535,7 @@ impl UserInner {
 
         let mut dst_space = dst_space_lock.acquire_write();
 
-        let free_span = dst_space
-            .grants
-            .find_free(dst_space.mmap_min, page_count)
-            .ok_or(Error::new(ENOMEM))?;
+        let free_span = dst_space.find_free(page_count).ok_or(Error::new(ENOMEM))?;
 
         let head = if !head_part_of_buf.is_empty() {
             // FIXME: Signal context can probably recursively use head/tail.
@@ -892,7 +890,8 @@ impl UserInner {
 
         let tag = self.next_id()?;
         let mut states = self.states.lock();
-        states[tag as usize] = State::Fmap(Arc::downgrade(&context::current()));
+        states[tag as usize] =
+            State::Fmap(Arc::downgrade(&context::current()), required_page_count);
 
         /*self.todo.send(Packet {
             id: packet_id,
@@ -995,10 +994,6 @@ impl UserInner {
                     return Err(Error::new(EINVAL));
                 }
 
-                if page_count != 1 {
-                    return Err(Error::new(EINVAL));
-                }
-
                 let context = {
                     let mut states = self.states.lock();
 
@@ -1013,7 +1008,15 @@ impl UserInner {
                                 *o = old_state;
                                 return Err(Error::new(EINVAL));
                             }
-                            State::Fmap(context) => {
+                            // The scheme may provide fewer pages than were requested, but always
+                            // at least the faulting one.
+                            State::Fmap(context, required_page_count)
+                                if page_count == 0 || page_count > required_page_count =>
+                            {
+                                *o = State::Fmap(context, required_page_count);
+                                return Err(Error::new(EINVAL));
+                            }
+                            State::Fmap(context, _) => {
                                 states.remove(tag as usize);
                                 context
                             }
@@ -1024,21 +1027,32 @@ impl UserInner {
 
                 let context = context.upgrade().ok_or(Error::new(ESRCH))?;
 
-                let (frame, _) = AddrSpace::current()?
-                    .acquire_read()
-                    .table
-                    .utable
-                    .translate(base_addr)
-                    .ok_or(Error::new(EFAULT))?;
+                // The frames will be shared with the faulting address space.
+                let addr_space = AddrSpace::current()?;
+                let span = PageSpan::new(Page::containing_address(base_addr), page_count);
+                addr_space.split_huge_pages(span)?;
+                let frames = {
+                    let guard = addr_space.acquire_read();
+                    span.pages()
+                        .map(|page| {
+                            guard
+                                .table
+                                .utable
+                                .translate(page.start_address())
+                                .map(|(phys, _)| Frame::containing(phys))
+                                .ok_or(Error::new(EFAULT))
+                        })
+                        .collect::<Result<Vec<_>>>()?
+                };
 
                 let mut context = context.write();
                 match context.status {
                     Status::HardBlocked {
                         reason: HardBlockedReason::AwaitingMmap { .. },
-                    } => context.status = Status::Runnable,
+                    } => context.set_runnable(),
                     _ => (),
                 }
-                context.fmap_ret = Some(Frame::containing(frame));
+                context.fmap_ret = Some(frames);
             }
             ParsedCqe::TriggerFevent { number, flags } => {
                 event::trigger(self.scheme_id, number, flags)
@@ -1056,7 +1070,7 @@ impl UserInner {
                 // invalid state
                 State::Placeholder => return Err(Error::new(EBADFD)),
                 // invalid scheme to kernel call
-                old_state @ (State::Responded(_) | State::Fmap(_)) => {
+                old_state @ (State::Responded(_) | State::Fmap(..)) => {
                     *o = old_state;
                     return Err(Error::new(EINVAL));
                 }
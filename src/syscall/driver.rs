@@ -2,6 +2,7 @@ use alloc::sync::Arc;
 
 use crate::{
     context::{self, process},
+    memory::huge,
     paging::VirtualAddress,
     syscall::error::{Error, Result, EFAULT, EPERM},
 };
@@ -34,11 +35,10 @@ pub fn virttophys(virtual_address: usize) -> Result<usize> {
     let addr_space = Arc::clone(context::current().read().addr_space()?);
     let addr_space = addr_space.acquire_read();
 
-    match addr_space
-        .table
-        .utable
-        .translate(VirtualAddress::new(virtual_address))
-    {
+    match huge::translate(
+        &addr_space.table.utable,
+        VirtualAddress::new(virtual_address),
+    ) {
         Some((physical_address, _)) => Ok(physical_address.data()),
         None => Err(Error::new(EFAULT)),
     }
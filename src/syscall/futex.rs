@@ -3,57 +3,214 @@
 //!
 //! For more information about futexes, please read [this](https://eli.thegreenplace.net/2018/basics-of-futexes/) blog post, and the [futex(2)](http://man7.org/linux/man-pages/man2/futex.2.html) man page
 use alloc::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     sync::{Arc, Weak},
+    vec::Vec,
 };
 use core::sync::atomic::{AtomicU32, Ordering};
 use rmm::Arch;
-use spin::RwLock;
+use spin::{Mutex, MutexGuard};
 use spinning_top::RwSpinlock;
 use syscall::EINTR;
 
 use crate::{
     context::{
         self,
-        memory::{AddrSpace, AddrSpaceWrapper},
-        Context,
+        memory::{try_correcting_page_tables, AccessMode, AddrSpace, AddrSpaceWrapper},
+        switch, Context,
     },
-    memory::PhysicalAddress,
+    memory::{get_page_info, huge, the_zeroed_frame, Frame, PageInfo, PhysicalAddress, RefCount},
     paging::{Page, VirtualAddress},
     time,
 };
 
 use crate::syscall::{
     data::TimeSpec,
-    error::{Error, Result, EAGAIN, EFAULT, EINVAL, ETIMEDOUT},
-    flag::{FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
+    error::{
+        Error, Result, EAGAIN, EDEADLK, EFAULT, EINVAL, EOVERFLOW, EOWNERDEAD, EPERM, ETIMEDOUT,
+    },
+    flag::{FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
 };
 
 use super::usercopy::UserSlice;
 
+// TODO: Move these to redox_syscall. The values are the same as on Linux.
+pub const FUTEX_CMP_REQUEUE: usize = 4;
+pub const FUTEX_LOCK_PI: usize = 6;
+pub const FUTEX_UNLOCK_PI: usize = 7;
+pub const FUTEX_TRYLOCK_PI: usize = 8;
+pub const FUTEX_WAIT_BITSET: usize = 9;
+pub const FUTEX_WAKE_BITSET: usize = 10;
+/// Wait on several futexes at once, returning the index of the one that was woken up. The
+/// value is the same as for the `FUTEX_WAIT_MULTIPLE` operation that preceded Linux's
+/// `futex_waitv`.
+pub const FUTEX_WAIT_MULTIPLE: usize = 31;
+/// Can be combined with any operation to skip checking whether the futex is in memory shared
+/// with other address spaces.
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+/// Set in the word of a PI futex while contexts wait for it in the kernel, so that its owner
+/// unlocks it using `FUTEX_UNLOCK_PI` rather than only in userspace.
+pub const FUTEX_WAITERS: u32 = 0x8000_0000;
+/// Set in the word of a PI futex when its previous owner exited without unlocking it.
+pub const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+/// Bits of the word of a PI futex that hold the thread ID of its owner, or 0 if unlocked.
+pub const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+/// Maximum length of a chain of PI futex owners waiting for each other that priorities are
+/// inherited through, which also bounds the work done for deadlocked chains.
+const PI_CHAIN_MAX: usize = 16;
+
+/// Bitset matching all waiters, which is what FUTEX_WAIT and FUTEX_WAKE use.
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+/// Maximum number of futexes that can be waited on at once.
+const FUTEX_WAITV_MAX: usize = 128;
+
+/// A futex to wait on with `FUTEX_WAIT_MULTIPLE`, which has the same layout as Linux's
+/// `struct futex_waitv`.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct FutexWaitv {
+    /// Value the futex word must still have
+    pub val: u64,
+    /// Address of the futex word, which is 32 bits in size
+    pub uaddr: u64,
+    /// Either 0 or `FUTEX_PRIVATE_FLAG`
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+/// Number of bits of the hash of a futex key that select its bucket.
+const FUTEX_HASH_BITS: u32 = 8;
+
 type FutexList = VecDeque<FutexEntry>;
 
+/// Identifies the futex a context waits on.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum FutexKey {
+    /// A futex in memory that may be shared with other address spaces, which is identified by the
+    /// physical address of its word.
+    Shared(PhysicalAddress),
+    /// A futex that is only used within one address space, which is identified by that address
+    /// space and the virtual address of its word. Unlike the physical address, these do not change
+    /// when a CoW page is copied.
+    Private {
+        addr_space: usize,
+        addr: VirtualAddress,
+    },
+}
+impl FutexKey {
+    fn bucket_index(self) -> usize {
+        let hash = match self {
+            Self::Shared(physaddr) => physaddr.data() as u64,
+            Self::Private { addr_space, addr } => {
+                (addr_space as u64).rotate_left(32) ^ addr.data() as u64
+            }
+        };
+        // Fibonacci hashing, ignoring the low bits which are the same for all aligned futexes
+        ((hash >> 2).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (u64::BITS - FUTEX_HASH_BITS)) as usize
+    }
+    fn bucket(self) -> &'static Mutex<FutexList> {
+        &FUTEX_BUCKETS[self.bucket_index()]
+    }
+}
+
 pub struct FutexEntry {
-    // Physical address, required if synchronizing across address spaces (necessitates MAP_SHARED
-    // since CoW would invalidate this address).
-    target_physaddr: PhysicalAddress,
-    // Virtual address, required if synchronizing across the same address space, if the memory is
-    // CoW.
-    // TODO: FUTEX_REQUEUE
-    target_virtaddr: VirtualAddress,
-    // Context to wake up, and compare address spaces.
+    key: FutexKey,
+    // Only wakeups with a bitset that has some of these bits set wake this waiter up.
+    bitset: u32,
+    // Context to wake up
     context_lock: Arc<RwSpinlock<Context>>,
-    // address space to check against if virt matches but not phys
-    addr_space: Weak<AddrSpaceWrapper>,
+    // Index of the futex in the array passed to FUTEX_WAIT_MULTIPLE, if waiting on several.
+    waitv_index: Option<usize>,
+}
+impl FutexEntry {
+    fn wake(self) {
+        let mut context = self.context_lock.write();
+        match self.waitv_index {
+            Some(index) => {
+                context.futex_woken.get_or_insert(index);
+            }
+            None => context.futex_blocked_on = None,
+        }
+        context.unblock();
+    }
+}
+
+/// Waiting contexts, in buckets selected by the hash of their futex key, so that operations on
+/// different futexes rarely contend or have to skip each other's waiters.
+static FUTEX_BUCKETS: [Mutex<FutexList>; 1 << FUTEX_HASH_BITS] =
+    [const { Mutex::new(FutexList::new()) }; 1 << FUTEX_HASH_BITS];
+
+/// Lock the buckets with the indices `a` and `b`, in a fixed order so that concurrent calls
+/// cannot deadlock. The second guard is `None` if both are the same bucket.
+fn lock_buckets(
+    a: usize,
+    b: usize,
+) -> (
+    MutexGuard<'static, FutexList>,
+    Option<MutexGuard<'static, FutexList>>,
+) {
+    if a == b {
+        (FUTEX_BUCKETS[a].lock(), None)
+    } else if a < b {
+        let a = FUTEX_BUCKETS[a].lock();
+        (a, Some(FUTEX_BUCKETS[b].lock()))
+    } else {
+        let b = FUTEX_BUCKETS[b].lock();
+        (FUTEX_BUCKETS[a].lock(), Some(b))
+    }
 }
 
-// TODO: Process-private futexes? In that case, put the futex table in each AddrSpace, or just
-// implement that fully in userspace. Although futex is probably the best API for process-shared
-// POSIX synchronization primitives, a local hash table and wait-for-thread kernel APIs (e.g.
-// lwp_park/lwp_unpark from NetBSD) could be a simpler replacement.
-//
-// TODO: Use an actual hash table.
-static FUTEXES: RwLock<FutexList> = RwLock::new(FutexList::new());
+/// Stop waiting on a futex after being woken up, returning whether the current context was still
+/// waiting rather than woken up by `FUTEX_WAKE` or similar.
+fn stop_waiting(context_lock: &Arc<RwSpinlock<Context>>) -> bool {
+    loop {
+        let Some(key) = context_lock.read().futex_blocked_on else {
+            return false;
+        };
+        let mut futexes = key.bucket().lock();
+
+        {
+            let mut context = context_lock.write();
+            if context.futex_blocked_on != Some(key) {
+                // Requeued to another futex in the meantime
+                continue;
+            }
+            context.futex_blocked_on = None;
+        }
+        futexes.retain(|entry| !Arc::ptr_eq(&entry.context_lock, context_lock));
+        return true;
+    }
+}
+
+struct PiState {
+    /// The context that owns the futex, according to its futex word
+    owner: Weak<RwSpinlock<Context>>,
+    /// Contexts blocked until they can lock the futex, in the order they started waiting
+    waiters: Vec<Arc<RwSpinlock<Context>>>,
+}
+impl PiState {
+    fn is_owned_by(&self, context_lock: &Arc<RwSpinlock<Context>>) -> bool {
+        Weak::as_ptr(&self.owner) == Arc::as_ptr(context_lock)
+    }
+    /// Remove the waiter that should own the futex next, which is the most important one, and of
+    /// those the one that has waited the longest.
+    fn take_next_owner(&mut self) -> Option<Arc<RwSpinlock<Context>>> {
+        let (idx, _) = self
+            .waiters
+            .iter()
+            .enumerate()
+            .min_by_key(|(idx, waiter)| (waiter.read().sched_priority(), *idx))?;
+        Some(self.waiters.remove(idx))
+    }
+}
+
+/// PI futexes that have waiters, or that have been handed over to a waiter, by their futex key.
+/// Futexes that are only ever locked without contention never appear here, as they are locked and
+/// unlocked entirely in userspace.
+static PI_FUTEXES: Mutex<BTreeMap<FutexKey, PiState>> = Mutex::new(BTreeMap::new());
 
 fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Option<PhysicalAddress> {
     // TODO: Move this elsewhere!
@@ -64,36 +221,490 @@ fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Optio
     let page = Page::containing_address(addr);
     let off = addr.data() - page.start_address().data();
 
-    let (frame, _) = space.table.utable.translate(page.start_address())?;
+    let (frame, _) = huge::translate(&space.table.utable, page.start_address())?;
 
     Some(frame.add(off))
 }
 
-pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) -> Result<usize> {
+/// Fault in the page containing `addr` if it is not mapped, which can be because it was paged out
+/// to swap, so that the futex word can be accessed through its physical address.
+fn fault_in(addr_space: &AddrSpaceWrapper, addr: VirtualAddress, access: AccessMode) -> Result<()> {
+    let page = Page::containing_address(addr);
+    let mapped = huge::translate(
+        &addr_space.acquire_read().table.utable,
+        page.start_address(),
+    )
+    .is_some_and(|(_, flags)| access != AccessMode::Write || flags.has_write());
+    if !mapped {
+        try_correcting_page_tables(page, access).map_err(|_| Error::new(EFAULT))?;
+    }
+    Ok(())
+}
+
+/// Determine the key of the futex at `addr`, and the physical address its word can be accessed
+/// at. Unless `private`, futexes are keyed by physical address, so that they match across address
+/// spaces borrowing the same frames, except for frames that are shared copy-on-write, as their
+/// physical address changes when copied. Such frames can only be shared with other address spaces
+/// by copying them first.
+fn futex_key(
+    addr_space: &Arc<AddrSpaceWrapper>,
+    space: &AddrSpace,
+    addr: VirtualAddress,
+    private: bool,
+) -> Result<(FutexKey, PhysicalAddress)> {
+    let physaddr = validate_and_translate_virt(space, addr).ok_or(Error::new(EFAULT))?;
+
+    let frame = Frame::containing(physaddr);
+    let private = private
+        || frame == the_zeroed_frame().0
+        || matches!(
+            get_page_info(frame).and_then(PageInfo::refcount),
+            Some(RefCount::Cow(_))
+        );
+    let key = if private {
+        FutexKey::Private {
+            addr_space: Arc::as_ptr(addr_space) as usize,
+            addr,
+        }
+    } else {
+        FutexKey::Shared(physaddr)
+    };
+
+    Ok((key, physaddr))
+}
+
+/// The word of a PI futex, which unlike other futex words is also written by the kernel. As that
+/// happens through the physical mapping, the page must be mapped writable, which also ensures it
+/// is not shared copy-on-write.
+fn pi_futex_word(space: &AddrSpace, addr: VirtualAddress) -> Result<&AtomicU32> {
+    // Must be aligned, so that it cannot cross a page boundary.
+    if addr.data() % 4 != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    if addr.data().saturating_add(core::mem::size_of::<u32>()) >= crate::USER_END_OFFSET {
+        return Err(Error::new(EFAULT));
+    }
+
+    let page = Page::containing_address(addr);
+    let (frame, flags) =
+        huge::translate(&space.table.utable, page.start_address()).ok_or(Error::new(EFAULT))?;
+    if !flags.has_write() {
+        return Err(Error::new(EFAULT));
+    }
+    let physaddr = frame.add(addr.data() - page.start_address().data());
+
+    let accessible_addr = unsafe { crate::paging::RmmA::phys_to_virt(physaddr) }.data();
+    Ok(unsafe { &*(accessible_addr as *const AtomicU32) })
+}
+
+/// Find the context with thread ID `tid`, if it still exists.
+fn find_pi_owner(state: Option<&PiState>, tid: u32) -> Option<Arc<RwSpinlock<Context>>> {
+    if let Some(owner) = state.and_then(|state| state.owner.upgrade())
+        && owner.read().tid == tid as usize
+    {
+        return Some(owner);
+    }
+    context::by_tid(tid as usize)
+}
+
+/// Recompute the real-time priority `owner` inherits from the waiters of the PI futexes it owns.
+/// If it changed, pass it on to the owner of the PI futex that `owner` is itself waiting for, and
+/// so on.
+fn update_pi_boost(pi_futexes: &BTreeMap<FutexKey, PiState>, mut owner: Arc<RwSpinlock<Context>>) {
+    for _ in 0..PI_CHAIN_MAX {
+        let boost = pi_futexes
+            .values()
+            .filter(|state| state.is_owned_by(&owner))
+            .flat_map(|state| state.waiters.iter())
+            .filter_map(|waiter| waiter.read().rt_priority())
+            .max();
+
+        let blocked_on = {
+            let mut context = owner.write();
+            if context.sched_pi_boost == boost {
+                return;
+            }
+            context.sched_pi_boost = boost;
+            switch::requeue(&mut context);
+            context.pi_blocked_on
+        };
+
+        let Some(next_owner) = blocked_on
+            .and_then(|key| pi_futexes.get(&key))
+            .and_then(|state| state.owner.upgrade())
+        else {
+            return;
+        };
+        owner = next_owner;
+    }
+}
+
+/// Lock a PI futex, blocking until it is handed over by its owner unless `try_only` is set, and
+/// letting the owner inherit the priority of the current context in the meantime.
+fn lock_pi(
+    addr: VirtualAddress,
+    private: bool,
+    timeout: Option<u128>,
+    try_only: bool,
+) -> Result<usize> {
+    let current_addrsp = AddrSpace::current()?;
+    let context_lock = context::current();
+    let tid = u32::try_from(context_lock.read().tid)
+        .ok()
+        .filter(|tid| tid & !FUTEX_TID_MASK == 0)
+        .ok_or(Error::new(EOVERFLOW))?;
+
+    loop {
+        fault_in(&current_addrsp, addr, AccessMode::Write)?;
+        let key = {
+            let addr_space_guard = current_addrsp.acquire_read();
+            let word = pi_futex_word(&addr_space_guard, addr)?;
+            let (key, _) = futex_key(&current_addrsp, &addr_space_guard, addr, private)?;
+            let mut pi_futexes = PI_FUTEXES.lock();
+
+            let owner = loop {
+                let value = word.load(Ordering::SeqCst);
+                let owner_tid = value & FUTEX_TID_MASK;
+                if owner_tid == tid {
+                    return Err(Error::new(EDEADLK));
+                }
+
+                let owner = if owner_tid == 0 {
+                    None
+                } else {
+                    find_pi_owner(pi_futexes.get(&key), owner_tid)
+                };
+                let Some(owner) = owner else {
+                    // Either unlocked, or its owner exited without unlocking it, in which case
+                    // the data it protects may be inconsistent.
+                    let owner_died = owner_tid != 0;
+                    let has_waiters = pi_futexes
+                        .get(&key)
+                        .is_some_and(|state| !state.waiters.is_empty());
+                    let mut new_value = tid;
+                    if has_waiters {
+                        new_value |= FUTEX_WAITERS;
+                    }
+                    if owner_died {
+                        new_value |= FUTEX_OWNER_DIED;
+                    }
+                    if word
+                        .compare_exchange(value, new_value, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_err()
+                    {
+                        continue;
+                    }
+
+                    if has_waiters && let Some(state) = pi_futexes.get_mut(&key) {
+                        state.owner = Arc::downgrade(&context_lock);
+                    } else {
+                        pi_futexes.remove(&key);
+                    }
+                    update_pi_boost(&pi_futexes, Arc::clone(&context_lock));
+                    return if owner_died {
+                        Err(Error::new(EOWNERDEAD))
+                    } else {
+                        Ok(0)
+                    };
+                };
+
+                if try_only {
+                    return Err(Error::new(EAGAIN));
+                }
+                if value & FUTEX_WAITERS == 0
+                    && word
+                        .compare_exchange(
+                            value,
+                            value | FUTEX_WAITERS,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        )
+                        .is_err()
+                {
+                    continue;
+                }
+                break owner;
+            };
+
+            {
+                let mut context = context_lock.write();
+
+                if let Some((tctl, pctl, _)) = context.sigcontrol() {
+                    if tctl.currently_pending_unblocked(pctl) != 0 {
+                        return Err(Error::new(EINTR));
+                    }
+                }
+
+                context.wake = timeout;
+                context.block("futex pi");
+                context.pi_blocked_on = Some(key);
+            }
+
+            let state = pi_futexes.entry(key).or_insert_with(|| PiState {
+                owner: Weak::new(),
+                waiters: Vec::new(),
+            });
+            state.owner = Arc::downgrade(&owner);
+            state.waiters.push(Arc::clone(&context_lock));
+            update_pi_boost(&pi_futexes, owner);
+
+            key
+        };
+
+        context::switch();
+
+        let mut pi_futexes = PI_FUTEXES.lock();
+
+        let timed_out = {
+            let mut context = context_lock.write();
+            context.pi_blocked_on = None;
+            let timed_out = timeout.is_some() && context.wake.is_none();
+            context.wake = None;
+            timed_out
+        };
+
+        let Some(state) = pi_futexes.get_mut(&key) else {
+            continue;
+        };
+        if let Some(idx) = state
+            .waiters
+            .iter()
+            .position(|waiter| Arc::ptr_eq(waiter, &context_lock))
+        {
+            // Still waiting, so woken up by a signal or the timeout.
+            state.waiters.remove(idx);
+            let owner = state.owner.upgrade();
+            if state.waiters.is_empty() {
+                pi_futexes.remove(&key);
+            }
+            if let Some(owner) = owner {
+                update_pi_boost(&pi_futexes, owner);
+            }
+            return Err(Error::new(if timed_out { ETIMEDOUT } else { EINTR }));
+        }
+        if state.is_owned_by(&context_lock) {
+            // Handed over by the previous owner
+            return Ok(0);
+        }
+        // Otherwise, the owner exited, so try to take it over.
+    }
+}
+
+/// Unlock a PI futex owned by the current context, handing it over to the next waiter if any.
+fn unlock_pi(addr: VirtualAddress, private: bool) -> Result<usize> {
+    let current_addrsp = AddrSpace::current()?;
+    let context_lock = context::current();
+    let tid = context_lock.read().tid;
+
+    fault_in(&current_addrsp, addr, AccessMode::Write)?;
+    let addr_space_guard = current_addrsp.acquire_read();
+    let word = pi_futex_word(&addr_space_guard, addr)?;
+    let (key, _) = futex_key(&current_addrsp, &addr_space_guard, addr, private)?;
+    let mut pi_futexes = PI_FUTEXES.lock();
+
+    if (word.load(Ordering::SeqCst) & FUTEX_TID_MASK) as usize != tid {
+        return Err(Error::new(EPERM));
+    }
+
+    let next_owner = pi_futexes.get_mut(&key).and_then(|state| {
+        let next_owner = state.take_next_owner()?;
+        state.owner = Arc::downgrade(&next_owner);
+        Some(next_owner)
+    });
+    match next_owner {
+        Some(next_owner) => {
+            // The next owner has to unlock it through the kernel as well, even if no one else is
+            // waiting, so that the state of the futex is released.
+            let next_tid = next_owner.read().tid as u32;
+            word.store(next_tid | FUTEX_WAITERS, Ordering::SeqCst);
+            update_pi_boost(&pi_futexes, Arc::clone(&next_owner));
+            next_owner.write().unblock();
+        }
+        None => {
+            pi_futexes.remove(&key);
+            word.store(0, Ordering::SeqCst);
+        }
+    }
+    update_pi_boost(&pi_futexes, context_lock);
+
+    Ok(0)
+}
+
+/// Wake up the next waiter of every PI futex owned by an exiting context, which will then take
+/// the futex over. Must be called once the context can no longer be found by its thread ID.
+pub fn pi_owner_exited(context_lock: &Arc<RwSpinlock<Context>>) {
+    PI_FUTEXES.lock().retain(|_, state| {
+        if !state.is_owned_by(context_lock) {
+            return true;
+        }
+        state.owner = Weak::new();
+        if let Some(next_owner) = state.take_next_owner() {
+            next_owner.write().unblock();
+        }
+        !state.waiters.is_empty()
+    });
+}
+
+/// Wait until one of the `count` futexes in the array at `addr` is woken up, returning its index.
+fn futex_waitv(addr: usize, count: usize, timeout: usize) -> Result<usize> {
+    if count == 0 || count > FUTEX_WAITV_MAX {
+        return Err(Error::new(EINVAL));
+    }
+    let waitvs = UserSlice::ro(addr, count * core::mem::size_of::<FutexWaitv>())?
+        .in_exact_chunks(core::mem::size_of::<FutexWaitv>())
+        .map(|buf| unsafe { buf.read_exact::<FutexWaitv>() })
+        .collect::<Result<Vec<_>>>()?;
+    let timeout_opt = UserSlice::ro(timeout, core::mem::size_of::<TimeSpec>())?
+        .none_if_null()
+        .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
+        .transpose()?
+        .map(|TimeSpec { tv_sec, tv_nsec }| tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128);
+
     let current_addrsp = AddrSpace::current()?;
+    let context_lock = context::current();
+    for waitv in &waitvs {
+        let addr = usize::try_from(waitv.uaddr).map_err(|_| Error::new(EFAULT))?;
+        fault_in(&current_addrsp, VirtualAddress::new(addr), AccessMode::Read)?;
+    }
+
+    // Buckets are always locked in order of their index, and only once, even if several of the
+    // futexes are in the same bucket.
+    let mut bucket_indices;
+    {
+        let addr_space_guard = current_addrsp.acquire_read();
+
+        let mut keys = Vec::with_capacity(count);
+        for waitv in &waitvs {
+            if waitv.flags & !(FUTEX_PRIVATE_FLAG as u32) != 0 || waitv.uaddr % 4 != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            let addr = usize::try_from(waitv.uaddr).map_err(|_| Error::new(EFAULT))?;
+            keys.push(futex_key(
+                &current_addrsp,
+                &addr_space_guard,
+                VirtualAddress::new(addr),
+                waitv.flags & FUTEX_PRIVATE_FLAG as u32 != 0,
+            )?);
+        }
+
+        bucket_indices = keys
+            .iter()
+            .map(|(key, _)| key.bucket_index())
+            .collect::<Vec<_>>();
+        bucket_indices.sort_unstable();
+        bucket_indices.dedup();
+        let mut buckets = bucket_indices
+            .iter()
+            .map(|&idx| FUTEX_BUCKETS[idx].lock())
+            .collect::<Vec<_>>();
+
+        for ((_, physaddr), waitv) in keys.iter().zip(&waitvs) {
+            let accessible_addr = unsafe { crate::paging::RmmA::phys_to_virt(*physaddr) }.data();
+            let fetched =
+                unsafe { (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst) };
+            if u64::from(fetched) != waitv.val {
+                return Err(Error::new(EAGAIN));
+            }
+        }
+
+        {
+            let mut context = context_lock.write();
+
+            context.wake = timeout_opt;
+            if let Some((tctl, pctl, _)) = context.sigcontrol() {
+                if tctl.currently_pending_unblocked(pctl) != 0 {
+                    return Err(Error::new(EINTR));
+                }
+            }
+
+            context.block("futex waitv");
+            context.futex_woken = None;
+        }
+
+        for (index, (key, _)) in keys.into_iter().enumerate() {
+            let Ok(bucket) = bucket_indices.binary_search(&key.bucket_index()) else {
+                continue;
+            };
+            buckets[bucket].push_back(FutexEntry {
+                key,
+                bitset: FUTEX_BITSET_MATCH_ANY,
+                context_lock: Arc::clone(&context_lock),
+                waitv_index: Some(index),
+            });
+        }
+    }
+
+    context::switch();
+
+    let timed_out = {
+        let mut context = context_lock.write();
+        let timed_out = timeout_opt.is_some() && context.wake.is_none();
+        context.wake = None;
+        timed_out
+    };
+
+    // Once the entries have been removed from all buckets, nothing can wake this context up
+    // anymore, so the result is final.
+    for idx in bucket_indices {
+        FUTEX_BUCKETS[idx].lock().retain(|entry| {
+            !(entry.waitv_index.is_some() && Arc::ptr_eq(&entry.context_lock, &context_lock))
+        });
+    }
+    match context_lock.write().futex_woken.take() {
+        Some(index) => Ok(index),
+        None if timed_out => Err(Error::new(ETIMEDOUT)),
+        None => Err(Error::new(EINTR)),
+    }
+}
+
+pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> Result<usize> {
+    let private = op & FUTEX_PRIVATE_FLAG != 0;
+    let op = op & !FUTEX_PRIVATE_FLAG;
+
+    if op == FUTEX_WAIT_MULTIPLE {
+        return futex_waitv(addr, val, val2);
+    }
+
+    let current_addrsp = AddrSpace::current()?;
+    fault_in(&current_addrsp, VirtualAddress::new(addr), AccessMode::Read)?;
+    if op == FUTEX_REQUEUE || op == FUTEX_CMP_REQUEUE {
+        fault_in(
+            &current_addrsp,
+            VirtualAddress::new(addr2),
+            AccessMode::Read,
+        )?;
+    }
 
     // Keep the address space locked so we can safely read from the physical address. Unlock it
     // before context switching.
     let addr_space_guard = current_addrsp.acquire_read();
 
     let target_virtaddr = VirtualAddress::new(addr);
-    let target_physaddr = validate_and_translate_virt(&*addr_space_guard, target_virtaddr)
-        .ok_or(Error::new(EFAULT))?;
+    let (key, target_physaddr) =
+        futex_key(&current_addrsp, &addr_space_guard, target_virtaddr, private)?;
 
     match op {
-        // TODO: FUTEX_WAIT_MULTIPLE?
-        FUTEX_WAIT | FUTEX_WAIT64 => {
+        // FUTEX_WAIT_BITSET takes the bitset in `addr2`, and only FUTEX_WAKE_BITSET calls with an
+        // overlapping bitset wake it up.
+        FUTEX_WAIT | FUTEX_WAIT64 | FUTEX_WAIT_BITSET => {
+            let bitset = if op == FUTEX_WAIT_BITSET {
+                addr2 as u32
+            } else {
+                FUTEX_BITSET_MATCH_ANY
+            };
+            if bitset == 0 {
+                return Err(Error::new(EINVAL));
+            }
             let timeout_opt = UserSlice::ro(val2, core::mem::size_of::<TimeSpec>())?
                 .none_if_null()
                 .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
                 .transpose()?;
 
+            let context_lock = context::current();
             {
-                let mut futexes = FUTEXES.write();
+                let mut futexes = key.bucket().lock();
 
-                let context_lock = context::current();
-
-                let (fetched, expected) = if op == FUTEX_WAIT {
+                let (fetched, expected) = if op != FUTEX_WAIT64 {
                     // Must be aligned, otherwise it could cross a page boundary and mess up the
                     // (simpler) validation we did in the first place.
                     if addr % 4 != 0 {
@@ -149,13 +760,14 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
                     }
 
                     context.block("futex");
+                    context.futex_blocked_on = Some(key);
                 }
 
                 futexes.push_back(FutexEntry {
-                    target_physaddr,
-                    target_virtaddr,
-                    context_lock,
-                    addr_space: Arc::downgrade(&current_addrsp),
+                    key,
+                    bitset,
+                    context_lock: Arc::clone(&context_lock),
+                    waitv_index: None,
                 });
             }
 
@@ -163,38 +775,134 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, _addr2: usize) ->
 
             context::switch();
 
-            if timeout_opt.is_some() {
-                context::current().write().wake = None;
+            let timed_out = {
+                let mut context = context_lock.write();
+                let timed_out = timeout_opt.is_some() && context.wake.is_none();
+                context.wake = None;
+                timed_out
+            };
+            if !stop_waiting(&context_lock) {
+                Ok(0)
+            } else if timed_out {
                 Err(Error::new(ETIMEDOUT))
             } else {
-                Ok(0)
+                Err(Error::new(EINTR))
             }
         }
-        FUTEX_WAKE => {
+        FUTEX_WAKE | FUTEX_WAKE_BITSET => {
+            let bitset = if op == FUTEX_WAKE_BITSET {
+                addr2 as u32
+            } else {
+                FUTEX_BITSET_MATCH_ANY
+            };
+            if bitset == 0 {
+                return Err(Error::new(EINVAL));
+            }
             let mut woken = 0;
 
             {
-                let mut futexes = FUTEXES.write();
+                let mut futexes = key.bucket().lock();
 
                 let mut i = 0;
 
                 // TODO: Use something like retain, once it is possible to tell it when to stop iterating...
                 while i < futexes.len() && woken < val {
-                    if futexes[i].target_physaddr != target_physaddr
-                        && (futexes[i].target_virtaddr != target_virtaddr
-                            || !Arc::downgrade(&current_addrsp).ptr_eq(&futexes[i].addr_space))
-                    {
+                    if futexes[i].key != key || futexes[i].bitset & bitset == 0 {
                         i += 1;
                         continue;
                     }
-                    futexes[i].context_lock.write().unblock();
-                    futexes.swap_remove_back(i);
+                    if let Some(entry) = futexes.remove(i) {
+                        entry.wake();
+                    }
                     woken += 1;
                 }
             }
 
             Ok(woken)
         }
+        // Wake up to `val` waiters, and move the remaining ones to wait on `addr2` instead, up to
+        // `val2` of them. FUTEX_CMP_REQUEUE first checks that the futex still has the value
+        // `val2`, like FUTEX_WAIT, and then moves all remaining waiters, since there is no
+        // argument left for the limit.
+        FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+            if addr % 4 != 0 || addr2 % 4 != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            let (new_key, _) = futex_key(
+                &current_addrsp,
+                &addr_space_guard,
+                VirtualAddress::new(addr2),
+                private,
+            )?;
+
+            let (mut futexes, mut new_futexes) =
+                lock_buckets(key.bucket_index(), new_key.bucket_index());
+
+            let max_requeued = if op == FUTEX_CMP_REQUEUE {
+                let accessible_addr =
+                    unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
+                let fetched =
+                    unsafe { (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst) };
+                if fetched != val2 as u32 {
+                    return Err(Error::new(EAGAIN));
+                }
+                usize::MAX
+            } else {
+                val2
+            };
+
+            let mut woken = 0;
+            let mut requeued = 0;
+            let mut i = 0;
+
+            while i < futexes.len() && (woken < val || requeued < max_requeued) {
+                if futexes[i].key != key {
+                    i += 1;
+                    continue;
+                }
+                // Waiters on several futexes cannot be moved to another futex, so they are woken
+                // up instead, which they have to be prepared for anyway.
+                if woken < val || futexes[i].waitv_index.is_some() {
+                    if let Some(entry) = futexes.remove(i) {
+                        entry.wake();
+                    }
+                    woken += 1;
+                    continue;
+                }
+
+                futexes[i].key = new_key;
+                futexes[i].context_lock.write().futex_blocked_on = Some(new_key);
+                match new_futexes {
+                    Some(ref mut new_futexes) => new_futexes.extend(futexes.remove(i)),
+                    None => i += 1,
+                }
+                requeued += 1;
+            }
+
+            Ok(woken + requeued)
+        }
+        FUTEX_LOCK_PI => {
+            let timeout_opt = UserSlice::ro(val2, core::mem::size_of::<TimeSpec>())?
+                .none_if_null()
+                .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
+                .transpose()?
+                .map(|TimeSpec { tv_sec, tv_nsec }| {
+                    tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
+                });
+            drop(addr_space_guard);
+
+            lock_pi(target_virtaddr, private, timeout_opt, false)
+        }
+        FUTEX_TRYLOCK_PI => {
+            drop(addr_space_guard);
+
+            lock_pi(target_virtaddr, private, None, true)
+        }
+        FUTEX_UNLOCK_PI => {
+            drop(addr_space_guard);
+
+            unlock_pi(target_virtaddr, private)
+        }
         _ => Err(Error::new(EINVAL)),
     }
 }
@@ -27,7 +27,11 @@ use self::{
 use crate::percpu::PercpuBlock;
 
 use crate::{
-    context::{memory::AddrSpace, process::ProcessId},
+    context::{
+        memory::{AddrSpace, PageSpan},
+        process::ProcessId,
+    },
+    paging::VirtualAddress,
     scheme::{memory::MemoryScheme, FileHandle, SchemeNamespace},
 };
 
@@ -46,6 +50,9 @@ pub mod fs;
 /// Fast userspace mutex
 pub mod futex;
 
+/// Parking and unparking threads
+pub mod park;
+
 /// Privilege syscalls
 pub mod privilege;
 
@@ -90,13 +97,19 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
             SYS_FMAP => {
                 let addrspace = AddrSpace::current()?;
                 let map = unsafe { UserSlice::ro(c, d)?.read_exact::<Map>()? };
-                if b == !0 {
+                let address = if b == !0 {
                     MemoryScheme::fmap_anonymous(&addrspace, &map, false)
                 } else {
                     file_op_generic(fd, |scheme, number| {
                         scheme.kfmap(number, &addrspace, &map, false)
                     })
+                }?;
+                if let Some(span) =
+                    PageSpan::validate_nonempty(VirtualAddress::new(address), map.size)
+                {
+                    addrspace.fault_in_future_locked(span);
                 }
+                Ok(address)
             }
             SYS_GETDENTS => {
                 let header_size = u16::try_from(e).map_err(|_| Error::new(EINVAL))?;
@@ -256,6 +269,9 @@ pub fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> us
         exit(SIGKILL);
     }
 
+    crate::memory::pressure::trigger_pending();
+    crate::context::switch::resched_if_needed();
+
     // errormux turns Result<usize> into -errno
     Error::mux(result)
 }
@@ -0,0 +1,60 @@
+//! # Park
+//! Parking blocks the current context until another one unparks it, or a timeout expires, like
+//! `lwp_park` and `lwp_unpark` on NetBSD. Unlike with futexes, there is no word in shared memory.
+//! Instead, unparking a context that is not parked yet makes its next park return immediately, so
+//! that wakeups are not lost.
+use alloc::sync::Arc;
+use core::mem;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    context::{self, Context},
+    syscall::error::{Error, Result, EINTR, ETIMEDOUT},
+};
+
+/// Park the current context until it is unparked, or until the monotonic clock reaches `timeout`.
+pub fn park(timeout: Option<u128>) -> Result<()> {
+    let context_lock = context::current();
+
+    {
+        let mut context = context_lock.write();
+
+        if mem::take(&mut context.unpark_pending) {
+            return Ok(());
+        }
+        if let Some((tctl, pctl, _)) = context.sigcontrol() {
+            if tctl.currently_pending_unblocked(pctl) != 0 {
+                return Err(Error::new(EINTR));
+            }
+        }
+
+        context.wake = timeout;
+        context.block("park");
+        context.parked = true;
+    }
+
+    context::switch();
+
+    let mut context = context_lock.write();
+    context.parked = false;
+    let timed_out = timeout.is_some() && context.wake.is_none();
+    context.wake = None;
+
+    if mem::take(&mut context.unpark_pending) {
+        Ok(())
+    } else if timed_out {
+        Err(Error::new(ETIMEDOUT))
+    } else {
+        Err(Error::new(EINTR))
+    }
+}
+
+/// Unpark a context, or make its next park return immediately if it is not parked.
+pub fn unpark(context_lock: &Arc<RwSpinlock<Context>>) {
+    let mut context = context_lock.write();
+
+    context.unpark_pending = true;
+    if context.parked {
+        context.unblock();
+    }
+}
@@ -11,7 +11,7 @@ use spin::RwLock;
 use crate::context::{
     memory::{AddrSpace, Grant, PageSpan},
     process::{self, Process, ProcessId, ProcessInfo, ProcessStatus},
-    Context, ContextRef, WaitpidKey,
+    Context, WaitpidKey,
 };
 
 use crate::{
@@ -56,7 +56,10 @@ pub fn exit_this_context() -> ! {
     drop(addrspace_opt);
     // TODO: Should status == Status::HardBlocked be handled differently?
     context_lock.write().status = context::Status::Dead;
-    let _ = context::contexts_mut().remove(&ContextRef(context_lock));
+    context::remove(&context_lock);
+    // Otherwise, waiters for PI futexes this context did not unlock would never wake up
+    super::futex::pi_owner_exited(&context_lock);
+    drop(context_lock);
     context::switch();
     unreachable!();
 }
@@ -64,8 +67,8 @@ pub fn exit_this_context() -> ! {
 pub fn wait_for_exit(context_lock: Arc<RwSpinlock<Context>>) {
     {
         let mut ctxt = context_lock.write();
-        ctxt.status = context::Status::Runnable;
         ctxt.being_sigkilled = true;
+        ctxt.set_runnable();
     }
     while !matches!(context_lock.read().status, context::Status::Dead) {
         context::switch();
@@ -333,6 +336,7 @@ pub fn send_signal(
         if sig == SIGKILL {
             context_guard.being_sigkilled = true;
             context_guard.unblock();
+            crate::memory::swap::wake(&mut context_guard);
             drop(context_guard);
             *killed_self |= is_self;
 
@@ -823,6 +827,10 @@ pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap) {
                 .expect("expected bootstrap context to have an address space"),
         );
 
+        // The bootstrap executable is linked to run at this address and its entry point is
+        // absolute, so it cannot be moved, but everything it maps afterwards is randomized.
+        // TODO: Map it at a random base once it is built as a position-independent image that
+        // relocates itself, and pass it the base it was loaded at.
         let base = Page::containing_address(VirtualAddress::new(PAGE_SIZE));
         let flags = MapFlags::MAP_FIXED_NOREPLACE
             | MapFlags::PROT_EXEC